@@ -0,0 +1,265 @@
+//! On-disk cache of the documents of the remarkable, so that the whole tree does not have to be
+//! crawled (one request per folder) before every command.
+
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    cmd::{list_folders, RemarkableFSHierarchy},
+    events::Reporter,
+    scheme::{DocType, RmkDocument, RmkDocuments},
+    utils::write_file,
+};
+
+/// The web interface does not expose any serial number, the device is identified by its address
+pub const DEFAULT_DEVICE: &str = "10.11.99.1";
+/// Bumped when the format of the cache changes, older caches are ignored
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct TreeCache {
+    pub version: u32,
+    pub device: String,
+    pub updated_at: String,
+    pub documents: RmkDocuments,
+}
+
+/// What an incremental refresh had to do
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RefreshStats {
+    /// folders listed again on the remarkable (root is always listed)
+    pub listed: usize,
+    /// folders taken from the cache because they have not been modified
+    pub reused: usize,
+}
+
+/// e.g: "~/.cache/remarkable2-downloader/10.11.99.1.json"
+pub fn cache_path(device: &str) -> Result<PathBuf> {
+    let cache_dir = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(dir), _) if !dir.is_empty() => PathBuf::from(dir),
+        (_, Some(home)) => PathBuf::from(home).join(".cache"),
+        _ => match std::env::var_os("LOCALAPPDATA") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                return Err(anyhow!(
+                    "Cannot find a cache directory, set $XDG_CACHE_HOME"
+                ))
+            }
+        },
+    };
+    let file_name = device.replace(['/', '\\', ':'], "_");
+    Ok(cache_dir
+        .join("remarkable2-downloader")
+        .join(format!("{file_name}.json")))
+}
+
+/// cached documents of `device`, `None` if there is no (usable) cache yet
+pub fn load_cache(device: &str) -> Result<Option<TreeCache>> {
+    let path = cache_path(device)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let cache = serde_json::from_slice::<TreeCache>(&fs::read(&path)?)?;
+    match cache.version == CACHE_VERSION && cache.device == device {
+        true => Ok(Some(cache)),
+        false => Ok(None),
+    }
+}
+
+pub fn save_cache(device: &str, documents: &RmkDocuments) -> Result<()> {
+    let path = cache_path(device)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let cache = TreeCache {
+        version: CACHE_VERSION,
+        device: device.to_string(),
+        updated_at: Local::now().to_rfc3339(),
+        documents: documents.clone(),
+    };
+    write_file(&path, &serde_json::to_vec(&cache)?, true)?;
+    Ok(())
+}
+
+/// Fetch the documents of the remarkable again, only folders whose `modified_client` changed
+/// since `cached` are listed again, the content of the others is taken from the cache.
+///
+/// The remarkable does not update a folder when a document inside it is edited, added, deleted or
+/// moved, so the refreshed tree can be out of date: it is only good enough to browse the documents
+/// (`ls`, `tree`, `search`...), the transfers always crawl the whole tree
+/// ([`crate::fetch_documents`]).
+pub async fn refresh_documents(
+    client: &reqwest::Client,
+    cached: &RmkDocuments,
+    reporter: &dyn Reporter,
+) -> Result<(RemarkableFSHierarchy, RefreshStats)> {
+    let cached = CachedTree::new(cached);
+    let mut stats = RefreshStats::default();
+    let mut all_docs = vec![];
+    // listed level by level, so that the folders of a level can be listed concurrently
+    let mut folders = vec![String::new()];
+    while !folders.is_empty() {
+        let listings = list_folders(client, &folders, reporter).await?;
+        stats.listed += folders.len();
+        folders = cached.merge_level(listings, &mut all_docs, &mut stats);
+    }
+    Ok((RemarkableFSHierarchy::from_documents(all_docs), stats))
+}
+
+/// The cached documents, by ID and by folder
+struct CachedTree<'a> {
+    by_id: HashMap<&'a str, &'a RmkDocument>,
+    children: HashMap<&'a str, Vec<&'a RmkDocument>>,
+}
+
+impl<'a> CachedTree<'a> {
+    fn new(cached: &'a RmkDocuments) -> Self {
+        let mut children: HashMap<&str, Vec<&RmkDocument>> = HashMap::new();
+        for doc in cached {
+            children.entry(doc.parent.as_str()).or_default().push(doc);
+        }
+        let by_id = cached.iter().map(|doc| (doc.id.as_str(), doc)).collect();
+        Self { by_id, children }
+    }
+
+    /// Add the `listings` of a level of folders to `all_docs`, with the cached content of their
+    /// unmodified subfolders. Returns the subfolders to list
+    fn merge_level(
+        &self,
+        listings: Vec<RmkDocuments>,
+        all_docs: &mut RmkDocuments,
+        stats: &mut RefreshStats,
+    ) -> Vec<String> {
+        let mut folders = vec![];
+        for doc in listings
+            .iter()
+            .flatten()
+            .filter(|doc| doc.doc_type == DocType::CollectionType)
+        {
+            let unchanged = self.by_id.get(doc.id.as_str()).is_some_and(|cached| {
+                cached.doc_type == DocType::CollectionType
+                    && cached.modified_client == doc.modified_client
+            });
+            match unchanged {
+                true => {
+                    stats.reused += 1;
+                    self.descendants(&doc.id, all_docs, stats);
+                }
+                false => folders.push(doc.id.clone()),
+            }
+        }
+        all_docs.extend(listings.into_iter().flatten());
+        folders
+    }
+
+    /// everything inside the folder `id` according to the cache
+    fn descendants(&self, id: &str, all_docs: &mut RmkDocuments, stats: &mut RefreshStats) {
+        let Some(docs) = self.children.get(id) else {
+            return;
+        };
+        for doc in docs
+            .iter()
+            .filter(|doc| doc.doc_type == DocType::CollectionType)
+        {
+            stats.reused += 1;
+            self.descendants(&doc.id, all_docs, stats);
+        }
+        all_docs.extend(docs.iter().map(|doc| (*doc).clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(id: &str, parent: &str, doc_type: DocType, modified_client: &str) -> RmkDocument {
+        RmkDocument {
+            id: id.to_string(),
+            parent: parent.to_string(),
+            doc_type,
+            modified_client: modified_client.to_string(),
+            vissible_name: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(docs: &RmkDocuments) -> Vec<&str> {
+        let mut ids = docs.iter().map(|doc| doc.id.as_str()).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    fn cached() -> RmkDocuments {
+        vec![
+            doc("books", "", DocType::CollectionType, "1"),
+            doc("fantasy", "books", DocType::CollectionType, "1"),
+            doc("dune", "fantasy", DocType::DocumentType, "1"),
+            doc("course", "books", DocType::DocumentType, "1"),
+            doc("notes", "", DocType::DocumentType, "1"),
+        ]
+    }
+
+    #[test]
+    fn unmodified_folders_are_taken_from_the_cache() {
+        let cached = cached();
+        let tree = CachedTree::new(&cached);
+        let mut all_docs = vec![];
+        let mut stats = RefreshStats::default();
+        let root = vec![
+            doc("books", "", DocType::CollectionType, "1"),
+            doc("notes", "", DocType::DocumentType, "2"),
+        ];
+        let folders = tree.merge_level(vec![root], &mut all_docs, &mut stats);
+
+        assert!(folders.is_empty());
+        assert_eq!(
+            ids(&all_docs),
+            ["books", "course", "dune", "fantasy", "notes"]
+        );
+        // the listing wins over the cache
+        let notes = all_docs.iter().find(|doc| doc.id == "notes").unwrap();
+        assert_eq!(notes.modified_client, "2");
+        assert_eq!(stats.reused, 2);
+    }
+
+    #[test]
+    fn modified_and_new_folders_are_listed_again() {
+        let cached = cached();
+        let tree = CachedTree::new(&cached);
+        let mut all_docs = vec![];
+        let mut stats = RefreshStats::default();
+        let root = vec![
+            doc("books", "", DocType::CollectionType, "2"),
+            doc("new", "", DocType::CollectionType, "1"),
+        ];
+        let folders = tree.merge_level(vec![root], &mut all_docs, &mut stats);
+        assert_eq!(folders, ["books", "new"]);
+        assert_eq!(ids(&all_docs), ["books", "new"]);
+
+        let books = vec![
+            doc("fantasy", "books", DocType::CollectionType, "1"),
+            doc("course", "books", DocType::DocumentType, "2"),
+        ];
+        let folders = tree.merge_level(vec![books], &mut all_docs, &mut stats);
+        assert!(folders.is_empty());
+        assert_eq!(
+            ids(&all_docs),
+            ["books", "course", "dune", "fantasy", "new"]
+        );
+        assert_eq!(stats.reused, 1);
+    }
+
+    #[test]
+    fn a_document_is_not_mistaken_for_a_folder() {
+        let cached = vec![doc("books", "", DocType::DocumentType, "1")];
+        let tree = CachedTree::new(&cached);
+        let mut all_docs = vec![];
+        let mut stats = RefreshStats::default();
+        let root = vec![doc("books", "", DocType::CollectionType, "1")];
+        let folders = tree.merge_level(vec![root], &mut all_docs, &mut stats);
+        assert_eq!(folders, ["books"]);
+    }
+}
@@ -0,0 +1,58 @@
+//! Stop an operation cleanly when asked to (e.g. Ctrl-C in the CLI): no new transfer is started,
+//! the ones in progress finish, and what has been done so far is written before returning
+//! [`Cancelled`].
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// files being written, see [`crate::utils::write_file`]
+static TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+/// Error returned by an operation that stopped because of [`cancel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interrupted")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Ask the running operation to stop, it can be called from any thread
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn track_temp_file(path: &Path) {
+    if let Ok(mut temp_files) = TEMP_FILES.lock() {
+        temp_files.push(path.to_path_buf());
+    }
+}
+
+pub(crate) fn untrack_temp_file(path: &Path) {
+    if let Ok(mut temp_files) = TEMP_FILES.lock() {
+        temp_files.retain(|temp_file| temp_file != path);
+    }
+}
+
+/// Remove the files that are being written, for when the process has to exit right away
+pub fn remove_temp_files() {
+    if let Ok(mut temp_files) = TEMP_FILES.lock() {
+        for path in temp_files.drain(..) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
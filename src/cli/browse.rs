@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use colored::Colorize;
+use indicatif::HumanBytes;
+use remarkable2_downloader::{
+    cmd::{normalize_path, FolderSummary},
+    scheme::{DocType, RmkDocument},
+    FolderNode, RemarkableFSHierarchy,
+};
+use serde_derive::Serialize;
+
+use super::output::{output, print_records, DocumentRecord, OutputFormat};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SortBy {
+    /// alphabetical order
+    #[default]
+    Name,
+    /// most recently modified first
+    Date,
+}
+
+/// folders first, then documents, each sorted by `sort_by`
+pub fn sort_documents(docs: &mut [&RmkDocument], sort_by: SortBy) {
+    docs.sort_by(|a, b| {
+        let folder_first =
+            (b.doc_type == DocType::CollectionType).cmp(&(a.doc_type == DocType::CollectionType));
+        folder_first.then_with(|| match sort_by {
+            SortBy::Name => a
+                .vissible_name
+                .to_lowercase()
+                .cmp(&b.vissible_name.to_lowercase()),
+            SortBy::Date => b.modified_client.cmp(&a.modified_client),
+        })
+    });
+}
+
+/// e.g: "2024-01-01 11:00", the raw value if it cannot be parsed
+pub fn format_date(modified: &str) -> String {
+    match modified.parse::<DateTime<Local>>() {
+        Ok(date) => date.format("%Y-%m-%d %H:%M").to_string(),
+        Err(_) => modified.to_string(),
+    }
+}
+
+/// every tag used on the remarkable with its number of documents and pages
+pub fn print_tags(fs_hierarchy: &RemarkableFSHierarchy) -> Result<()> {
+    let tags = fs_hierarchy.tags();
+    if output() != OutputFormat::Text {
+        return print_records(&tags);
+    }
+    if tags.is_empty() {
+        println!("{}", "No tag on the remarkable".bright_black());
+    }
+    for tag in tags {
+        println!(
+            "{} {}",
+            tag.name.cyan().bold(),
+            format!("({} documents, {} pages)", tag.documents, tag.pages).bright_black()
+        );
+    }
+    Ok(())
+}
+
+/// folder of the hierarchy at `path`, "/" is for root
+pub fn find_folder<'a>(
+    fs_hierarchy: &'a RemarkableFSHierarchy,
+    path: &str,
+) -> Result<&'a FolderNode> {
+    let id = match normalize_path(path).as_str() {
+        "/" => "",
+        path => {
+            let doc = fs_hierarchy
+                .find_by_path(path)
+                .ok_or(anyhow!("No such file or folder: '{path}'"))?;
+            if doc.doc_type != DocType::CollectionType {
+                return Err(anyhow!("'{path}' is not a folder"));
+            }
+            &doc.id
+        }
+    };
+    fs_hierarchy
+        .folder_hierarchy
+        .find(id)
+        .ok_or(anyhow!("No such folder: '{path}'"))
+}
+
+pub fn ls(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    path: &str,
+    long: bool,
+    sort_by: SortBy,
+) -> Result<()> {
+    let mut docs = match normalize_path(path).as_str() {
+        "/" => fs_hierarchy.children(""),
+        path => {
+            let doc = fs_hierarchy
+                .find_by_path(path)
+                .ok_or(anyhow!("No such file or folder: '{path}'"))?;
+            match doc.doc_type {
+                DocType::CollectionType => fs_hierarchy.children(&doc.id),
+                _ => vec![doc],
+            }
+        }
+    };
+    sort_documents(&mut docs, sort_by);
+
+    let records = docs
+        .iter()
+        .map(|doc| DocumentRecord::new(doc, fs_hierarchy))
+        .collect::<Vec<_>>();
+    if output() != OutputFormat::Text {
+        return print_records(&records);
+    }
+
+    for record in records {
+        let name = match record.doc_type {
+            "folder" => format!("{}/", record.name).blue().bold(),
+            _ => record.name.normal(),
+        };
+        if !long {
+            println!("{name}");
+            continue;
+        }
+        println!(
+            "{} {:>5} {:>10} {} {} {} {}",
+            match record.doc_type {
+                "folder" => "d",
+                _ => "-",
+            },
+            record
+                .page_count
+                .map(|pages| pages.to_string())
+                .unwrap_or("-".to_string()),
+            record
+                .size_in_bytes
+                .map(|size| HumanBytes(size).to_string())
+                .unwrap_or("-".to_string()),
+            format_date(&record.modified).bright_black(),
+            format!(
+                "[{}]",
+                record
+                    .tags
+                    .iter()
+                    .map(|tag| tag.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )
+            .cyan(),
+            record.id.bright_black(),
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Machine-readable view of a folder of the tree
+#[derive(Serialize)]
+struct TreeRecord {
+    #[serde(flatten)]
+    record: DocumentRecord,
+    depth: usize,
+    /// only for folders
+    documents: Option<usize>,
+    folders: Option<usize>,
+    total_size: Option<u64>,
+    /// empty for documents and for folders deeper than the depth limit
+    children: Vec<TreeRecord>,
+}
+
+fn folder_label(name: &str, summary: &FolderSummary) -> String {
+    format!(
+        "{} {}",
+        format!("{name}/").blue().bold(),
+        format!(
+            "({} documents, {} folders, {})",
+            summary.documents,
+            summary.folders,
+            HumanBytes(summary.size)
+        )
+        .bright_black()
+    )
+}
+
+pub fn tree(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    path: &str,
+    max_depth: Option<usize>,
+    sort_by: SortBy,
+) -> Result<()> {
+    let folder = find_folder(fs_hierarchy, path)?;
+
+    fn build(
+        fs_hierarchy: &RemarkableFSHierarchy,
+        folder: &FolderNode,
+        depth: usize,
+        max_depth: Option<usize>,
+        sort_by: SortBy,
+    ) -> Vec<TreeRecord> {
+        if max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return vec![];
+        }
+        let mut docs = fs_hierarchy.children(&folder.id);
+        sort_documents(&mut docs, sort_by);
+        docs.into_iter()
+            .map(|doc| {
+                let subfolder = folder.subfolders.iter().find(|sub| sub.id == doc.id);
+                let summary = subfolder.map(|sub| fs_hierarchy.folder_summary(sub));
+                TreeRecord {
+                    record: DocumentRecord::new(doc, fs_hierarchy),
+                    depth,
+                    documents: summary.map(|summary| summary.documents),
+                    folders: summary.map(|summary| summary.folders),
+                    total_size: summary.map(|summary| summary.size),
+                    children: subfolder
+                        .map(|sub| build(fs_hierarchy, sub, depth + 1, max_depth, sort_by))
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+    let nodes = build(fs_hierarchy, folder, 1, max_depth, sort_by);
+
+    match output() {
+        OutputFormat::Json => return print_records(&nodes),
+        OutputFormat::Ndjson => {
+            fn flatten(nodes: Vec<TreeRecord>, flat: &mut Vec<TreeRecord>) {
+                for mut node in nodes {
+                    let children = std::mem::take(&mut node.children);
+                    flat.push(node);
+                    flatten(children, flat);
+                }
+            }
+            let mut flat = vec![];
+            flatten(nodes, &mut flat);
+            return print_records(&flat);
+        }
+        OutputFormat::Text => {}
+    }
+
+    fn print_nodes(nodes: &[TreeRecord], prefix: &str) {
+        for (i, node) in nodes.iter().enumerate() {
+            let last = i == nodes.len() - 1;
+            let label = match (node.documents, node.folders, node.total_size) {
+                (Some(documents), Some(folders), Some(size)) => folder_label(
+                    &node.record.name,
+                    &FolderSummary {
+                        documents,
+                        folders,
+                        size,
+                    },
+                ),
+                _ => node.record.name.clone(),
+            };
+            println!("{prefix}{}{label}", if last { "└── " } else { "├── " });
+            print_nodes(
+                &node.children,
+                &format!("{prefix}{}", if last { "    " } else { "│   " }),
+            );
+        }
+    }
+    let root_name = match folder.id.as_str() {
+        "" => "",
+        _ => folder.name.as_str(),
+    };
+    println!(
+        "{}",
+        folder_label(root_name, &fs_hierarchy.folder_summary(folder))
+    );
+    print_nodes(&nodes, "");
+    Ok(())
+}
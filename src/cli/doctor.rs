@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use remarkable2_downloader::{
+    cmd::set_pdf_route,
+    probe::{probe_capabilities, Capabilities, Connection, PdfRoute},
+};
+use serde_derive::Serialize;
+
+use super::output::{output, print_record, say, OutputFormat};
+
+/// Machine-readable result of `doctor`
+#[derive(Serialize)]
+struct DoctorRecord<'a> {
+    connection: Connection,
+    capabilities: Option<&'a Capabilities>,
+    generation: Option<&'static str>,
+    problems: Vec<String>,
+}
+
+/// what to do about each missing piece, empty if everything works
+fn problems(connection: Connection, capabilities: Option<&Capabilities>) -> Vec<String> {
+    let mut problems = vec![];
+    match connection {
+        Connection::Unreachable => problems.push(
+            "Nothing answers at 10.11.99.1: plug the remarkable with a USB cable that carries data, and wake it up".to_string(),
+        ),
+        Connection::WebInterfaceDisabled => problems.push(
+            "The remarkable is plugged in but its web interface is off: turn on 'USB web interface' in Settings > Storage (the tablet must stay unlocked)".to_string(),
+        ),
+        Connection::Connected => {}
+    }
+    let Some(capabilities) = capabilities else {
+        return problems;
+    };
+
+    if !capabilities.documents {
+        problems.push("The documents cannot be listed: this firmware speaks another API, please open an issue with its version".to_string());
+    }
+    if capabilities.sample_document.is_none() && capabilities.documents {
+        problems.push(
+            "There is no document on the remarkable, the exports could not be tested".to_string(),
+        );
+    } else if capabilities.pdf_export.is_none() && capabilities.documents {
+        problems.push(
+            "No PDF export endpoint found: 'download' and 'backup' cannot work with this firmware"
+                .to_string(),
+        );
+    }
+    if !capabilities.upload {
+        problems.push(
+            "No upload endpoint found: 'restore' and uploads cannot work with this firmware"
+                .to_string(),
+        );
+    }
+    problems
+}
+
+pub fn print_diagnostics(
+    connection: Connection,
+    capabilities: Option<&Capabilities>,
+) -> Result<()> {
+    let problems = problems(connection, capabilities);
+    if output() != OutputFormat::Text {
+        return print_record(&DoctorRecord {
+            connection,
+            capabilities,
+            generation: capabilities.map(|capabilities| capabilities.generation()),
+            problems,
+        });
+    }
+
+    let check = |name: &str, ok: bool, detail: &str| {
+        let mark = match ok {
+            true => "✔".green().bold(),
+            false => "✘".red().bold(),
+        };
+        println!("{mark} {:<16} {}", name, detail.bright_black());
+    };
+    check(
+        "Connection",
+        connection == Connection::Connected,
+        match connection {
+            Connection::Connected => "web interface answering at http://10.11.99.1",
+            Connection::WebInterfaceDisabled => "plugged in, web interface disabled",
+            Connection::Unreachable => "not plugged in",
+        },
+    );
+    if let Some(capabilities) = capabilities {
+        check("Documents list", capabilities.documents, "/documents/{id}");
+        check(
+            "PDF export",
+            capabilities.pdf_export.is_some(),
+            match capabilities.pdf_export {
+                Some(PdfRoute::Placeholder) => "/download/{id}/placeholder",
+                Some(PdfRoute::Pdf) => "/download/{id}/pdf",
+                None => "not found",
+            },
+        );
+        check(
+            ".rmdoc export",
+            capabilities.rmdoc_export,
+            "/download/{id}/rmdoc",
+        );
+        check("Upload", capabilities.upload, "/upload");
+        check("Thumbnails", capabilities.thumbnails, "/thumbnail/{id}");
+        if let Some(sample) = &capabilities.sample_document {
+            println!(
+                "{}",
+                format!("  (exports tested with '{sample}')").bright_black()
+            );
+        }
+        if !capabilities.unconfirmed.is_empty() {
+            println!(
+                "{}",
+                format!(
+                    "  (too slow to answer, assumed to work: {})",
+                    capabilities.unconfirmed.join(", ")
+                )
+                .yellow()
+            );
+        }
+        println!("{} {}", "Web interface:".bold(), capabilities.generation());
+    }
+
+    match problems.is_empty() {
+        true => println!("{}", "Everything looks fine".green()),
+        false => {
+            for problem in problems {
+                println!("{} {problem}", "→".yellow().bold());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Probe the remarkable and make the transfers use the endpoints it supports, fails with a
+/// diagnostic when the command cannot work with this firmware
+pub async fn adapt_to_device(downloads: bool, uploads: bool) -> Result<Capabilities> {
+    let capabilities = probe_capabilities(&reqwest::Client::new()).await;
+    if let Some(route) = capabilities.pdf_export {
+        set_pdf_route(route);
+    }
+
+    let can_download = capabilities.pdf_export.is_some() || capabilities.sample_document.is_none();
+    if downloads && !can_download {
+        return Err(anyhow!(
+            "This remarkable cannot export documents as PDF through its web interface ({}), run 'doctor' for details",
+            capabilities.generation()
+        ));
+    }
+    if uploads && !capabilities.upload {
+        return Err(anyhow!(
+            "This remarkable does not accept uploads through its web interface ({}), run 'doctor' for details",
+            capabilities.generation()
+        ));
+    }
+    if !can_download {
+        say("[WARNING]: no PDF export endpoint found, downloads will fail (run 'doctor' for details)".yellow());
+    }
+    Ok(capabilities)
+}
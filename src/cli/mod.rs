@@ -0,0 +1,729 @@
+use std::{
+    cell::RefCell,
+    env,
+    io::{self, IsTerminal, Write},
+};
+
+use age::secrecy::SecretString;
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use indicatif::HumanBytes;
+use remarkable2_downloader::{
+    cache::{load_cache, refresh_documents, save_cache, DEFAULT_DEVICE},
+    cancel::{cancel, remove_temp_files},
+    cmd::{
+        archive::ArchiveListing,
+        dry_run::PlannedAction,
+        fetch_documents_with_client,
+        plan::TransferPlan,
+        restore::{RestoreAction, RestorePlan},
+        status::{BackupStatus, Change},
+        verify::{FileStatus, VerifyReport},
+    },
+    crypto::Protection,
+    events::{Event, Reporter},
+    manifest::Manifest,
+    report::{last_backup_manifest, BackupFailure, ChangeReport, ReportFormat},
+    scheme::RmkDocument,
+    utils::join_path,
+    RemarkableFSHierarchy,
+};
+use serde_derive::Serialize;
+
+pub mod browse;
+pub mod doctor;
+pub mod output;
+pub mod progress;
+pub mod shell;
+pub mod tui;
+
+use output::{
+    output, print_record, print_records, say, DocumentRecord, JsonReporter, OutputFormat,
+    StderrJsonReporter,
+};
+use progress::TransferStats;
+
+pub fn print_err(err: &str) {
+    eprintln!("{}", err.red().bold());
+}
+
+/// Print the events of the library as colored text, one line per event
+#[derive(Default)]
+pub struct ConsoleReporter {
+    pub stats: TransferStats,
+}
+
+impl ConsoleReporter {
+    pub fn print(&self, event: &Event) {
+        match event {
+            Event::Info { message } => println!("{}", message.blue()),
+            Event::Warning { message } => println!("{}", message.yellow()),
+            Event::SmartModeAdded { name, .. } => println!(
+                "{}",
+                format!("[SMART_MODE]: adding '{name}', because change made since last download")
+                    .purple()
+            ),
+            Event::SmartModeSkipped { name, .. } => println!(
+                "{}",
+                format!(
+                    "[SMART_MODE]: skipped '{name}', because no change made since last download"
+                )
+                .yellow()
+            ),
+            Event::TransferPlanned { total, total_bytes } => println!(
+                "{}",
+                format!(
+                    "Transferring {total} files (~{})... (This may take a (very) long time)",
+                    HumanBytes(*total_bytes)
+                )
+                .blue()
+            ),
+            Event::DownloadStarted { name, .. } => {
+                println!("{}", format!("Downloading {name}...").purple())
+            }
+            Event::DownloadProgress { .. }
+            | Event::DownloadFinished { .. }
+            | Event::UploadFinished { .. } => {}
+            Event::DownloadFailed { name, error, .. } => {
+                print_err(&format!("Failed to download '{name}': {error}"))
+            }
+            Event::DownloadReused { name, same_as, .. } => println!(
+                "{}",
+                format!("'{name}' is identical to '{same_as}', not downloaded").bright_black()
+            ),
+            Event::UploadStarted { path, folder } => {
+                println!("{}", format!("Uploading {path} into {folder}...").purple())
+            }
+            Event::UploadFailed { path, error } => {
+                print_err(&format!("Failed to upload '{path}': {error}"))
+            }
+            Event::Deduplicated {
+                documents,
+                files,
+                saved_bytes,
+            } => println!(
+                "{}",
+                format!(
+                    "{documents} documents stored as {files} files ({} saved by deduplication)",
+                    HumanBytes(*saved_bytes)
+                )
+                .blue()
+            ),
+            Event::Skipped { reason } => println!("{}", reason.yellow()),
+            Event::Completed { message } => {
+                println!("{}", message.green());
+                if let Some(summary) = self.stats.summary() {
+                    println!("{}", summary.green());
+                }
+            }
+            Event::Interrupted { message } => {
+                println!("{}", message.yellow());
+                if let Some(summary) = self.stats.summary() {
+                    println!("{}", summary.yellow());
+                }
+            }
+        }
+    }
+}
+
+impl Reporter for ConsoleReporter {
+    fn report(&self, event: Event) {
+        self.stats.record(&event);
+        self.print(&event);
+    }
+}
+
+/// Progress bars when the output is a terminal, plain log lines otherwise, JSON records if asked
+pub fn reporter() -> Box<dyn Reporter> {
+    match output() {
+        OutputFormat::Json | OutputFormat::Ndjson => Box::new(JsonReporter::new(output())),
+        OutputFormat::Text if io::stdout().is_terminal() => {
+            Box::new(progress::ProgressReporter::new())
+        }
+        OutputFormat::Text => Box::<ConsoleReporter>::default(),
+    }
+}
+
+/// Reporter of the commands printing records (ls, status, plans, dry runs...): their events stay
+/// out of stdout when the output is machine-readable
+pub fn record_reporter() -> Box<dyn Reporter> {
+    match output() {
+        OutputFormat::Json | OutputFormat::Ndjson => Box::new(StderrJsonReporter),
+        OutputFormat::Text => reporter(),
+    }
+}
+
+/// Crawl the whole tree of documents again and save it in the cache
+pub async fn reload_documents(
+    client: &reqwest::Client,
+    reporter: &dyn Reporter,
+) -> Result<RemarkableFSHierarchy> {
+    let fs_hierarchy = fetch_documents_with_client(client, "", "root", reporter).await?;
+    if let Err(why) = save_cache(DEFAULT_DEVICE, &fs_hierarchy.all_docs) {
+        say(format!("Failed to save the documents cache: {why}").yellow());
+    }
+    Ok(fs_hierarchy)
+}
+
+/// Documents of the remarkable: the cached tree refreshed incrementally (see
+/// [`refresh_documents`], only to browse the documents), or a full crawl if there is no cache yet
+/// or if `full_refresh` is set
+pub async fn load_documents(full_refresh: bool) -> Result<RemarkableFSHierarchy> {
+    // only the documents that cannot be read are reported while fetching
+    let warn = |event: Event| {
+        if let Event::Warning { message } = event {
+            say(format!("[WARNING]: {message}").yellow());
+        }
+    };
+    let cache = match full_refresh {
+        true => None,
+        false => load_cache(DEFAULT_DEVICE).unwrap_or_else(|why| {
+            say(format!("Ignoring the documents cache, it cannot be read: {why}").yellow());
+            None
+        }),
+    };
+    let Some(cache) = cache else {
+        return reload_documents(&reqwest::Client::new(), &warn).await;
+    };
+
+    let (fs_hierarchy, stats) =
+        refresh_documents(&reqwest::Client::new(), &cache.documents, &warn).await?;
+    if let Err(why) = save_cache(DEFAULT_DEVICE, &fs_hierarchy.all_docs) {
+        say(format!("Failed to save the documents cache: {why}").yellow());
+    }
+    say(format!(
+        "Documents refreshed from the cache: {} folders listed, {} unchanged (use '--refresh' for a full crawl)",
+        stats.listed, stats.reused
+    )
+    .bright_black());
+    Ok(fs_hierarchy)
+}
+
+/// Cached documents and the date of the cache, for read-only commands when the remarkable is not plugged in
+pub fn cached_documents() -> Option<(RemarkableFSHierarchy, String)> {
+    let cache = load_cache(DEFAULT_DEVICE).ok()??;
+    Some((
+        RemarkableFSHierarchy::from_documents(cache.documents),
+        cache.updated_at,
+    ))
+}
+
+pub fn print_documents(docs: &[&RmkDocument], fs_hierarchy: &RemarkableFSHierarchy) -> Result<()> {
+    let records = docs
+        .iter()
+        .map(|doc| DocumentRecord::new(doc, fs_hierarchy))
+        .collect::<Vec<_>>();
+    if output() != OutputFormat::Text {
+        return print_records(&records);
+    }
+
+    if records.is_empty() {
+        println!("{}", "No document found".yellow());
+    }
+    for record in records {
+        let path = match record.doc_type {
+            "folder" => format!("{}/", record.path).blue().bold(),
+            _ => record.path.normal(),
+        };
+        println!("{path} {}", format!("[{}]", record.id).bright_black());
+    }
+    Ok(())
+}
+
+pub fn print_document_info(doc: &RmkDocument, fs_hierarchy: &RemarkableFSHierarchy) -> Result<()> {
+    let record = DocumentRecord::new(doc, fs_hierarchy);
+    if output() != OutputFormat::Text {
+        return print_record(&record);
+    }
+
+    let field = |name: &str, value: String| println!("{:>13} {value}", format!("{name}:").bold());
+    field("Name", record.name);
+    field("Path", record.path);
+    field("ID", record.id);
+    field("Type", record.doc_type.to_string());
+    field("Modified", record.modified);
+    if let Some(file_type) = record.file_type {
+        field("File type", file_type);
+    }
+    if let Some(page_count) = record.page_count {
+        field(
+            "Pages",
+            match record.current_page {
+                Some(current) => format!("{page_count} (currently at page {})", current + 1),
+                None => page_count.to_string(),
+            },
+        );
+    }
+    if let Some(size) = record.size_in_bytes {
+        field("Size", HumanBytes(size).to_string());
+    }
+    if let Some(title) = record.title {
+        field("Title", title);
+    }
+    if !record.authors.is_empty() {
+        field("Authors", record.authors.join(", "));
+    }
+    if !record.tags.is_empty() {
+        field(
+            "Tags",
+            record
+                .tags
+                .iter()
+                .map(|tag| tag.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+    if !record.page_tags.is_empty() {
+        field(
+            "Page tags",
+            record
+                .page_tags
+                .iter()
+                .map(|tag| match doc.page_index(&tag.page_id) {
+                    Some(i) => format!("{} (page {})", tag.name, i + 1),
+                    None => tag.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    }
+    field("Bookmarked", record.bookmarked.to_string());
+    Ok(())
+}
+
+pub fn print_archive_listing(listing: &ArchiveListing) -> Result<()> {
+    match (listing, output()) {
+        (ArchiveListing::Manifest(manifest), OutputFormat::Json) => return print_record(manifest),
+        (ArchiveListing::Manifest(manifest), OutputFormat::Ndjson) => {
+            return print_records(&manifest.documents)
+        }
+        (ArchiveListing::Raw(names), OutputFormat::Json | OutputFormat::Ndjson) => {
+            return print_records(names)
+        }
+        _ => {}
+    }
+
+    match listing {
+        ArchiveListing::Manifest(manifest) => {
+            println!(
+                "{}",
+                format!(
+                    "Backup made the {} ({} folders, {} documents)",
+                    manifest.created_at,
+                    manifest.folders.len(),
+                    manifest.documents.len()
+                )
+                .blue()
+            );
+            for entry in &manifest.documents {
+                println!(
+                    "{} {} {}",
+                    entry.path,
+                    format!("[{}]", entry.id).bright_black(),
+                    format!(
+                        "{} pages, modified {}",
+                        entry.page_count.unwrap_or_default(),
+                        entry.modified_client
+                    )
+                    .bright_black()
+                );
+            }
+        }
+        ArchiveListing::Raw(names) => {
+            println!(
+                "{}",
+                "No manifest found in this archive, listing raw entries".yellow()
+            );
+            for name in names {
+                println!("{name}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Machine-readable view of an action of a restore plan
+#[derive(Serialize)]
+struct RestoreActionRecord<'a> {
+    action: &'static str,
+    local_path: String,
+    /// folder of the document in the backup
+    folder: &'a str,
+    /// folder of the remarkable the document will be uploaded into
+    upload_folder: Option<&'a str>,
+    reason: Option<&'a str>,
+}
+
+pub fn print_restore_plan(plan: &RestorePlan) -> Result<()> {
+    if output() != OutputFormat::Text {
+        let records = plan
+            .actions
+            .iter()
+            .map(|action| match action {
+                RestoreAction::Upload { doc, folder, .. } => RestoreActionRecord {
+                    action: "upload",
+                    local_path: doc.local_path.display().to_string(),
+                    folder: &doc.folder,
+                    upload_folder: Some(folder),
+                    reason: None,
+                },
+                RestoreAction::Skip { doc, reason } => RestoreActionRecord {
+                    action: "skip",
+                    local_path: doc.local_path.display().to_string(),
+                    folder: &doc.folder,
+                    upload_folder: None,
+                    reason: Some(reason),
+                },
+                RestoreAction::MissingFolder { doc } => RestoreActionRecord {
+                    action: "missing_folder",
+                    local_path: doc.local_path.display().to_string(),
+                    folder: &doc.folder,
+                    upload_folder: None,
+                    reason: None,
+                },
+            })
+            .collect::<Vec<_>>();
+        return print_records(&records);
+    }
+
+    println!("{}", "Restore plan:".blue().bold());
+    for folder in &plan.missing_folders {
+        println!("{} {folder}", "  missing folder".yellow());
+    }
+    for action in &plan.actions {
+        match action {
+            RestoreAction::Upload { doc, folder, .. } => {
+                println!(
+                    "{} {} -> {folder}",
+                    "  upload".green(),
+                    doc.local_path.display()
+                )
+            }
+            RestoreAction::Skip { doc, reason } => println!(
+                "{} {} {}",
+                "  skip".bright_black(),
+                join_path(&doc.folder, &doc.name),
+                format!("({reason})").bright_black()
+            ),
+            RestoreAction::MissingFolder { doc } => println!(
+                "{} {} {}",
+                "  no folder".red(),
+                join_path(&doc.folder, &doc.name),
+                "(its folder does not exist on the remarkable)".bright_black()
+            ),
+        }
+    }
+
+    let uploads = plan.uploads();
+    let skipped = plan
+        .actions
+        .iter()
+        .filter(|action| matches!(action, RestoreAction::Skip { .. }))
+        .count();
+    println!(
+        "{}",
+        format!(
+            "{uploads} files to upload, {skipped} skipped, {} missing folders",
+            plan.missing_folders.len()
+        )
+        .blue()
+    );
+    Ok(())
+}
+
+/// Print what a dry run would do, action by action
+pub fn print_planned_actions(actions: &[PlannedAction]) -> Result<()> {
+    if output() != OutputFormat::Text {
+        return print_records(actions);
+    }
+
+    println!("{}", "Dry run, nothing is transferred:".blue().bold());
+    let (mut transfers, mut overwrites, mut skips, mut others) = (0, 0, 0, 0);
+    for action in actions {
+        match action {
+            PlannedAction::CreateFolder { path } => {
+                others += 1;
+                println!("{} {path}", "  create folder".bright_blue())
+            }
+            PlannedAction::Download { path, .. } => {
+                transfers += 1;
+                println!("{} {path}", "  download".green())
+            }
+            PlannedAction::Overwrite { path, .. } => {
+                overwrites += 1;
+                println!("{} {path}", "  overwrite".yellow())
+            }
+            PlannedAction::Skip { path, reason } => {
+                skips += 1;
+                println!(
+                    "{} {path} {}",
+                    "  skip".bright_black(),
+                    format!("({reason})").bright_black()
+                )
+            }
+            PlannedAction::Delete { path } => {
+                others += 1;
+                println!("{} {path}", "  delete".red())
+            }
+            PlannedAction::Upload {
+                local_path, folder, ..
+            } => {
+                transfers += 1;
+                println!("{} {local_path} -> {folder}", "  upload".green())
+            }
+        }
+    }
+    println!(
+        "{}",
+        format!(
+            "{transfers} files to transfer, {overwrites} to overwrite, {skips} skipped, {others} folders to create or delete"
+        )
+        .blue()
+    );
+    Ok(())
+}
+
+pub fn print_transfer_plan(plan: &TransferPlan) -> Result<()> {
+    if output() != OutputFormat::Text {
+        return print_record(plan);
+    }
+
+    println!(
+        "{}",
+        format!("{:<32} {:>9} {:>12}", "Folder", "Documents", "Size").bold()
+    );
+    for folder in &plan.folders {
+        println!(
+            "{:<32} {:>9} {:>12}",
+            folder.folder,
+            folder.documents,
+            HumanBytes(folder.bytes).to_string()
+        );
+    }
+    println!(
+        "{}",
+        format!(
+            "{:<32} {:>9} {:>12}",
+            "Total",
+            plan.documents,
+            HumanBytes(plan.bytes).to_string()
+        )
+        .bold()
+    );
+    match plan.free_space {
+        Some(free_space) if free_space < plan.bytes => println!(
+            "{}",
+            format!("Not enough free space: {}", HumanBytes(free_space)).red()
+        ),
+        Some(free_space) => println!(
+            "{}",
+            format!("Free space: {}", HumanBytes(free_space)).bright_black()
+        ),
+        None => println!("{}", "Free space: unknown".bright_black()),
+    }
+    Ok(())
+}
+
+/// Print the changes between the remarkable and a backup, grouped like `git status`
+pub fn print_backup_status(status: &BackupStatus) -> Result<()> {
+    if output() != OutputFormat::Text {
+        return print_record(status);
+    }
+
+    let group = |change: &Change| match change {
+        Change::New => "New on the remarkable:",
+        Change::Modified => "Modified since the backup:",
+        Change::Moved(_) => "Moved on the remarkable:",
+        Change::Deleted => "Deleted from the remarkable:",
+        Change::Untracked => "Not on the remarkable:",
+    };
+    let titles = [
+        Change::New,
+        Change::Modified,
+        Change::Moved(String::new()),
+        Change::Deleted,
+        Change::Untracked,
+    ]
+    .map(|change| group(&change));
+    for title in titles {
+        let documents = status
+            .changes
+            .iter()
+            .filter(|document| group(&document.change) == title)
+            .collect::<Vec<_>>();
+        if documents.is_empty() {
+            continue;
+        }
+        println!("{}", title.bold());
+        for document in documents {
+            match &document.change {
+                Change::New => println!("  {}", document.path.green()),
+                Change::Modified => println!("  {}", document.path.yellow()),
+                Change::Moved(from) => {
+                    println!("  {} -> {}", from.bright_black(), document.path.yellow())
+                }
+                Change::Deleted => println!("  {}", document.path.red()),
+                Change::Untracked => println!("  {}", document.path.bright_black()),
+            }
+        }
+    }
+
+    let made = match &status.created_at {
+        Some(created_at) => format!("the backup made the {created_at}"),
+        None => "the backup (no manifest, compared by path and date)".to_string(),
+    };
+    match status.changes.is_empty() {
+        true => println!(
+            "{}",
+            format!("{} documents, {made} is up to date", status.up_to_date).green()
+        ),
+        false => println!(
+            "{}",
+            format!(
+                "{} changes since {made}, {} documents up to date",
+                status.changes.len(),
+                status.up_to_date
+            )
+            .yellow()
+        ),
+    }
+    Ok(())
+}
+
+/// Print the problems found in a backup, fails if there is any
+pub fn print_verify_report(report: &VerifyReport) -> Result<()> {
+    let failures = report.failures().count();
+    if output() != OutputFormat::Text {
+        print_records(&report.files)?;
+    } else {
+        for file in &report.files {
+            let problem = match &file.status {
+                FileStatus::Ok => continue,
+                FileStatus::Missing => "missing".red(),
+                FileStatus::ChecksumMismatch => "modified since the backup".red(),
+                FileStatus::NoChecksum => "no checksum recorded".yellow(),
+                FileStatus::Invalid(why) => why.red(),
+            };
+            println!("{} {problem}", file.path);
+        }
+        let unchecked = report
+            .files
+            .iter()
+            .filter(|file| file.status == FileStatus::NoChecksum)
+            .count();
+        let summary = format!(
+            "{}/{} files intact in the backup made the {}",
+            report.files.len() - failures - unchecked,
+            report.files.len(),
+            report.created_at
+        );
+        match failures {
+            0 => println!("{}", summary.green()),
+            _ => println!("{}", summary.yellow()),
+        }
+    }
+
+    match failures {
+        0 => Ok(()),
+        _ => Err(anyhow!("{failures} files of the backup are damaged")),
+    }
+}
+
+/// Exit code of a transfer stopped with Ctrl-C (128 + SIGINT, like shells)
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Pass the events to `reporter`, keeping the documents that failed to download for the change report
+pub fn record_failures<'a>(
+    reporter: &'a dyn Reporter,
+    failures: &'a RefCell<Vec<BackupFailure>>,
+) -> impl Reporter + 'a {
+    move |event: Event| {
+        if let Event::DownloadFailed { id, name, error } = &event {
+            failures.borrow_mut().push(BackupFailure {
+                id: id.clone(),
+                name: name.clone(),
+                error: error.clone(),
+            });
+        }
+        reporter.report(event)
+    }
+}
+
+/// Save in the backup folder what changed since `previous` (the manifest of the last backup before
+/// this run), encrypted with `protection` like the backup, and print it if asked. Nothing is saved
+/// when a failed run did not change anything
+pub async fn report_changes(
+    previous: Option<Manifest>,
+    out_path: &str,
+    archive: bool,
+    protection: &Protection,
+    failures: Vec<BackupFailure>,
+    succeeded: bool,
+    (format, print): (ReportFormat, bool),
+) -> Result<Option<ChangeReport>> {
+    let Some(current) = last_backup_manifest(out_path, archive, protection).await else {
+        return Ok(None);
+    };
+    let report = ChangeReport::new(previous.as_ref(), &current, failures);
+    if !succeeded && report.is_empty() {
+        return Ok(None);
+    }
+    let path = report.save(out_path, format, protection).await?;
+    say(format!("Change report saved in '{path}'").bright_blue());
+    if print {
+        match output() {
+            OutputFormat::Text => println!("{}", report.render(format)),
+            _ => print_record(&report)?,
+        }
+    }
+    Ok(Some(report))
+}
+
+/// First Ctrl-C: no new transfer is started and what has been done so far is saved.
+/// Second Ctrl-C: exit right away, only the files being written are removed
+pub fn handle_interrupts() {
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        cancel();
+        say(
+            "Interrupted: finishing the current transfers, press Ctrl-C again to stop right away"
+                .yellow(),
+        );
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            remove_temp_files();
+            print_err("Stopped");
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+}
+
+/// Environment variable with the passphrase of the encrypted backups
+pub const PASSPHRASE_ENV: &str = "REMARKABLE2_PASSPHRASE";
+
+/// The passphrase of an encrypted backup: `$REMARKABLE2_PASSPHRASE`, or asked for (twice when it
+/// is a new one)
+pub fn read_passphrase(new: bool) -> Result<SecretString> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(SecretString::new(passphrase));
+    }
+    let passphrase = rpassword::prompt_password("Passphrase of the backup: ")?;
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase cannot be empty"));
+    }
+    if new && rpassword::prompt_password("Confirm the passphrase: ")? != passphrase {
+        return Err(anyhow!("The passphrases do not match"));
+    }
+    Ok(SecretString::new(passphrase))
+}
+
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
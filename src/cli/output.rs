@@ -0,0 +1,186 @@
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use remarkable2_downloader::{
+    events::{Event, Reporter},
+    scheme::{DocType, PageTag, RmkDocument, Tag},
+    RemarkableFSHierarchy,
+};
+use serde::Serialize;
+use serde_derive::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// colored text for humans
+    #[default]
+    Text,
+    /// a single JSON document
+    Json,
+    /// one JSON record per line
+    Ndjson,
+}
+
+static OUTPUT: OnceLock<OutputFormat> = OnceLock::new();
+
+pub fn set_output(format: OutputFormat) {
+    let _ = OUTPUT.set(format);
+}
+
+pub fn output() -> OutputFormat {
+    OUTPUT.get().copied().unwrap_or_default()
+}
+
+/// print a message meant for humans, it goes to stderr when the output is machine-readable to keep stdout parsable
+pub fn say(message: impl Display) {
+    match output() {
+        OutputFormat::Text => println!("{message}"),
+        OutputFormat::Json | OutputFormat::Ndjson => eprintln!("{message}"),
+    }
+}
+
+/// whether records were printed on stdout, the events of a json output then go to stderr
+static RECORDS_PRINTED: AtomicBool = AtomicBool::new(false);
+
+/// print a list of records as a JSON array or as one JSON document per line
+pub fn print_records<T: Serialize>(records: &[T]) -> Result<()> {
+    RECORDS_PRINTED.store(true, Ordering::Relaxed);
+    match output() {
+        OutputFormat::Ndjson => {
+            for record in records {
+                println!("{}", serde_json::to_string(record)?);
+            }
+        }
+        _ => println!("{}", serde_json::to_string_pretty(records)?),
+    }
+    Ok(())
+}
+
+/// print a single record, on one line for ndjson
+pub fn print_record<T: Serialize>(record: &T) -> Result<()> {
+    RECORDS_PRINTED.store(true, Ordering::Relaxed);
+    match output() {
+        OutputFormat::Ndjson => println!("{}", serde_json::to_string(record)?),
+        _ => println!("{}", serde_json::to_string_pretty(record)?),
+    }
+    Ok(())
+}
+
+/// Machine-readable view of a document or a folder of the remarkable, field names are stable
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentRecord {
+    pub id: String,
+    pub name: String,
+    /// e.g: "/Books/Fantasy/Dune"
+    pub path: String,
+    /// "document", "folder" or "unknown"
+    #[serde(rename = "type")]
+    pub doc_type: &'static str,
+    pub parent: String,
+    pub modified: String,
+    pub bookmarked: bool,
+    pub file_type: Option<String>,
+    pub page_count: Option<i64>,
+    pub current_page: Option<i64>,
+    pub size_in_bytes: Option<u64>,
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub tags: Vec<Tag>,
+    pub page_tags: Vec<PageTag>,
+}
+
+impl DocumentRecord {
+    pub fn new(doc: &RmkDocument, fs_hierarchy: &RemarkableFSHierarchy) -> Self {
+        Self {
+            id: doc.id.clone(),
+            name: doc.vissible_name.clone(),
+            path: fs_hierarchy.path_of(&doc.id).unwrap_or_default(),
+            doc_type: match doc.doc_type {
+                DocType::CollectionType => "folder",
+                DocType::DocumentType => "document",
+                DocType::Unknown => "unknown",
+            },
+            parent: doc.parent.clone(),
+            modified: doc.modified_client.clone(),
+            bookmarked: doc.bookmarked,
+            file_type: doc.file_type.clone(),
+            page_count: doc.page_count,
+            current_page: doc.current_page,
+            size_in_bytes: doc.size_estimate(),
+            title: doc
+                .document_metadata
+                .as_ref()
+                .and_then(|metadata| metadata.title.clone()),
+            authors: doc
+                .document_metadata
+                .as_ref()
+                .map(|metadata| metadata.authors.clone())
+                .unwrap_or_default(),
+            tags: doc.tags.clone(),
+            page_tags: doc.page_tags.clone(),
+        }
+    }
+}
+
+/// Emit the events as JSON: one per line for ndjson, a single array printed at the end for json.
+/// Stdout holds a single JSON document: the array is left out when there is no event, and goes to
+/// stderr when records were already printed
+pub struct JsonReporter {
+    format: OutputFormat,
+    events: RefCell<Vec<Event>>,
+}
+
+impl JsonReporter {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            events: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn report(&self, event: Event) {
+        match self.format {
+            OutputFormat::Ndjson => match serde_json::to_string(&event) {
+                Ok(line) => println!("{line}"),
+                Err(why) => eprintln!("Failed to serialize event: {why}"),
+            },
+            _ => self.events.borrow_mut().push(event),
+        }
+    }
+}
+
+impl Drop for JsonReporter {
+    fn drop(&mut self) {
+        let events = self.events.take();
+        if self.format != OutputFormat::Json || events.is_empty() {
+            return;
+        }
+        match serde_json::to_string_pretty(&events) {
+            Ok(json) if RECORDS_PRINTED.load(Ordering::Relaxed) => eprintln!("{json}"),
+            Ok(json) => println!("{json}"),
+            Err(why) => eprintln!("Failed to serialize events: {why}"),
+        }
+    }
+}
+
+/// Emit the events of the commands printing records as JSON on stderr, one per line, so that
+/// stdout only holds the records
+pub struct StderrJsonReporter;
+
+impl Reporter for StderrJsonReporter {
+    fn report(&self, event: Event) {
+        match serde_json::to_string(&event) {
+            Ok(line) => eprintln!("{line}"),
+            Err(why) => eprintln!("Failed to serialize event: {why}"),
+        }
+    }
+}
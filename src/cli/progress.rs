@@ -0,0 +1,206 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use remarkable2_downloader::events::{Event, Reporter};
+
+use super::ConsoleReporter;
+
+/// Keep track of what has been transferred to print a summary at the end
+#[derive(Default)]
+pub struct TransferStats {
+    started: Cell<Option<Instant>>,
+    files: Cell<usize>,
+    bytes: Cell<u64>,
+}
+
+impl TransferStats {
+    pub fn record(&self, event: &Event) {
+        match event {
+            Event::TransferPlanned { .. } => self.started.set(Some(Instant::now())),
+            Event::DownloadFinished { size, .. } => self.add(*size as u64),
+            Event::UploadFinished { size, .. } => self.add(*size),
+            _ => {}
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        self.files.set(self.files.get() + 1);
+        self.bytes.set(self.bytes.get() + bytes);
+    }
+
+    /// e.g: "Transferred 12 files (35.20 MiB) in 41.3s (872.81 KiB/s)", None if nothing was transferred
+    pub fn summary(&self) -> Option<String> {
+        let elapsed = self.started.get()?.elapsed();
+        let bytes = self.bytes.get();
+        let throughput = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        Some(format!(
+            "Transferred {} files ({}) in {:.1}s ({}/s)",
+            self.files.get(),
+            HumanBytes(bytes),
+            elapsed.as_secs_f64(),
+            HumanBytes(throughput as u64)
+        ))
+    }
+}
+
+/// Multi-line progress bars: overall files and bytes (with rate and ETA) and one per file being transferred.
+///
+/// Only meant for terminals, everything that isn't progress is printed by a [`ConsoleReporter`]
+pub struct ProgressReporter {
+    multi: MultiProgress,
+    files: RefCell<Option<ProgressBar>>,
+    bytes: RefCell<Option<ProgressBar>>,
+    /// transfers in progress (by document ID or local path) with the bytes received so far
+    current: RefCell<HashMap<String, (ProgressBar, u64)>>,
+    /// bytes of the finished transfers, the progress of the current ones comes on top of it
+    done_bytes: Cell<u64>,
+    console: ConsoleReporter,
+}
+
+impl ProgressReporter {
+    pub fn new() -> Self {
+        Self {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::stdout()),
+            files: RefCell::new(None),
+            bytes: RefCell::new(None),
+            current: RefCell::new(HashMap::new()),
+            done_bytes: Cell::new(0),
+            console: ConsoleReporter::default(),
+        }
+    }
+
+    fn start(&self, total: usize, total_bytes: u64) {
+        let files = self.multi.add(ProgressBar::new(total as u64));
+        files.set_style(
+            ProgressStyle::with_template("{prefix:>6.bold} [{bar:40.cyan/blue}] {pos}/{len} files")
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        files.set_prefix("Files");
+
+        let bytes = self.multi.add(ProgressBar::new(total_bytes));
+        bytes.set_style(
+            ProgressStyle::with_template(
+                "{prefix:>6.bold} [{bar:40.green/white}] {bytes}/{total_bytes} (~) {bytes_per_sec}, ETA {eta}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        bytes.set_prefix("Bytes");
+
+        self.done_bytes.set(0);
+        *self.files.borrow_mut() = Some(files);
+        *self.bytes.borrow_mut() = Some(bytes);
+    }
+
+    fn set_current(&self, key: &str, message: String, size_estimate: Option<u64>) {
+        let current = match size_estimate {
+            Some(size) => {
+                let bar = ProgressBar::new(size);
+                bar.set_style(
+                    ProgressStyle::with_template("{spinner} {msg} {bytes}/{total_bytes}").unwrap(),
+                );
+                bar
+            }
+            None => {
+                let bar = ProgressBar::new_spinner();
+                bar.set_style(ProgressStyle::with_template("{spinner} {msg} {bytes}").unwrap());
+                bar
+            }
+        };
+        let current = self.multi.add(current);
+        current.set_message(message);
+        current.enable_steady_tick(Duration::from_millis(120));
+        // a download that is tried again starts over
+        if let Some((previous, _)) = self
+            .current
+            .borrow_mut()
+            .insert(key.to_string(), (current, 0))
+        {
+            previous.finish_and_clear();
+        }
+    }
+
+    /// the estimation of the sizes may be wrong, the bars grow instead of overflowing
+    fn set_position(bar: &ProgressBar, position: u64) {
+        if position > bar.length().unwrap_or_default() {
+            bar.set_length(position);
+        }
+        bar.set_position(position);
+    }
+
+    /// total of the finished transfers and of what the current ones received
+    fn update_bytes(&self) {
+        let current = self
+            .current
+            .borrow()
+            .values()
+            .map(|(_, downloaded)| downloaded)
+            .sum::<u64>();
+        if let Some(bytes) = self.bytes.borrow().as_ref() {
+            Self::set_position(bytes, self.done_bytes.get() + current);
+        }
+    }
+
+    /// a transfer is over, successfully or not
+    fn transfer_done(&self, key: &str, size: u64) {
+        self.done_bytes.set(self.done_bytes.get() + size);
+        if let Some((current, _)) = self.current.borrow_mut().remove(key) {
+            current.finish_and_clear();
+        }
+        self.update_bytes();
+        if let Some(files) = self.files.borrow().as_ref() {
+            files.inc(1);
+        }
+    }
+
+    fn clear(&self) {
+        for (bar, _) in self.current.take().into_values() {
+            bar.finish_and_clear();
+        }
+        for bar in [&self.files, &self.bytes] {
+            if let Some(bar) = bar.take() {
+                bar.finish_and_clear();
+            }
+        }
+    }
+}
+
+impl Reporter for ProgressReporter {
+    fn report(&self, event: Event) {
+        self.console.stats.record(&event);
+        match &event {
+            Event::TransferPlanned { total, total_bytes } => self.start(*total, *total_bytes),
+            Event::DownloadStarted {
+                id,
+                name,
+                size_estimate,
+            } => self.set_current(id, format!("Downloading {name}"), *size_estimate),
+            Event::DownloadProgress { id, downloaded } => {
+                if let Some((current, received)) = self.current.borrow_mut().get_mut(id) {
+                    Self::set_position(current, *downloaded);
+                    *received = *downloaded;
+                }
+                self.update_bytes();
+            }
+            Event::DownloadFinished { id, size, .. } => self.transfer_done(id, *size as u64),
+            Event::UploadStarted { path, folder } => {
+                self.set_current(path, format!("Uploading {path} into {folder}"), None)
+            }
+            Event::UploadFinished { path, size, .. } => self.transfer_done(path, *size),
+            Event::DownloadFailed { id: key, .. } | Event::UploadFailed { path: key, .. } => {
+                self.transfer_done(key, 0);
+                self.multi.suspend(|| self.console.print(&event));
+            }
+            Event::Completed { .. } | Event::Interrupted { .. } => {
+                self.clear();
+                self.console.print(&event);
+            }
+            _ => self.multi.suspend(|| self.console.print(&event)),
+        }
+    }
+}
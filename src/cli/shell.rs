@@ -0,0 +1,390 @@
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use colored::Colorize;
+use remarkable2_downloader::{
+    cmd::{
+        download::{download_documents, DownloadOptions},
+        normalize_path,
+        upload::upload_file,
+    },
+    lock::{lock_dir, Wait},
+    scheme::DocType,
+    utils::check_output_path,
+    RemarkableFSHierarchy,
+};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use super::{
+    browse::{find_folder, ls, tree, SortBy},
+    print_document_info, print_documents, print_err, reload_documents, reporter,
+};
+
+const HELP: &str = "Commands:
+  ls [-l] [-t] [path]      list the content of a folder (-l: long format, -t: sort by date)
+  tree [path] [depth]      show the hierarchy of a folder
+  cd [path]                change the current folder ('..' for the parent, '/' or nothing for root)
+  pwd                      print the current folder
+  get <path> [local_dir]   download a file or a folder (default local folder is '.')
+  put <local_file> [path]  upload a pdf/epub into a folder (default is the current folder)
+  find <name>              search files and folders by name inside the current folder
+  info <path>              get information on a file or a folder
+  refresh                  fetch the documents of the remarkable again
+  help                     show this help
+  exit                     leave the shell";
+
+/// What the shell and its completion share
+struct ShellState {
+    /// shared so that it can be used while awaiting without keeping the state borrowed
+    fs_hierarchy: Rc<RemarkableFSHierarchy>,
+    /// current folder, e.g: "/Books"
+    cwd: String,
+}
+
+impl ShellState {
+    /// absolute path of `path` from the current folder, handles '.' and '..'
+    fn resolve(&self, path: &str) -> String {
+        resolve_path(&self.cwd, path)
+    }
+}
+
+fn resolve_path(cwd: &str, path: &str) -> String {
+    let full_path = match path.starts_with('/') {
+        true => path.to_string(),
+        false => format!("{cwd}/{path}"),
+    };
+    let mut parts: Vec<&str> = vec![];
+    for part in full_path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    normalize_path(&parts.join("/"))
+}
+
+/// split a line into words, words can be quoted ("my notes") or have escaped spaces (my\ notes)
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                in_word = true;
+            }
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (c, _) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace(' ', "\\ ")
+}
+
+/// Tab completion on the paths of the remarkable
+struct ShellHelper {
+    state: Rc<RefCell<ShellState>>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        // start of the word being completed, escaped spaces are part of the word
+        let before = &line[..pos];
+        let mut start = 0;
+        let mut escaped = false;
+        for (i, c) in before.char_indices() {
+            match c {
+                '\\' if !escaped => {
+                    escaped = true;
+                    continue;
+                }
+                c if c.is_whitespace() && !escaped => start = i + c.len_utf8(),
+                _ => {}
+            }
+            escaped = false;
+        }
+        let word = split_words(&before[start..]).pop().unwrap_or_default();
+
+        let (dir, prefix) = match word.rsplit_once('/') {
+            Some((dir, prefix)) => (format!("{dir}/"), prefix.to_string()),
+            None => (String::new(), word.clone()),
+        };
+        let state = self.state.borrow();
+        let folder_path = state.resolve(&dir);
+        let folder_id = match folder_path.as_str() {
+            "/" => String::new(),
+            path => match state.fs_hierarchy.find_by_path(path) {
+                Some(doc) => doc.id.clone(),
+                None => return Ok((start, vec![])),
+            },
+        };
+
+        let mut candidates = state
+            .fs_hierarchy
+            .children(&folder_id)
+            .into_iter()
+            .filter(|doc| doc.vissible_name.starts_with(&prefix))
+            .map(|doc| {
+                let suffix = match doc.doc_type {
+                    DocType::CollectionType => "/",
+                    _ => "",
+                };
+                Pair {
+                    display: format!("{}{suffix}", doc.vissible_name),
+                    replacement: format!("{}{}{suffix}", escape(&dir), escape(&doc.vissible_name)),
+                }
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.display.cmp(&b.display));
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+impl Highlighter for ShellHelper {}
+impl Validator for ShellHelper {}
+impl Helper for ShellHelper {}
+
+pub struct Shell {
+    state: Rc<RefCell<ShellState>>,
+    /// one client for the whole session
+    client: reqwest::Client,
+    udp_mode: bool,
+    override_mode: bool,
+    /// for an output folder locked by another run
+    wait: Wait,
+}
+
+impl Shell {
+    pub fn new(
+        fs_hierarchy: RemarkableFSHierarchy,
+        udp_mode: bool,
+        override_mode: bool,
+        wait: Wait,
+    ) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(ShellState {
+                fs_hierarchy: Rc::new(fs_hierarchy),
+                cwd: "/".to_string(),
+            })),
+            client: reqwest::Client::new(),
+            udp_mode,
+            override_mode,
+            wait,
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        let mut editor = Editor::<ShellHelper, DefaultHistory>::new()?;
+        editor.set_helper(Some(ShellHelper {
+            state: self.state.clone(),
+        }));
+        println!(
+            "{}",
+            "Remarkable shell, type 'help' to list the commands".bright_blue()
+        );
+
+        loop {
+            let prompt = format!("rmk:{}> ", self.state.borrow().cwd);
+            let line = match editor.readline(&prompt) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(why) => return Err(why.into()),
+            };
+            let words = split_words(&line);
+            let Some((command, args)) = words.split_first() else {
+                continue;
+            };
+            let _ = editor.add_history_entry(line.as_str());
+
+            match command.as_str() {
+                "exit" | "quit" => break,
+                command => {
+                    if let Err(why) = self.execute(command, args).await {
+                        print_err(&why.to_string());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn execute(&mut self, command: &str, args: &[String]) -> Result<()> {
+        let flags = args
+            .iter()
+            .filter(|arg| arg.starts_with('-'))
+            .map(|arg| arg.as_str())
+            .collect::<Vec<_>>();
+        let args = args
+            .iter()
+            .filter(|arg| !arg.starts_with('-'))
+            .collect::<Vec<_>>();
+        let arg = |i: usize| args.get(i).map(|arg| arg.as_str());
+
+        match command {
+            "help" => println!("{HELP}"),
+            "pwd" => println!("{}", self.state.borrow().cwd),
+            "ls" => {
+                let state = self.state.borrow();
+                let sort_by = match flags.iter().any(|flag| flag.contains('t')) {
+                    true => SortBy::Date,
+                    false => SortBy::Name,
+                };
+                let long = flags.iter().any(|flag| flag.contains('l'));
+                ls(
+                    &state.fs_hierarchy,
+                    &state.resolve(arg(0).unwrap_or(".")),
+                    long,
+                    sort_by,
+                )?
+            }
+            "tree" => {
+                let state = self.state.borrow();
+                let depth = arg(1).map(|depth| depth.parse()).transpose()?;
+                tree(
+                    &state.fs_hierarchy,
+                    &state.resolve(arg(0).unwrap_or(".")),
+                    depth,
+                    SortBy::Name,
+                )?
+            }
+            "cd" => {
+                let mut state = self.state.borrow_mut();
+                let path = state.resolve(arg(0).unwrap_or("/"));
+                find_folder(&state.fs_hierarchy, &path)?;
+                state.cwd = path;
+            }
+            "find" => {
+                let query = arg(0).ok_or(anyhow!("Usage: find <name>"))?;
+                let state = self.state.borrow();
+                let prefix = match state.cwd.as_str() {
+                    "/" => "/".to_string(),
+                    cwd => format!("{cwd}/"),
+                };
+                let hits = state
+                    .fs_hierarchy
+                    .search(query)
+                    .into_iter()
+                    .filter(|doc| {
+                        state
+                            .fs_hierarchy
+                            .path_of(&doc.id)
+                            .is_some_and(|path| path.starts_with(&prefix))
+                    })
+                    .collect::<Vec<_>>();
+                print_documents(&hits, &state.fs_hierarchy)?
+            }
+            "info" => {
+                let state = self.state.borrow();
+                let path = state.resolve(arg(0).ok_or(anyhow!("Usage: info <path>"))?);
+                let doc = state
+                    .fs_hierarchy
+                    .find_by_path(&path)
+                    .ok_or(anyhow!("No such file or folder: '{path}'"))?;
+                print_document_info(doc, &state.fs_hierarchy)?
+            }
+            "get" => {
+                let path = arg(0).ok_or(anyhow!("Usage: get <path> [local_dir]"))?;
+                let out_path = arg(1).unwrap_or(".").to_string();
+                let (fs_hierarchy, path) = {
+                    let state = self.state.borrow();
+                    (state.fs_hierarchy.clone(), state.resolve(path))
+                };
+                let doc = fs_hierarchy
+                    .find_by_path(&path)
+                    .ok_or(anyhow!("No such file or folder: '{path}'"))?;
+                check_output_path(&out_path, true)?;
+                let reporter = reporter();
+                let _lock = lock_dir(&out_path, "download", self.wait, reporter.as_ref()).await?;
+                download_documents(
+                    &self.client,
+                    &fs_hierarchy,
+                    std::slice::from_ref(&doc.id),
+                    DownloadOptions {
+                        out_path,
+                        udp_mode: self.udp_mode,
+                        override_mode: self.override_mode,
+                    },
+                    reporter.as_ref(),
+                )
+                .await?
+            }
+            "put" => {
+                let local_path = arg(0).ok_or(anyhow!("Usage: put <local_file> [path]"))?;
+                let folder_id = {
+                    let state = self.state.borrow();
+                    let folder =
+                        find_folder(&state.fs_hierarchy, &state.resolve(arg(1).unwrap_or(".")))?;
+                    folder.id.clone()
+                };
+                println!("{}", format!("Uploading {local_path}...").purple());
+                upload_file(&self.client, &folder_id, Path::new(local_path)).await?;
+                println!("{}", "Uploaded".green());
+                self.refresh().await?;
+            }
+            "refresh" => self.refresh().await?,
+            command => {
+                return Err(anyhow!(
+                    "Unknown command '{command}', type 'help' to list the commands"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    async fn refresh(&mut self) -> Result<()> {
+        let fs_hierarchy = reload_documents(&self.client, reporter().as_ref()).await?;
+        let mut state = self.state.borrow_mut();
+        state.fs_hierarchy = Rc::new(fs_hierarchy);
+        // the current folder may have been deleted in the meantime
+        if find_folder(&state.fs_hierarchy, &state.cwd).is_err() {
+            state.cwd = "/".to_string();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,708 @@
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    future::Future,
+    io::{self, Stdout},
+    path::PathBuf,
+    pin::Pin,
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use crossterm::{
+    event::{Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::StreamExt;
+use indicatif::HumanBytes;
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, Gauge, List, ListItem, ListState, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use remarkable2_downloader::{
+    cmd::{
+        download::{download_documents, DownloadOptions},
+        upload::{upload_files, UploadTask},
+    },
+    events::{Event, Reporter},
+    lock::{lock_dir, Wait},
+    scheme::DocType,
+    utils::check_output_path,
+    RemarkableFSHierarchy,
+};
+
+use super::{
+    browse::{format_date, sort_documents, SortBy},
+    reload_documents,
+};
+
+const HELP: &str = "↑/↓ move  →/← open/close  space select  d download  u queue upload  s start uploads  r refresh  q quit";
+/// number of messages kept in the status panel
+const LOG_SIZE: usize = 50;
+
+/// State of the transfers, updated by the library through [`TuiReporter`] and displayed live
+#[derive(Default)]
+struct TransferStatus {
+    total: usize,
+    done: usize,
+    failed: usize,
+    total_bytes: u64,
+    done_bytes: u64,
+    current: Option<String>,
+    current_bytes: u64,
+    log: VecDeque<String>,
+}
+
+impl TransferStatus {
+    fn log(&mut self, message: String) {
+        if self.log.len() == LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back(message);
+    }
+}
+
+struct TuiReporter {
+    status: Rc<RefCell<TransferStatus>>,
+}
+
+impl Reporter for TuiReporter {
+    fn report(&self, event: Event) {
+        let mut status = self.status.borrow_mut();
+        match event {
+            Event::TransferPlanned { total, total_bytes } => {
+                status.total = total;
+                status.total_bytes = total_bytes;
+                status.done = 0;
+                status.failed = 0;
+                status.done_bytes = 0;
+            }
+            Event::DownloadStarted { name, .. } => {
+                status.current = Some(format!("Downloading {name}"));
+                status.current_bytes = 0;
+            }
+            Event::DownloadProgress { downloaded, .. } => status.current_bytes = downloaded,
+            Event::UploadStarted { path, folder } => {
+                status.current = Some(format!("Uploading {path} into {folder}"));
+                status.current_bytes = 0;
+            }
+            Event::DownloadFinished { size, .. } => {
+                status.done += 1;
+                status.done_bytes += size as u64;
+                status.current = None;
+            }
+            Event::UploadFinished { size, .. } => {
+                status.done += 1;
+                status.done_bytes += size;
+                status.current = None;
+            }
+            Event::DownloadFailed { name, error, .. } => {
+                status.failed += 1;
+                status.current = None;
+                status.log(format!("Failed to download '{name}': {error}"));
+            }
+            Event::UploadFailed { path, error } => {
+                status.failed += 1;
+                status.current = None;
+                status.log(format!("Failed to upload '{path}': {error}"));
+            }
+            Event::Info { message }
+            | Event::Warning { message }
+            | Event::Skipped { reason: message }
+            | Event::Completed { message }
+            | Event::Interrupted { message } => status.log(message),
+            Event::SmartModeAdded { .. }
+            | Event::SmartModeSkipped { .. }
+            | Event::DownloadReused { .. }
+            | Event::Deduplicated { .. } => {}
+        }
+    }
+}
+
+/// A line of the tree panel
+struct Row {
+    id: String,
+    name: String,
+    depth: usize,
+    is_folder: bool,
+}
+
+enum Mode {
+    Browse,
+    /// typing the folder where the selection is downloaded
+    DownloadPrompt(String),
+    /// typing the path of a local file to upload
+    UploadPrompt(String),
+}
+
+/// Outcome of a background transfer, uploads change the documents so the tree is fetched again
+type Transfer = Pin<Box<dyn Future<Output = Result<Option<RemarkableFSHierarchy>>>>>;
+
+struct App {
+    fs_hierarchy: Rc<RemarkableFSHierarchy>,
+    client: reqwest::Client,
+    udp_mode: bool,
+    override_mode: bool,
+    /// for an output folder locked by another run
+    wait: Wait,
+    rows: Vec<Row>,
+    list_state: ListState,
+    expanded: HashSet<String>,
+    selected: HashSet<String>,
+    upload_queue: Vec<UploadTask>,
+    mode: Mode,
+    status: Rc<RefCell<TransferStatus>>,
+    transfer: Option<Transfer>,
+}
+
+impl App {
+    fn new(
+        fs_hierarchy: RemarkableFSHierarchy,
+        udp_mode: bool,
+        override_mode: bool,
+        wait: Wait,
+    ) -> Self {
+        let mut app = Self {
+            fs_hierarchy: Rc::new(fs_hierarchy),
+            client: reqwest::Client::new(),
+            udp_mode,
+            override_mode,
+            wait,
+            rows: vec![],
+            list_state: ListState::default(),
+            expanded: HashSet::new(),
+            selected: HashSet::new(),
+            upload_queue: vec![],
+            mode: Mode::Browse,
+            status: Rc::new(RefCell::new(TransferStatus::default())),
+            transfer: None,
+        };
+        app.build_rows();
+        app.list_state.select(Some(0));
+        app
+    }
+
+    /// flatten the expanded part of the tree into the rows of the tree panel
+    fn build_rows(&mut self) {
+        fn add_rows(
+            fs_hierarchy: &RemarkableFSHierarchy,
+            expanded: &HashSet<String>,
+            folder_id: &str,
+            depth: usize,
+            rows: &mut Vec<Row>,
+        ) {
+            let mut docs = fs_hierarchy.children(folder_id);
+            sort_documents(&mut docs, SortBy::Name);
+            for doc in docs {
+                let is_folder = doc.doc_type == DocType::CollectionType;
+                rows.push(Row {
+                    id: doc.id.clone(),
+                    name: doc.vissible_name.clone(),
+                    depth,
+                    is_folder,
+                });
+                if is_folder && expanded.contains(&doc.id) {
+                    add_rows(fs_hierarchy, expanded, &doc.id, depth + 1, rows);
+                }
+            }
+        }
+
+        let mut rows = vec![];
+        add_rows(&self.fs_hierarchy, &self.expanded, "", 0, &mut rows);
+        self.rows = rows;
+        if let Some(index) = self.list_state.selected() {
+            if index >= self.rows.len() {
+                self.list_state
+                    .select(Some(self.rows.len().saturating_sub(1)));
+            }
+        }
+    }
+
+    fn current_row(&self) -> Option<&Row> {
+        self.rows.get(self.list_state.selected()?)
+    }
+
+    /// folder under the cursor, or the folder of the document under the cursor ("" is for root)
+    fn current_folder(&self) -> String {
+        match self.current_row() {
+            Some(row) if row.is_folder => row.id.clone(),
+            Some(row) => self
+                .fs_hierarchy
+                .find_document(&row.id)
+                .map(|doc| doc.parent.clone())
+                .unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    fn log(&self, message: String) {
+        self.status.borrow_mut().log(message);
+    }
+
+    fn move_cursor(&mut self, offset: isize) {
+        if self.rows.is_empty() {
+            return;
+        }
+        let index = self.list_state.selected().unwrap_or(0) as isize + offset;
+        self.list_state
+            .select(Some(index.clamp(0, self.rows.len() as isize - 1) as usize));
+    }
+
+    /// returns false when the app must quit
+    fn on_key(&mut self, key: KeyEvent) -> bool {
+        match &mut self.mode {
+            Mode::DownloadPrompt(input) | Mode::UploadPrompt(input) => {
+                match key.code {
+                    KeyCode::Char(c) => input.push(c),
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Esc => self.mode = Mode::Browse,
+                    KeyCode::Enter => {
+                        let mode = std::mem::replace(&mut self.mode, Mode::Browse);
+                        match mode {
+                            Mode::DownloadPrompt(out_path) => self.start_download(out_path),
+                            Mode::UploadPrompt(path) => self.queue_upload(path),
+                            Mode::Browse => {}
+                        }
+                    }
+                    _ => {}
+                }
+                return true;
+            }
+            Mode::Browse => {}
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::PageUp => self.move_cursor(-10),
+            KeyCode::PageDown => self.move_cursor(10),
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => {
+                if let Some(row) = self.current_row().filter(|row| row.is_folder) {
+                    let id = row.id.clone();
+                    if !self.expanded.remove(&id) {
+                        self.expanded.insert(id);
+                    }
+                    self.build_rows();
+                }
+            }
+            KeyCode::Left | KeyCode::Char('h') => {
+                let Some(row) = self.current_row() else {
+                    return true;
+                };
+                if row.is_folder && self.expanded.contains(&row.id) {
+                    let id = row.id.clone();
+                    self.expanded.remove(&id);
+                } else {
+                    // go to the parent folder and close it
+                    let parent = self
+                        .fs_hierarchy
+                        .find_document(&row.id)
+                        .map(|doc| doc.parent.clone())
+                        .unwrap_or_default();
+                    if let Some(index) = self.rows.iter().position(|row| row.id == parent) {
+                        self.list_state.select(Some(index));
+                        self.expanded.remove(&parent);
+                    }
+                }
+                self.build_rows();
+            }
+            KeyCode::Char(' ') => {
+                if let Some(row) = self.current_row() {
+                    let id = row.id.clone();
+                    if !self.selected.remove(&id) {
+                        self.selected.insert(id);
+                    }
+                    self.move_cursor(1);
+                }
+            }
+            KeyCode::Char('d') => match self.selected.is_empty() {
+                true => self.log("Select documents or folders with 'space' first".to_string()),
+                false => self.mode = Mode::DownloadPrompt(".".to_string()),
+            },
+            KeyCode::Char('u') => self.mode = Mode::UploadPrompt(String::new()),
+            KeyCode::Char('s') => self.start_uploads(),
+            KeyCode::Char('r') => self.start_refresh(),
+            _ => {}
+        }
+        true
+    }
+
+    fn busy(&self) -> bool {
+        if self.transfer.is_some() {
+            self.log("A transfer is already running, wait for it to finish".to_string());
+        }
+        self.transfer.is_some()
+    }
+
+    fn start_download(&mut self, out_path: String) {
+        if self.busy() {
+            return;
+        }
+        if let Err(why) = check_output_path(&out_path, true) {
+            self.log(format!("Cannot use '{out_path}' as output folder: {why}"));
+            return;
+        }
+        let ids = self.selected.drain().collect::<Vec<_>>();
+        let fs_hierarchy = self.fs_hierarchy.clone();
+        let client = self.client.clone();
+        let reporter = TuiReporter {
+            status: self.status.clone(),
+        };
+        let options = DownloadOptions {
+            out_path,
+            udp_mode: self.udp_mode,
+            override_mode: self.override_mode,
+        };
+        let wait = self.wait;
+        self.transfer = Some(Box::pin(async move {
+            let _lock = lock_dir(&options.out_path, "download", wait, &reporter).await?;
+            download_documents(&client, &fs_hierarchy, &ids, options, &reporter).await?;
+            Ok(None)
+        }));
+    }
+
+    fn queue_upload(&mut self, path: String) {
+        let local_path = PathBuf::from(path.trim());
+        if !local_path.is_file() {
+            self.log(format!("'{}' is not a file", local_path.display()));
+            return;
+        }
+        let folder_id = self.current_folder();
+        let folder = self
+            .fs_hierarchy
+            .path_of(&folder_id)
+            .unwrap_or("/".to_string());
+        self.log(format!(
+            "Queued '{}' for upload into {folder}",
+            local_path.display()
+        ));
+        self.upload_queue.push(UploadTask {
+            local_path,
+            folder_id,
+            folder,
+        });
+    }
+
+    fn start_uploads(&mut self) {
+        if self.upload_queue.is_empty() {
+            self.log("No file queued for upload, queue some with 'u'".to_string());
+            return;
+        }
+        if self.busy() {
+            return;
+        }
+        let uploads = std::mem::take(&mut self.upload_queue);
+        let client = self.client.clone();
+        let reporter = TuiReporter {
+            status: self.status.clone(),
+        };
+        let udp_mode = self.udp_mode;
+        self.transfer = Some(Box::pin(async move {
+            upload_files(&client, &uploads, udp_mode, &reporter).await?;
+            reporter.report(Event::Completed {
+                message: format!("Finished uploading {} files", uploads.len()),
+            });
+            Ok(Some(reload_documents(&client, &reporter).await?))
+        }));
+    }
+
+    fn start_refresh(&mut self) {
+        if self.busy() {
+            return;
+        }
+        let client = self.client.clone();
+        let reporter = TuiReporter {
+            status: self.status.clone(),
+        };
+        self.log("Refreshing the documents...".to_string());
+        self.transfer = Some(Box::pin(async move {
+            Ok(Some(reload_documents(&client, &reporter).await?))
+        }));
+    }
+
+    fn on_transfer_done(&mut self, result: Result<Option<RemarkableFSHierarchy>>) {
+        match result {
+            Ok(Some(fs_hierarchy)) => {
+                self.fs_hierarchy = Rc::new(fs_hierarchy);
+                self.selected
+                    .retain(|id| self.fs_hierarchy.find_document(id).is_some());
+                self.build_rows();
+                self.log("Documents refreshed".to_string());
+            }
+            Ok(None) => {}
+            Err(why) => self.log(format!("Transfer failed: {why}")),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, help] = *Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(5),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(frame.size())
+        else {
+            return;
+        };
+        let [tree, details] = *Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(main)
+        else {
+            return;
+        };
+
+        let items = self
+            .rows
+            .iter()
+            .map(|row| {
+                let marker = match self.selected.contains(&row.id) {
+                    true => "[x] ",
+                    false => "[ ] ",
+                };
+                let icon = match (row.is_folder, self.expanded.contains(&row.id)) {
+                    (true, true) => "▾ ",
+                    (true, false) => "▸ ",
+                    (false, _) => "  ",
+                };
+                let style = match row.is_folder {
+                    true => Style::default()
+                        .fg(Color::Blue)
+                        .add_modifier(Modifier::BOLD),
+                    false => Style::default(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(marker),
+                    Span::raw("  ".repeat(row.depth)),
+                    Span::styled(format!("{icon}{}", row.name), style),
+                ]))
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(format!(
+                " Remarkable ({} selected, {} queued for upload) ",
+                self.selected.len(),
+                self.upload_queue.len()
+            )))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, tree, &mut self.list_state);
+
+        frame.render_widget(
+            Paragraph::new(self.details())
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL).title(" Details ")),
+            details,
+        );
+
+        self.draw_status(frame, status);
+        frame.render_widget(
+            Paragraph::new(HELP).style(Style::default().fg(Color::DarkGray)),
+            help,
+        );
+
+        let prompt = match &self.mode {
+            Mode::DownloadPrompt(input) => {
+                Some((" Download the selection into (local folder) ", input))
+            }
+            Mode::UploadPrompt(input) => Some((" Local pdf/epub file to upload ", input)),
+            Mode::Browse => None,
+        };
+        if let Some((title, input)) = prompt {
+            // clamped to the frame, which can be smaller than the popup
+            let area = Rect {
+                x: frame.size().width / 6,
+                y: (frame.size().height / 2).saturating_sub(2),
+                width: frame.size().width * 2 / 3,
+                height: 3,
+            }
+            .intersection(frame.size());
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(format!("{input}_"))
+                    .block(Block::default().borders(Borders::ALL).title(title)),
+                area,
+            );
+        }
+    }
+
+    fn details(&self) -> Vec<Line<'static>> {
+        let Some(doc) = self
+            .current_row()
+            .and_then(|row| self.fs_hierarchy.find_document(&row.id))
+        else {
+            return vec![];
+        };
+        let field = |name: &str, value: String| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{name:>12}: "),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(value),
+            ])
+        };
+
+        let mut lines = vec![
+            field("Name", doc.vissible_name.clone()),
+            field(
+                "Path",
+                self.fs_hierarchy.path_of(&doc.id).unwrap_or_default(),
+            ),
+            field("Modified", format_date(&doc.modified_client)),
+        ];
+        if doc.doc_type == DocType::CollectionType {
+            if let Some(folder) = self.fs_hierarchy.folder_hierarchy.find(&doc.id) {
+                let summary = self.fs_hierarchy.folder_summary(folder);
+                lines.push(field("Documents", summary.documents.to_string()));
+                lines.push(field("Folders", summary.folders.to_string()));
+                lines.push(field("Size", HumanBytes(summary.size).to_string()));
+            }
+        } else {
+            if let Some(metadata) = &doc.document_metadata {
+                if let Some(title) = &metadata.title {
+                    lines.push(field("Title", title.clone()));
+                }
+                if !metadata.authors.is_empty() {
+                    lines.push(field("Authors", metadata.authors.join(", ")));
+                }
+            }
+            if let Some(pages) = doc.page_count {
+                lines.push(field("Pages", pages.to_string()));
+            }
+            if let Some(current_page) = doc.current_page {
+                lines.push(field("Current page", (current_page + 1).to_string()));
+            }
+            if let Some(size) = doc.size_estimate() {
+                lines.push(field("Size", HumanBytes(size).to_string()));
+            }
+            if let Some(file_type) = &doc.file_type {
+                lines.push(field("File type", file_type.clone()));
+            }
+        }
+        let tags = doc.tag_names();
+        if !tags.is_empty() {
+            lines.push(field("Tags", tags.join(", ")));
+        }
+        lines.push(field("ID", doc.id.clone()));
+        lines
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let status = self.status.borrow();
+        let [gauge, log] = *Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(1)])
+            .split(area)
+        else {
+            return;
+        };
+
+        let done_bytes =
+            status.done_bytes + status.current.as_ref().map_or(0, |_| status.current_bytes);
+        let ratio = match status.total_bytes {
+            0 => 0.0,
+            total => (done_bytes as f64 / total as f64).min(1.0),
+        };
+        let label = format!(
+            "{}/{} files{} - {}/~{}{}",
+            status.done,
+            status.total,
+            match status.failed {
+                0 => String::new(),
+                failed => format!(" ({failed} failed)"),
+            },
+            HumanBytes(done_bytes),
+            HumanBytes(status.total_bytes),
+            match &status.current {
+                Some(current) => format!(" - {current}"),
+                None => String::new(),
+            }
+        );
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title(" Transfers "))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio)
+                .label(label),
+            gauge,
+        );
+
+        let lines = status
+            .log
+            .iter()
+            .rev()
+            .take(log.height as usize)
+            .rev()
+            .map(|message| Line::from(message.clone()))
+            .collect::<Vec<_>>();
+        frame.render_widget(Paragraph::new(lines), log);
+    }
+}
+
+fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+/// Full-screen library browser
+pub async fn run_tui(
+    fs_hierarchy: RemarkableFSHierarchy,
+    udp_mode: bool,
+    override_mode: bool,
+    wait: Wait,
+) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let result = event_loop(
+        &mut terminal,
+        App::new(fs_hierarchy, udp_mode, override_mode, wait),
+    )
+    .await;
+    restore_terminal(&mut terminal)?;
+    result
+}
+
+async fn event_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, mut app: App) -> Result<()> {
+    let mut events = EventStream::new();
+    // redraw regularly to show the progress of the transfers
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(TermEvent::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if !app.on_key(key) {
+                        break;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(why)) => return Err(why.into()),
+                None => break,
+            },
+            result = async { app.transfer.as_mut().unwrap().await }, if app.transfer.is_some() => {
+                app.transfer = None;
+                app.on_transfer_done(result);
+            }
+            _ = tick.tick() => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,479 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    cancel::{is_cancelled, track_temp_file, untrack_temp_file, Cancelled},
+    events::{Event, Reporter},
+    integrity::sha256_hex,
+    manifest::{Manifest, ManifestEntry, MANIFEST_FILE, METADATA_DIR},
+    utils::{join_path, temp_path, udp_continue},
+};
+
+use super::{plan::TransferPlan, queue::DownloadQueue, RemarkableFSHierarchy};
+
+/// In the name of an archive whose backup was interrupted, e.g:
+/// "remarkable-backup-20240131-180200.partial.zip"
+pub const PARTIAL_MARKER: &str = "partial";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveFormat {
+    /// tar archive compressed with zstd (.tar.zst)
+    TarZst,
+    /// zip archive (.zip)
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    /// guess the format of an archive from its file name
+    pub fn from_path(path: &str) -> Result<Self> {
+        if path.ends_with(".tar.zst") || path.ends_with(".tzst") {
+            Ok(ArchiveFormat::TarZst)
+        } else if path.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(anyhow!(
+                "Unknown archive format for '{path}', expected a '.tar.zst' or '.zip' file"
+            ))
+        }
+    }
+}
+
+/// Archive being written, entries are appended one by one so that files don't need to be kept in memory
+enum ArchiveWriter {
+    TarZst(tar::Builder<zstd::Encoder<'static, File>>),
+    Zip(ZipWriter<File>),
+}
+
+impl ArchiveWriter {
+    fn create(path: &Path, format: ArchiveFormat) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            ArchiveFormat::TarZst => {
+                ArchiveWriter::TarZst(tar::Builder::new(zstd::Encoder::new(file, 0)?))
+            }
+            ArchiveFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(file)),
+        })
+    }
+
+    fn append(&mut self, path: &str, bytes: &[u8]) -> Result<()> {
+        match self {
+            ArchiveWriter::TarZst(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(bytes.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(Local::now().timestamp().max(0) as u64);
+                header.set_cksum();
+                builder.append_data(&mut header, path, bytes)?;
+            }
+            ArchiveWriter::Zip(writer) => {
+                let options =
+                    FileOptions::default().compression_method(CompressionMethod::Deflated);
+                writer.start_file(path, options)?;
+                writer.write_all(bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_directory(&mut self, path: &str) -> Result<()> {
+        match self {
+            ArchiveWriter::TarZst(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                header.set_mode(0o755);
+                header.set_mtime(Local::now().timestamp().max(0) as u64);
+                header.set_cksum();
+                builder.append_data(&mut header, format!("{path}/"), std::io::empty())?;
+            }
+            ArchiveWriter::Zip(writer) => {
+                writer.add_directory(path, FileOptions::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            ArchiveWriter::TarZst(builder) => {
+                builder.into_inner()?.finish()?.sync_all()?;
+            }
+            ArchiveWriter::Zip(mut writer) => {
+                writer.finish()?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What an archive backup would download: every document, nothing is downloaded
+pub fn plan_archive(fs_hierarchy: &RemarkableFSHierarchy, out_path: &str) -> TransferPlan {
+    let manifest = Manifest::from_hierarchy(fs_hierarchy);
+    TransferPlan::new(
+        fs_hierarchy,
+        manifest.documents.iter().map(|entry| entry.id.as_str()),
+        out_path,
+    )
+}
+
+/// Download every document of the remarkable into a single archive created inside `out_path`,
+/// files are written in the archive as soon as they are downloaded.
+///
+/// Returns the path of the created archive
+pub async fn archive_backup(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    out_path: &str,
+    format: ArchiveFormat,
+    udp_mode: bool,
+    reporter: &dyn Reporter,
+) -> Result<String> {
+    let manifest = Manifest::from_hierarchy(fs_hierarchy);
+    let plan = plan_archive(fs_hierarchy, out_path);
+    plan.check_space(out_path, reporter)?;
+    let name = format!("remarkable-backup-{}", Local::now().format("%Y%m%d-%H%M%S"));
+    let archive_path = join_path(out_path, &format!("{name}.{}", format.extension()));
+    reporter.report(Event::Info {
+        message: format!("Writing the backup into '{archive_path}'"),
+    });
+    // written next to its final path, so that an interrupted backup never looks like a complete one:
+    // it is named `<name>.partial.<extension>` instead
+    let partial_path = join_path(
+        out_path,
+        &format!("{name}.{PARTIAL_MARKER}.{}", format.extension()),
+    );
+    let temp = temp_path(Path::new(&archive_path));
+    track_temp_file(&temp);
+    let written = async {
+        let mut archive = ArchiveWriter::create(&temp, format)?;
+
+        for folder in &manifest.folders {
+            archive.add_directory(&folder.path)?;
+        }
+
+        reporter.report(Event::TransferPlanned {
+            total: manifest.documents.len(),
+            total_bytes: manifest
+                .documents
+                .iter()
+                .filter_map(|entry| entry.size_estimate())
+                .sum(),
+        });
+
+        let client = reqwest::Client::new();
+        let mut archived = Manifest {
+            documents: vec![],
+            ..manifest.clone()
+        };
+        let mut downloads = DownloadQueue::new(&client, reporter);
+        for entry in &manifest.documents {
+            let doc = udp_continue!(
+                fs_hierarchy
+                    .find_document(&entry.id)
+                    .ok_or(anyhow!("No file with the ID '{}'", entry.id)),
+                udp_mode,
+                reporter,
+                format!("Failed to download '{}'", entry.name)
+            );
+            downloads.push(entry, doc, entry.name.clone());
+        }
+
+        while let Some((entry, result)) = downloads.next().await {
+            let bytes = udp_continue!(
+                result,
+                udp_mode,
+                reporter,
+                format!("Failed to download '{}'", entry.name)
+            );
+            // a half-written entry would corrupt the ones after it, only downloads can be skipped
+            archive
+                .append(&entry.path, &bytes)
+                .map_err(|why| anyhow!("Failed to write '{}' in the archive: {why}", entry.name))?;
+
+            if let Some(doc) = fs_hierarchy.find_document(&entry.id) {
+                let metadata = serde_json::to_vec_pretty(doc)?;
+                archive.append(&format!("{METADATA_DIR}/{}.json", entry.id), &metadata)?;
+            }
+            archived.documents.push(ManifestEntry {
+                sha256: Some(sha256_hex(&bytes)),
+                ..entry.clone()
+            });
+        }
+
+        // only documents that made it to the archive are listed in the manifest, in the order of the tree
+        archived.documents.sort_by_key(|archived| {
+            manifest
+                .documents
+                .iter()
+                .position(|entry| entry.id == archived.id)
+        });
+        archive.append(MANIFEST_FILE, &serde_json::to_vec_pretty(&archived)?)?;
+        archive.finish()?;
+        let cancelled = is_cancelled();
+        fs::rename(
+            &temp,
+            match cancelled {
+                true => &partial_path,
+                false => &archive_path,
+            },
+        )?;
+        Ok::<_, anyhow::Error>((
+            archived.documents.len(),
+            manifest.documents.len(),
+            cancelled,
+        ))
+    }
+    .await;
+    untrack_temp_file(&temp);
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    let (archived, total, cancelled) = written?;
+
+    if cancelled {
+        reporter.report(Event::Interrupted {
+            message: format!(
+                "Backup interrupted, '{partial_path}' only holds {archived} of the {total} documents"
+            ),
+        });
+        return Err(Cancelled.into());
+    }
+    reporter.report(Event::Completed {
+        message: format!("Finished writing archive, go see: '{archive_path}'"),
+    });
+
+    Ok(archive_path)
+}
+
+/// Read the manifest of a backup archive, if it has one
+pub fn read_manifest(path: &str) -> Result<Option<Manifest>> {
+    match ArchiveFormat::from_path(path)? {
+        ArchiveFormat::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.path()?.as_os_str() == MANIFEST_FILE {
+                    let mut raw = vec![];
+                    entry.read_to_end(&mut raw)?;
+                    return Ok(Some(serde_json::from_slice(&raw)?));
+                }
+            }
+            Ok(None)
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            let manifest = match archive.by_name(MANIFEST_FILE) {
+                Ok(mut file) => {
+                    let mut raw = vec![];
+                    file.read_to_end(&mut raw)?;
+                    Some(serde_json::from_slice(&raw)?)
+                }
+                Err(_) => None,
+            };
+            Ok(manifest)
+        }
+    }
+}
+
+/// Call `f` with the path and content of every file of an archive, in the order they are stored
+pub fn for_each_file(path: &str, mut f: impl FnMut(&str, &[u8])) -> Result<()> {
+    match ArchiveFormat::from_path(path)? {
+        ArchiveFormat::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type() != tar::EntryType::Regular {
+                    continue;
+                }
+                let name = entry.path()?.to_string_lossy().to_string();
+                let mut bytes = vec![];
+                entry.read_to_end(&mut bytes)?;
+                f(&name, &bytes);
+            }
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i)?;
+                if !file.is_file() {
+                    continue;
+                }
+                let name = file.name().to_string();
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)?;
+                f(&name, &bytes);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the raw entries of an archive, used when there is no manifest in it
+fn raw_entries(path: &str) -> Result<Vec<String>> {
+    match ArchiveFormat::from_path(path)? {
+        ArchiveFormat::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            let mut names = vec![];
+            for entry in archive.entries()? {
+                names.push(entry?.path()?.to_string_lossy().to_string());
+            }
+            Ok(names)
+        }
+        ArchiveFormat::Zip => {
+            let archive = ZipArchive::new(File::open(path)?)?;
+            Ok(archive.file_names().map(|name| name.to_string()).collect())
+        }
+    }
+}
+
+/// Content of a backup archive
+pub enum ArchiveListing {
+    Manifest(Manifest),
+    /// the archive has no manifest, only the name of its entries is known
+    Raw(Vec<String>),
+}
+
+pub fn list_archive(path: &str) -> Result<ArchiveListing> {
+    if !Path::new(path).is_file() {
+        return Err(anyhow!("'{path}' is not a file"));
+    }
+
+    Ok(match read_manifest(path)? {
+        Some(manifest) => ArchiveListing::Manifest(manifest),
+        None => ArchiveListing::Raw(raw_entries(path)?),
+    })
+}
+
+pub fn extract_archive(path: &str, out_path: &str) -> Result<()> {
+    if !Path::new(path).is_file() {
+        return Err(anyhow!("'{path}' is not a file"));
+    }
+    fs::create_dir_all(out_path)?;
+
+    match ArchiveFormat::from_path(path)? {
+        ArchiveFormat::TarZst => {
+            let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(path)?)?);
+            archive.unpack(out_path)?;
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = ZipArchive::new(File::open(path)?)?;
+            // `extract` refuses entries that would be written outside of `out_path`
+            archive.extract(out_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            documents: vec![ManifestEntry {
+                id: "dune".to_string(),
+                path: "root/Books/Dune.pdf".to_string(),
+                name: "Dune".to_string(),
+                sha256: Some(sha256_hex(b"%PDF-dune")),
+                ..Default::default()
+            }],
+            ..Manifest::new()
+        }
+    }
+
+    fn round_trip(format: ArchiveFormat) {
+        let manifest = manifest();
+        let dir = TempDir::new(&format!("archive-{}", format.extension()));
+        let path = dir.join(&format!("backup.{}", format.extension()));
+        let mut archive = ArchiveWriter::create(Path::new(&path), format).unwrap();
+        archive.add_directory("root/Books").unwrap();
+        archive.append("root/Books/Dune.pdf", b"%PDF-dune").unwrap();
+        archive
+            .append(
+                MANIFEST_FILE,
+                &serde_json::to_vec_pretty(&manifest).unwrap(),
+            )
+            .unwrap();
+        archive.finish().unwrap();
+
+        assert_eq!(read_manifest(&path).unwrap().as_ref(), Some(&manifest));
+        match list_archive(&path).unwrap() {
+            ArchiveListing::Manifest(listed) => assert_eq!(listed, manifest),
+            ArchiveListing::Raw(_) => panic!("the manifest was not found"),
+        }
+
+        let mut files = vec![];
+        for_each_file(&path, |name, bytes| {
+            files.push((name.to_string(), bytes.to_vec()))
+        })
+        .unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0],
+            ("root/Books/Dune.pdf".to_string(), b"%PDF-dune".to_vec())
+        );
+        assert_eq!(files[1].0, MANIFEST_FILE);
+
+        let out = dir.join("extracted");
+        extract_archive(&path, &out).unwrap();
+        assert_eq!(
+            fs::read(Path::new(&out).join("root/Books/Dune.pdf")).unwrap(),
+            b"%PDF-dune"
+        );
+        assert!(Path::new(&out).join(MANIFEST_FILE).is_file());
+    }
+
+    #[test]
+    fn tar_zst_round_trip() {
+        round_trip(ArchiveFormat::TarZst);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        round_trip(ArchiveFormat::Zip);
+    }
+
+    #[test]
+    fn archive_without_manifest() {
+        let dir = TempDir::new("archive-raw");
+        let path = dir.join("backup.zip");
+        let mut archive = ArchiveWriter::create(Path::new(&path), ArchiveFormat::Zip).unwrap();
+        archive.append("root/Notes.pdf", b"%PDF-notes").unwrap();
+        archive.finish().unwrap();
+
+        assert_eq!(read_manifest(&path).unwrap(), None);
+        match list_archive(&path).unwrap() {
+            ArchiveListing::Raw(names) => assert_eq!(names, ["root/Notes.pdf"]),
+            ArchiveListing::Manifest(_) => panic!("the archive has no manifest"),
+        }
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(
+            ArchiveFormat::from_path("a.tar.zst").unwrap(),
+            ArchiveFormat::TarZst
+        );
+        assert_eq!(
+            ArchiveFormat::from_path("a.partial.zip").unwrap(),
+            ArchiveFormat::Zip
+        );
+        assert!(ArchiveFormat::from_path("a.tar.gz").is_err());
+    }
+}
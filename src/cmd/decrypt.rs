@@ -0,0 +1,87 @@
+use anyhow::{anyhow, Result};
+
+use crate::{
+    crypto::{encrypted_manifest_file, Decryption},
+    events::{Event, Reporter},
+    integrity::sha256_hex,
+    manifest::{Manifest, MANIFEST_FILE},
+    storage::{LocalStorage, Storage},
+};
+
+/// Decrypt the encrypted backup `backup_path` (a local folder or the URL of a storage) into the
+/// local folder `out_path`: the documents get back their path on the remarkable and the manifest is
+/// written in clear, so that the copy can be verified and restored like any other backup.
+///
+/// Returns the number of decrypted documents
+pub async fn decrypt_backup(
+    backup_path: &str,
+    out_path: &str,
+    decryption: &Decryption,
+    override_mode: bool,
+    reporter: &dyn Reporter,
+) -> Result<usize> {
+    let storage = Storage::parse(backup_path)?;
+    let manifest_file = encrypted_manifest_file();
+    let raw = storage.read(&manifest_file).await?.ok_or(anyhow!(
+        "No '{manifest_file}' in '{}', it is not an encrypted backup",
+        storage.display("")
+    ))?;
+    let decryption = decryption.clone().for_backup(&storage).await?;
+    let raw = decryption
+        .decrypt(&raw)
+        .map_err(|why| anyhow!("Cannot decrypt the manifest of the backup: {why}"))?;
+    let mut manifest: Manifest = serde_json::from_slice(&raw)?;
+    reporter.report(Event::Info {
+        message: format!(
+            "Decrypting the {} documents of the backup made the {}",
+            manifest.documents.len(),
+            manifest.created_at
+        ),
+    });
+
+    let output = LocalStorage::new(out_path);
+    let mut decrypted = vec![];
+    for mut entry in manifest.documents {
+        let stored_path = entry
+            .stored_path
+            .take()
+            .unwrap_or_else(|| entry.path.clone());
+        let Some(bytes) = storage.read(&stored_path).await? else {
+            reporter.report(Event::Warning {
+                message: format!("'{}' is missing from the backup", entry.path),
+            });
+            continue;
+        };
+        let bytes = match decryption.decrypt(&bytes) {
+            Ok(bytes) => bytes,
+            Err(why) => {
+                reporter.report(Event::Warning {
+                    message: format!("Failed to decrypt '{}': {why}", entry.path),
+                });
+                continue;
+            }
+        };
+        if entry
+            .sha256
+            .as_ref()
+            .is_some_and(|sha256| *sha256 != sha256_hex(&bytes))
+        {
+            reporter.report(Event::Warning {
+                message: format!(
+                    "'{}' does not match the checksum recorded in the backup",
+                    entry.path
+                ),
+            });
+        }
+        output.write(&entry.path, &bytes, override_mode)?;
+        decrypted.push(entry);
+    }
+
+    let count = decrypted.len();
+    manifest.documents = decrypted;
+    output.write(MANIFEST_FILE, &serde_json::to_vec_pretty(&manifest)?, true)?;
+    reporter.report(Event::Completed {
+        message: format!("Decrypted {count} documents, go see: '{out_path}'"),
+    });
+    Ok(count)
+}
@@ -0,0 +1,573 @@
+//! Content-addressed directory backups (`backup --dedup`): every exported file is stored once, as
+//! `objects/<sha256>.pdf`, however many documents have this content, in the folders of the
+//! remarkable or in earlier backups. The manifest links each document to its file and, in a local
+//! folder, each document is also a hard link to it so that the backup reads like any other.
+//!
+//! Each run also saves its manifest in `snapshots/`: the files of the documents of every earlier
+//! backup stay in `objects/`, a new snapshot only costs the files that changed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+
+use crate::{
+    events::{Event, Reporter},
+    integrity::sha256_hex,
+    manifest::{Manifest, ManifestEntry, MANIFEST_FILE},
+    scheme::RmkFile,
+    storage::{Storage, StoredFile},
+    utils::{join_path, udp_continue},
+};
+
+use super::RemarkableFSHierarchy;
+
+/// Folder of a content-addressed backup where the files are stored, by checksum
+pub const OBJECTS_DIR: &str = "objects";
+/// Folder of a content-addressed backup where the manifest of each run is kept
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+
+/// where the file with the checksum `sha256`, exported at `path`, is stored
+pub fn object_path(sha256: &str, path: &str) -> String {
+    let extension = path.rsplit_once('.').map_or("pdf", |(_, ext)| ext);
+    join_path(OBJECTS_DIR, &format!("{sha256}.{extension}"))
+}
+
+/// The manifest of the backup in `storage`, `None` if it has none or if it cannot be parsed
+pub async fn read_manifest(storage: &Storage) -> Result<Option<Manifest>> {
+    Ok(storage
+        .read(MANIFEST_FILE)
+        .await?
+        .and_then(|raw| serde_json::from_slice(&raw).ok()))
+}
+
+/// the file of `entry` when it is stored in `objects/`, with its checksum
+fn stored_object(entry: &ManifestEntry) -> Option<(&str, &str)> {
+    let stored_path = entry.stored_path.as_deref()?;
+    let sha256 = entry.sha256.as_deref()?;
+    (stored_path == object_path(sha256, &entry.path)).then_some((stored_path, sha256))
+}
+
+/// path of the exported file of every document, by ID, e.g: "root/Books/Dune.pdf"
+fn document_paths(fs_hierarchy: &RemarkableFSHierarchy) -> HashMap<String, String> {
+    Manifest::from_hierarchy(fs_hierarchy)
+        .documents
+        .into_iter()
+        .map(|entry| (entry.id, entry.path))
+        .collect()
+}
+
+/// The documents that did not change since the `previous` manifest of the backup was written: same
+/// path and modification date, with their file still in `stored` (and their link in a local
+/// folder). By path and dated now, so that smart mode skips them
+pub fn unchanged_documents(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    previous: Option<&Manifest>,
+    stored: &HashMap<String, StoredFile>,
+    local: bool,
+) -> HashMap<String, StoredFile> {
+    let Some(previous) = previous else {
+        return HashMap::new();
+    };
+    Manifest::from_hierarchy(fs_hierarchy)
+        .documents
+        .into_iter()
+        .filter_map(|entry| {
+            let old = previous.documents.iter().find(|old| old.id == entry.id)?;
+            let (object, _) = stored_object(old)?;
+            let file = stored.get(object)?;
+            let unchanged = old.path == entry.path
+                && old.modified_client == entry.modified_client
+                && (!local || stored.contains_key(&entry.path));
+            unchanged.then(|| {
+                let file = StoredFile {
+                    modified: Some(Utc::now()),
+                    ..*file
+                };
+                (entry.path, file)
+            })
+        })
+        .collect()
+}
+
+/// A document that is not downloaded, its file is already in the backup or is downloaded for
+/// another document of this run
+pub struct Reused {
+    pub id: String,
+    name: String,
+    /// path of the document whose file is reused, e.g: "root/Books/Dune.pdf"
+    pub same_as: String,
+    source: Source,
+}
+
+enum Source {
+    /// checksum of the stored file
+    Object(String),
+    /// ID of the document downloaded in this run
+    SameAs(String),
+}
+
+/// Split `files_to_download` between the documents to download and the ones whose file can be
+/// reused: documents of the `previous` manifest that were only moved, and if `reuse_identical`,
+/// documents with the same size and number of pages as a stored file that still matches its
+/// checksum, or as another document downloaded in this run.
+///
+/// Documents of the remarkable do not have a checksum, the size and number of pages are only a
+/// hint: two documents annotated differently may look identical, hence the option
+pub async fn plan_reuse(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    files_to_download: Vec<(String, String)>,
+    previous: Option<&Manifest>,
+    storage: &Storage,
+    reuse_identical: bool,
+    reporter: &dyn Reporter,
+) -> Result<(Vec<(String, String)>, Vec<Reused>)> {
+    let previous_documents = previous.map_or(&[][..], |previous| &previous.documents);
+    let paths = document_paths(fs_hierarchy);
+    // stored files already checked, by checksum
+    let mut intact: HashMap<String, bool> = HashMap::new();
+    // first document downloaded in this run with a given size and number of pages, and its path
+    let mut first_seen: HashMap<(String, i64), (String, String)> = HashMap::new();
+    let mut downloads = vec![];
+    let mut reused = vec![];
+
+    for (id, name) in files_to_download {
+        let Some(doc) = fs_hierarchy.find_document(&id) else {
+            downloads.push((id, name));
+            continue;
+        };
+        let fingerprint = doc
+            .size_in_bytes
+            .clone()
+            .zip(doc.page_count)
+            .filter(|_| reuse_identical);
+        // the same document first (e.g: moved to another folder), then the ones looking identical
+        let candidates = previous_documents
+            .iter()
+            .filter(|old| old.id == id && old.modified_client == doc.modified_client)
+            .chain(previous_documents.iter().filter(|old| {
+                fingerprint.is_some()
+                    && old.size_in_bytes.clone().zip(old.page_count) == fingerprint
+            }));
+
+        let mut found = None;
+        for old in candidates {
+            let Some((object, sha256)) = stored_object(old) else {
+                continue;
+            };
+            if !intact.contains_key(sha256) {
+                let bytes = storage.read(object).await?;
+                let is_intact = bytes.is_some_and(|bytes| sha256_hex(&bytes) == sha256);
+                intact.insert(sha256.to_string(), is_intact);
+            }
+            if intact[sha256] {
+                found = Some((Source::Object(sha256.to_string()), old.path.clone()));
+                break;
+            }
+        }
+        if found.is_none() {
+            if let Some(fingerprint) = fingerprint {
+                match first_seen.get(&fingerprint) {
+                    Some((first_id, first_path)) => {
+                        found = Some((Source::SameAs(first_id.clone()), first_path.clone()))
+                    }
+                    None => {
+                        let path = paths.get(&id).cloned().unwrap_or_else(|| name.clone());
+                        first_seen.insert(fingerprint, (id.clone(), path));
+                    }
+                }
+            }
+        }
+
+        match found {
+            Some((source, same_as)) => {
+                reporter.report(Event::DownloadReused {
+                    id: id.clone(),
+                    name: name.clone(),
+                    same_as: same_as.clone(),
+                });
+                reused.push(Reused {
+                    id,
+                    name,
+                    same_as,
+                    source,
+                });
+            }
+            None => downloads.push((id, name)),
+        }
+    }
+    Ok((downloads, reused))
+}
+
+/// Store the `downloaded` files in `storage`, each content once in `objects/`, and link the
+/// `reused` documents to theirs. In a local folder every document is also a hard link to its file.
+///
+/// Returns the checksum of the file of each stored document, by ID
+pub async fn store_files(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    downloaded: &[RmkFile],
+    reused: &[Reused],
+    storage: &Storage,
+    (udp_mode, override_mode): (bool, bool),
+    reporter: &dyn Reporter,
+) -> Result<HashMap<String, String>> {
+    let paths = document_paths(fs_hierarchy);
+    let objects = storage
+        .list()
+        .await?
+        .into_keys()
+        .filter(|path| path.starts_with(&format!("{OBJECTS_DIR}/")))
+        .collect::<HashSet<_>>();
+
+    // objects written or checked in this run
+    let mut intact = HashSet::new();
+    let mut checksums = HashMap::new();
+    for (id, name, bytes) in downloaded {
+        let Some(path) = paths.get(id) else {
+            continue;
+        };
+        let sha256 = sha256_hex(bytes);
+        let object = object_path(&sha256, path);
+        if !intact.contains(&object) {
+            // a damaged file is replaced by the downloaded one
+            let stored = match objects.contains(&object) {
+                true => storage.read(&object).await.ok().flatten(),
+                false => None,
+            };
+            if stored.is_none_or(|stored| sha256_hex(&stored) != sha256) {
+                let task = storage.write(&object, bytes, true).await;
+                udp_continue!(
+                    task,
+                    udp_mode,
+                    reporter,
+                    format!("Failed to write '{name}'")
+                );
+            }
+            intact.insert(object.clone());
+        }
+        let task = link(storage, &object, path, override_mode);
+        udp_continue!(
+            task,
+            udp_mode,
+            reporter,
+            format!("Failed to write '{name}'")
+        );
+        checksums.insert(id.clone(), sha256);
+    }
+
+    for Reused {
+        id, name, source, ..
+    } in reused
+    {
+        let Some(path) = paths.get(id) else {
+            continue;
+        };
+        let sha256 = match source {
+            Source::Object(sha256) => sha256.clone(),
+            Source::SameAs(same_as) => udp_continue!(
+                checksums.get(same_as).cloned().ok_or(anyhow!(
+                    "the identical document it reuses could not be downloaded"
+                )),
+                udp_mode,
+                reporter,
+                format!("Failed to store '{name}'")
+            ),
+        };
+        let task = link(storage, &object_path(&sha256, path), path, override_mode);
+        udp_continue!(
+            task,
+            udp_mode,
+            reporter,
+            format!("Failed to write '{name}'")
+        );
+        checksums.insert(id.clone(), sha256);
+    }
+    Ok(checksums)
+}
+
+/// Link the document at `path` to its file `object`: a hard link in a local folder, in a remote
+/// storage the manifest is the only link.
+///
+/// The link shares the modification date of the file, the one of its first copy: which documents
+/// use the file and since when is only recorded by the manifest and the snapshots
+fn link(storage: &Storage, object: &str, path: &str, overwrite: bool) -> Result<()> {
+    match storage {
+        Storage::Local(local) => local.link(object, path, overwrite),
+        _ => Ok(()),
+    }
+}
+
+/// (Re)write the manifest of the backup and keep a copy of it in `snapshots/`. The documents
+/// stored in this run get their new `checksums`, the others keep the file the `previous` manifest
+/// linked them to, if it is still there
+pub async fn write_manifest(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    checksums: &HashMap<String, String>,
+    previous: Option<&Manifest>,
+    storage: &Storage,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let stored = storage.list().await?;
+    let mut manifest = Manifest::from_hierarchy(fs_hierarchy);
+    let mut documents = vec![];
+    for mut entry in manifest.documents {
+        let sha256 = checksums.get(&entry.id).cloned().or_else(|| {
+            previous
+                .iter()
+                .flat_map(|previous| &previous.documents)
+                .find(|old| {
+                    old.id == entry.id
+                        && old.path == entry.path
+                        && old.modified_client == entry.modified_client
+                })
+                .and_then(stored_object)
+                .map(|(_, sha256)| sha256.to_string())
+        });
+        let Some(sha256) = sha256 else {
+            continue;
+        };
+        let object = object_path(&sha256, &entry.path);
+        if !stored.contains_key(&object) {
+            continue;
+        }
+        entry.sha256 = Some(sha256);
+        entry.stored_path = Some(object);
+        documents.push(entry);
+    }
+    manifest.documents = documents;
+
+    let mut files = HashSet::new();
+    let mut saved_bytes = 0;
+    for entry in &manifest.documents {
+        let Some(object) = &entry.stored_path else {
+            continue;
+        };
+        if !files.insert(object) {
+            saved_bytes += stored.get(object).map_or(0, |file| file.size);
+        }
+    }
+    reporter.report(Event::Deduplicated {
+        documents: manifest.documents.len(),
+        files: files.len(),
+        saved_bytes,
+    });
+
+    let raw = serde_json::to_vec_pretty(&manifest)?;
+    let date = DateTime::<Local>::from_str(&manifest.created_at).unwrap_or_else(|_| Local::now());
+    let snapshot = join_path(
+        SNAPSHOTS_DIR,
+        &format!("{}.json", date.format("%Y%m%d-%H%M%S")),
+    );
+    storage.write(&snapshot, &raw, true).await?;
+    storage.write(MANIFEST_FILE, &raw, true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        events::SilentReporter,
+        scheme::{DocType, RmkDocument},
+        storage::LocalStorage,
+        test_utils::TempDir,
+    };
+
+    fn storage(dir: &TempDir) -> Storage {
+        Storage::Local(LocalStorage::new(dir.path()))
+    }
+
+    const MODIFIED: &str = "2024-01-01T10:00:00.000Z";
+
+    fn document(id: &str, parent: &str, (size, pages): (&str, i64)) -> RmkDocument {
+        RmkDocument {
+            id: id.to_string(),
+            vissible_name: id.to_string(),
+            parent: parent.to_string(),
+            doc_type: DocType::DocumentType,
+            modified_client: MODIFIED.to_string(),
+            size_in_bytes: Some(size.to_string()),
+            page_count: Some(pages),
+            ..Default::default()
+        }
+    }
+
+    fn device() -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(vec![
+            RmkDocument {
+                id: "books".to_string(),
+                vissible_name: "Books".to_string(),
+                doc_type: DocType::CollectionType,
+                ..Default::default()
+            },
+            // moved from the root since the previous backup
+            document("dune", "books", ("481", 3)),
+            // its stored file was damaged
+            document("course", "", ("570", 4)),
+            // looks like "dune"
+            document("copy", "", ("481", 3)),
+            // new, and looking like each other
+            document("new", "", ("303", 1)),
+            document("new-copy", "", ("303", 1)),
+        ])
+    }
+
+    /// the previous backup: "dune" and "course" stored in `objects/`, the file of "course" damaged
+    async fn previous_backup(storage: &Storage) -> Manifest {
+        let mut manifest = Manifest::new();
+        for (id, fingerprint, bytes, stored) in [
+            ("dune", ("481", 3), &b"%PDF-dune"[..], &b"%PDF-dune"[..]),
+            ("course", ("570", 4), b"%PDF-course", b"%PDF-damaged"),
+        ] {
+            let mut entry = ManifestEntry::new(&document(id, "", fingerprint), "root");
+            let sha256 = sha256_hex(bytes);
+            let object = object_path(&sha256, &entry.path);
+            storage.write(&object, stored, true).await.unwrap();
+            entry.sha256 = Some(sha256);
+            entry.stored_path = Some(object);
+            manifest.documents.push(entry);
+        }
+        manifest
+    }
+
+    fn all_documents() -> Vec<(String, String)> {
+        ["dune", "course", "copy", "new", "new-copy"]
+            .iter()
+            .map(|id| (id.to_string(), id.to_string()))
+            .collect()
+    }
+
+    fn ids(files: &[(String, String)]) -> Vec<&str> {
+        files.iter().map(|(id, _)| id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn reuses_moved_documents_and_intact_files_only() {
+        let dir = TempDir::new("dedup-moved");
+        let storage = storage(&dir);
+        let previous = previous_backup(&storage).await;
+
+        let (downloads, reused) = plan_reuse(
+            &device(),
+            all_documents(),
+            Some(&previous),
+            &storage,
+            false,
+            &SilentReporter,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&downloads), ["course", "copy", "new", "new-copy"]);
+        assert_eq!(reused.len(), 1);
+        assert_eq!(reused[0].id, "dune");
+        assert_eq!(reused[0].same_as, "root/dune.pdf");
+    }
+
+    #[tokio::test]
+    async fn reuses_identical_documents_when_asked() {
+        let dir = TempDir::new("dedup-identical");
+        let storage = storage(&dir);
+        let previous = previous_backup(&storage).await;
+
+        let (downloads, reused) = plan_reuse(
+            &device(),
+            all_documents(),
+            Some(&previous),
+            &storage,
+            true,
+            &SilentReporter,
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids(&downloads), ["course", "new"]);
+        let reused = reused
+            .iter()
+            .map(|reused| (reused.id.as_str(), reused.same_as.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reused,
+            [
+                ("dune", "root/dune.pdf"),
+                ("copy", "root/dune.pdf"),
+                ("new-copy", "root/new.pdf")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stores_each_file_once_and_links_the_documents() {
+        let dir = TempDir::new("dedup-store");
+        let storage = storage(&dir);
+        let previous = previous_backup(&storage).await;
+        let fs_hierarchy = device();
+        let (downloads, reused) = plan_reuse(
+            &fs_hierarchy,
+            all_documents(),
+            Some(&previous),
+            &storage,
+            true,
+            &SilentReporter,
+        )
+        .await
+        .unwrap();
+        let dune_object = dir.join(&object_path(&sha256_hex(b"%PDF-dune"), "dune.pdf"));
+        let dune_modified = fs::metadata(&dune_object).unwrap().modified().unwrap();
+
+        let downloaded = downloads
+            .into_iter()
+            .map(|(id, name)| {
+                let bytes = format!("%PDF-{id}").into_bytes();
+                (id, name, bytes)
+            })
+            .collect::<Vec<_>>();
+        let checksums = store_files(
+            &fs_hierarchy,
+            &downloaded,
+            &reused,
+            &storage,
+            (false, true),
+            &SilentReporter,
+        )
+        .await
+        .unwrap();
+        assert_eq!(checksums.len(), 5);
+        assert_eq!(checksums["new-copy"], checksums["new"]);
+        assert_eq!(checksums["copy"], sha256_hex(b"%PDF-dune"));
+
+        // the damaged file of "course" is replaced by the downloaded one
+        assert_eq!(
+            fs::read(dir.join("root/course.pdf")).unwrap(),
+            b"%PDF-course"
+        );
+        assert_eq!(
+            fs::read(dir.join("root/Books/dune.pdf")).unwrap(),
+            b"%PDF-dune"
+        );
+        // linking does not date the shared file
+        assert_eq!(
+            fs::metadata(&dune_object).unwrap().modified().unwrap(),
+            dune_modified
+        );
+
+        write_manifest(
+            &fs_hierarchy,
+            &checksums,
+            Some(&previous),
+            &storage,
+            &SilentReporter,
+        )
+        .await
+        .unwrap();
+        let manifest = read_manifest(&storage).await.unwrap().unwrap();
+        assert_eq!(manifest.documents.len(), 5);
+        assert!(manifest
+            .documents
+            .iter()
+            .all(|entry| stored_object(entry).is_some()));
+        assert_eq!(fs::read_dir(dir.join(SNAPSHOTS_DIR)).unwrap().count(), 1);
+    }
+}
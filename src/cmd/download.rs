@@ -1 +1,161 @@
+use anyhow::{anyhow, Result};
 
+use crate::{
+    cancel::{is_cancelled, Cancelled},
+    events::{Event, Reporter},
+    scheme::DocType,
+    storage::Storage,
+    utils::{ensure_file_extension, join_path, udp_continue},
+};
+
+use super::{
+    dry_run::{DryRun, PlannedAction},
+    plan::TransferPlan,
+    queue::DownloadQueue,
+    RemarkableFSHierarchy,
+};
+
+pub struct DownloadOptions {
+    pub out_path: String,
+    pub udp_mode: bool,
+    pub override_mode: bool,
+}
+
+/// list the documents to download with their path relative to the output folder,
+/// folders are downloaded with everything inside them
+pub fn plan_download(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    ids: &[String],
+) -> Result<Vec<(String, String)>> {
+    let mut files = vec![];
+    for id in ids {
+        let doc = fs_hierarchy
+            .find_document(id)
+            .ok_or(anyhow!("No file with the ID '{id}'"))?;
+        match doc.doc_type {
+            DocType::CollectionType => {
+                let folder = fs_hierarchy
+                    .folder_hierarchy
+                    .find(id)
+                    .ok_or(anyhow!("Folder '{}' not found", doc.vissible_name))?;
+                for (file_id, folder_path) in folder.files_path("") {
+                    if let Some(file) = fs_hierarchy.find_document(&file_id) {
+                        let name = ensure_file_extension(&file.vissible_name);
+                        files.push((file_id, join_path(&folder_path, &name)));
+                    }
+                }
+            }
+            _ => files.push((doc.id.clone(), ensure_file_extension(&doc.vissible_name))),
+        }
+    }
+    Ok(files)
+}
+
+/// What downloading `ids` into `out_path` would do file by file, nothing is downloaded nor written
+pub fn dry_run_download(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    ids: &[String],
+    out_path: &str,
+    override_mode: bool,
+) -> Result<Vec<PlannedAction>> {
+    let mut dry_run = DryRun::default();
+    dry_run.create_folder(out_path);
+    for (id, relative_path) in plan_download(fs_hierarchy, ids)? {
+        dry_run.download(&id, &join_path(out_path, &relative_path), override_mode);
+    }
+    Ok(dry_run.finish())
+}
+
+/// Download documents and folders of the remarkable into `out_path`, a local folder or the URL of
+/// a storage (see [`Storage::parse`])
+pub async fn download_documents(
+    client: &reqwest::Client,
+    fs_hierarchy: &RemarkableFSHierarchy,
+    ids: &[String],
+    DownloadOptions {
+        out_path,
+        udp_mode,
+        override_mode,
+    }: DownloadOptions,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let storage = Storage::parse(&out_path)?;
+    let files = plan_download(fs_hierarchy, ids)?;
+    let plan = TransferPlan::new(
+        fs_hierarchy,
+        files.iter().map(|(id, _)| id.as_str()),
+        &out_path,
+    );
+    plan.check_space(&out_path, reporter)?;
+    reporter.report(Event::TransferPlanned {
+        total: files.len(),
+        total_bytes: plan.bytes,
+    });
+
+    let mut downloads = DownloadQueue::new(client, reporter);
+    for (id, relative_path) in files {
+        if !override_mode {
+            let exists = udp_continue!(
+                storage.exists(&relative_path).await,
+                udp_mode,
+                reporter,
+                "Failed to download file"
+            );
+            if exists {
+                udp_continue!(
+                    Err::<(), _>(anyhow!(
+                        "'{}' already exists",
+                        storage.display(&relative_path)
+                    )),
+                    udp_mode,
+                    reporter,
+                    "Failed to download file"
+                );
+            }
+        }
+
+        let name = relative_path
+            .rsplit_once('/')
+            .map_or(relative_path.as_str(), |(_, name)| name)
+            .to_string();
+        let doc = udp_continue!(
+            fs_hierarchy
+                .find_document(&id)
+                .ok_or(anyhow!("No file with the ID '{id}'")),
+            udp_mode,
+            reporter,
+            format!("Failed to download '{name}'")
+        );
+        downloads.push(relative_path, doc, name);
+    }
+
+    while let Some((relative_path, result)) = downloads.next().await {
+        let bytes = udp_continue!(
+            result,
+            udp_mode,
+            reporter,
+            format!("Failed to download '{}'", storage.display(&relative_path))
+        );
+
+        // the folders are created along with the file
+        let task = storage.write(&relative_path, &bytes, override_mode).await;
+        udp_continue!(task, udp_mode, reporter, "Failed to write file");
+    }
+
+    if is_cancelled() {
+        reporter.report(Event::Interrupted {
+            message: format!(
+                "Download interrupted, the files downloaded so far are in '{}'",
+                storage.display("")
+            ),
+        });
+        return Err(Cancelled.into());
+    }
+    reporter.report(Event::Completed {
+        message: format!(
+            "Finished downloading files, go see: '{}'",
+            storage.display("")
+        ),
+    });
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! What a transfer would do, action by action, without touching the remarkable nor the disk: the
+//! same selection, naming and conflict rules as the real transfer are applied.
+
+use std::{collections::HashSet, path::Path};
+
+use serde_derive::Serialize;
+
+/// One step of a transfer
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlannedAction {
+    CreateFolder {
+        path: String,
+    },
+    Download {
+        id: String,
+        path: String,
+    },
+    /// download a file over an existing one
+    Overwrite {
+        id: String,
+        path: String,
+    },
+    /// the file is not transferred
+    Skip {
+        path: String,
+        reason: String,
+    },
+    Delete {
+        path: String,
+    },
+    Upload {
+        local_path: String,
+        /// path of the folder in the remarkable, e.g: "/Books"
+        folder: String,
+        /// "" is for root
+        folder_id: String,
+    },
+}
+
+/// Actions of a dry run, along with the folders and files they would create so that the
+/// following actions see them
+#[derive(Default)]
+pub(crate) struct DryRun {
+    actions: Vec<PlannedAction>,
+    created: HashSet<String>,
+    deleted: Vec<String>,
+}
+
+impl DryRun {
+    pub fn exists(&self, path: &str) -> bool {
+        self.created.contains(path)
+            || (!self
+                .deleted
+                .iter()
+                .any(|deleted| Path::new(path).starts_with(deleted))
+                && Path::new(path).exists())
+    }
+
+    /// create `dir` and its missing parents
+    pub fn create_folder(&mut self, dir: &str) {
+        if dir.is_empty() || self.exists(dir) {
+            return;
+        }
+        if let Some(parent) = Path::new(dir).parent() {
+            self.create_folder(&parent.to_string_lossy());
+        }
+        self.created.insert(dir.to_string());
+        self.actions.push(PlannedAction::CreateFolder {
+            path: dir.to_string(),
+        });
+    }
+
+    /// download the document `id` into `path`, existing files are only replaced in override mode
+    pub fn download(&mut self, id: &str, path: &str, override_mode: bool) {
+        if let Some(parent) = Path::new(path).parent() {
+            self.create_folder(&parent.to_string_lossy());
+        }
+        let exists = self.exists(path);
+        let (id, path) = (id.to_string(), path.to_string());
+        if exists && !override_mode {
+            self.actions.push(PlannedAction::Skip {
+                reason: "already exists, use '--override-mode' to replace it".to_string(),
+                path,
+            });
+            return;
+        }
+        self.created.insert(path.clone());
+        self.actions.push(match exists {
+            true => PlannedAction::Overwrite { id, path },
+            false => PlannedAction::Download { id, path },
+        });
+    }
+
+    /// delete `path` and everything inside it
+    pub fn delete(&mut self, path: &str) {
+        if !self.exists(path) {
+            return;
+        }
+        self.created
+            .retain(|created| !Path::new(created).starts_with(path));
+        self.deleted.push(path.to_string());
+        self.actions.push(PlannedAction::Delete {
+            path: path.to_string(),
+        });
+    }
+
+    pub fn push(&mut self, action: PlannedAction) {
+        self.actions.push(action);
+    }
+
+    pub fn finish(self) -> Vec<PlannedAction> {
+        self.actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn follows_the_folders_and_files_it_would_create() {
+        let dir = TempDir::with_files("dry-run-create", &["root/Dune.pdf"]);
+        let mut dry_run = DryRun::default();
+        dry_run.download("dune", &dir.join("root/Dune.pdf"), false);
+        dry_run.download("dune", &dir.join("root/Dune.pdf"), true);
+        dry_run.download("course", &dir.join("root/Books/Course.pdf"), false);
+        dry_run.download("course", &dir.join("root/Books/Course.pdf"), false);
+        dry_run.create_folder(&dir.join("root/Books/Fantasy"));
+
+        assert_eq!(
+            dry_run.finish(),
+            [
+                PlannedAction::Skip {
+                    path: dir.join("root/Dune.pdf"),
+                    reason: "already exists, use '--override-mode' to replace it".to_string(),
+                },
+                PlannedAction::Overwrite {
+                    id: "dune".to_string(),
+                    path: dir.join("root/Dune.pdf"),
+                },
+                PlannedAction::CreateFolder {
+                    path: dir.join("root/Books"),
+                },
+                PlannedAction::Download {
+                    id: "course".to_string(),
+                    path: dir.join("root/Books/Course.pdf"),
+                },
+                PlannedAction::Skip {
+                    path: dir.join("root/Books/Course.pdf"),
+                    reason: "already exists, use '--override-mode' to replace it".to_string(),
+                },
+                PlannedAction::CreateFolder {
+                    path: dir.join("root/Books/Fantasy"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_what_it_would_delete() {
+        let dir = TempDir::with_files("dry-run-delete", &["root/Dune.pdf"]);
+        let mut dry_run = DryRun::default();
+        dry_run.delete(&dir.join("root"));
+        // gone, nothing to delete or overwrite anymore
+        dry_run.delete(&dir.join("root/Dune.pdf"));
+        dry_run.download("dune", &dir.join("root/Dune.pdf"), false);
+
+        let actions = dry_run.finish();
+        let count = |kind: fn(&PlannedAction) -> bool| actions.iter().filter(|a| kind(a)).count();
+        assert_eq!(count(|a| matches!(a, PlannedAction::Delete { .. })), 1);
+        assert_eq!(
+            count(|a| matches!(a, PlannedAction::CreateFolder { .. })),
+            1
+        );
+        assert_eq!(count(|a| matches!(a, PlannedAction::Download { .. })), 1);
+        assert_eq!(actions.len(), 3);
+    }
+
+    #[test]
+    fn serializes_the_actions_as_flat_records() {
+        let actions = [
+            PlannedAction::Download {
+                id: "dune".to_string(),
+                path: "root/Dune.pdf".to_string(),
+            },
+            PlannedAction::Upload {
+                local_path: "Dune.pdf".to_string(),
+                folder: "/Books".to_string(),
+                folder_id: "books".to_string(),
+            },
+        ];
+        assert_eq!(
+            serde_json::to_value(actions).unwrap(),
+            serde_json::json!([
+                {"action": "download", "id": "dune", "path": "root/Dune.pdf"},
+                {"action": "upload", "local_path": "Dune.pdf", "folder": "/Books", "folder_id": "books"},
+            ])
+        );
+    }
+}
@@ -1,29 +1,53 @@
-use std::{fs, path::Path, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{anyhow, Result};
 use chrono::DateTime;
-use colored::Colorize;
 
 use crate::{
+    cancel::{is_cancelled, Cancelled},
     cmd::FolderNode,
+    crypto::{ensure_no_plain_backup, Protection, DOCUMENTS_DIR},
+    events::{Event, Reporter, SilentReporter},
+    integrity::{sha256_hex, validate_file},
+    manifest::Manifest,
     scheme::{DocType, RmkDocument, RmkFile},
-    udp_continue,
-    utils::ensure_file_extension,
+    storage::{Storage, StoredFile},
+    utils::{ensure_file_extension, join_path, udp_continue},
 };
 
-use super::{copy_to_localfs, RemarkableFSHierarchy};
+use super::{
+    copy_to_storage, dedup,
+    dry_run::{DryRun, PlannedAction},
+    plan::TransferPlan,
+    queue::DownloadQueue,
+    RemarkableFSHierarchy,
+};
 
 pub struct BackupOptions {
     pub out_path: String,
     pub udp_mode: bool,
     pub override_mode: bool,
     pub smart_mode: bool,
+    /// encryption and obfuscation of the files, none by default
+    pub protection: Protection,
+    /// store each exported file once, by checksum (see [`super::dedup`])
+    pub dedup: bool,
+    /// with `dedup`, do not download the documents looking identical to a stored file
+    pub reuse_identical: bool,
 }
 
+/// `existing`: the files already in the backup, by path relative to its root
 fn which_files_to_download(
     fs_hierarchy: &RemarkableFSHierarchy,
-    out_path: &str,
+    existing: &HashMap<String, StoredFile>,
+    protection: &Protection,
     smart_mode: bool,
+    reporter: &dyn Reporter,
 ) -> Vec<(String, String)> {
     if !smart_mode {
         let files_to_download = fs_hierarchy
@@ -47,16 +71,19 @@ fn which_files_to_download(
     fn compare_files_time(
         folder_hierarchy: &FolderNode,
         files: &[RmkDocument],
+        existing: &HashMap<String, StoredFile>,
+        protection: &Protection,
         path: &str,
         add_all: bool,
+        reporter: &dyn Reporter,
     ) -> Vec<(String, String)> {
         // update path to the subfolder
-        let curr_path = &format!(
-            "{path}{}{}",
-            if path.ends_with('/') { "" } else { "/" },
-            folder_hierarchy.name
-        );
-        if add_all || !Path::new(curr_path).exists() {
+        let curr_path = &join_path(path, &folder_hierarchy.name);
+        let folder_prefix = format!("{curr_path}/");
+        // the documents of a backup with obfuscated names are not in folders
+        let folder_exists = protection.obfuscate_names
+            || existing.keys().any(|path| path.starts_with(&folder_prefix));
+        if add_all || !folder_exists {
             let mut file_to_download = files
                 .iter()
                 .filter_map(
@@ -69,7 +96,9 @@ fn which_files_to_download(
                 )
                 .collect::<Vec<_>>();
             for subdir in &folder_hierarchy.subfolders {
-                let mut sub_files = compare_files_time(subdir, files, curr_path, true);
+                let mut sub_files = compare_files_time(
+                    subdir, files, existing, protection, curr_path, true, reporter,
+                );
                 file_to_download.append(&mut sub_files);
             }
 
@@ -88,31 +117,39 @@ fn which_files_to_download(
                     let file_to_dl = (id.to_owned(), vissible_name.to_owned());
                     match folder_hierarchy.files_id.contains(id) {
                         true => {
-                            let rmkfile_elapsed = match DateTime::<chrono::Local>::from_str(modified_client) {
-                                Ok(d) => match (chrono::Local::now() - d).to_std() {
-                                    Ok(elasped) => elasped,
-                                    Err(_) => return Some(file_to_dl),
-                                }
-                                Err(_) => return Some(file_to_dl),
-                            };
-
-                            let path = format!("{curr_path}/{vissible_name}");
-                            let localfile_elapsed = match fs::metadata(path) {
-                                Ok(metadata) => match metadata.modified() {
-                                    Ok(creation_date) => match creation_date.elapsed() {
+                            let rmkfile_elapsed =
+                                match DateTime::<chrono::Local>::from_str(modified_client) {
+                                    Ok(d) => match (chrono::Local::now() - d).to_std() {
                                         Ok(elasped) => elasped,
                                         Err(_) => return Some(file_to_dl),
                                     },
                                     Err(_) => return Some(file_to_dl),
+                                };
+
+                            let path =
+                                protection.stored_path(id, &join_path(curr_path, vissible_name));
+                            let localfile_elapsed = match existing.get(&path) {
+                                Some(StoredFile {
+                                    modified: Some(modified),
+                                    ..
+                                }) => match (chrono::Utc::now() - modified).to_std() {
+                                    Ok(elasped) => elasped,
+                                    Err(_) => return Some(file_to_dl),
                                 },
-                                Err(_) => return Some(file_to_dl),
+                                _ => return Some(file_to_dl),
                             };
 
                             if rmkfile_elapsed <= localfile_elapsed {
-                                println!("{}", format!("[SMART_MODE]: adding '{vissible_name}', because change made since last download").purple());
+                                reporter.report(Event::SmartModeAdded {
+                                    id: id.to_owned(),
+                                    name: vissible_name.to_owned(),
+                                });
                                 Some(file_to_dl)
                             } else {
-                                println!("{}", format!("[SMART_MODE]: skipped '{vissible_name}', because no change made since last download").yellow());
+                                reporter.report(Event::SmartModeSkipped {
+                                    id: id.to_owned(),
+                                    name: vissible_name.to_owned(),
+                                });
                                 None
                             }
                         }
@@ -124,7 +161,15 @@ fn which_files_to_download(
 
         // create subfolders
         for subfolder_hierarchy in &folder_hierarchy.subfolders {
-            let mut subfiles = compare_files_time(subfolder_hierarchy, files, curr_path, false);
+            let mut subfiles = compare_files_time(
+                subfolder_hierarchy,
+                files,
+                existing,
+                protection,
+                curr_path,
+                false,
+                reporter,
+            );
             files_to_download.append(&mut subfiles);
         }
 
@@ -143,7 +188,208 @@ fn which_files_to_download(
         })
         .collect::<Vec<_>>();
 
-    compare_files_time(&fs_hierarchy.folder_hierarchy, &all_files, out_path, false)
+    compare_files_time(
+        &fs_hierarchy.folder_hierarchy,
+        &all_files,
+        existing,
+        protection,
+        "",
+        false,
+        reporter,
+    )
+}
+
+/// The documents a backup into `storage` downloads (smart mode applied) and, with `dedup`, the
+/// ones whose file it reuses instead, with the manifest of the backup they are found from
+async fn files_to_transfer(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    storage: &Storage,
+    protection: &Protection,
+    smart_mode: bool,
+    (dedup, reuse_identical): (bool, bool),
+    reporter: &dyn Reporter,
+) -> Result<(Vec<(String, String)>, Vec<dedup::Reused>, Option<Manifest>)> {
+    ensure_no_plain_backup(storage, protection).await?;
+    let previous = match dedup {
+        true => dedup::read_manifest(storage).await?,
+        false => None,
+    };
+    // only smart mode needs to know what is already backed up
+    let existing = match (smart_mode, dedup) {
+        (false, _) => HashMap::new(),
+        (true, false) => storage.list().await?,
+        // the documents are in `objects/`, the manifest tells which is which
+        (true, true) => dedup::unchanged_documents(
+            fs_hierarchy,
+            previous.as_ref(),
+            &storage.list().await?,
+            storage.local_path().is_some(),
+        ),
+    };
+    let files_to_download =
+        which_files_to_download(fs_hierarchy, &existing, protection, smart_mode, reporter);
+    let (files_to_download, reused) = match dedup {
+        true => {
+            dedup::plan_reuse(
+                fs_hierarchy,
+                files_to_download,
+                previous.as_ref(),
+                storage,
+                reuse_identical,
+                reporter,
+            )
+            .await?
+        }
+        false => (files_to_download, vec![]),
+    };
+    Ok((files_to_download, reused, previous))
+}
+
+/// What a backup into `out_path` would download (smart mode applied), nothing is downloaded. With
+/// `dedup`, the documents whose file is reused are not counted
+pub async fn plan_backup(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    out_path: &str,
+    protection: &Protection,
+    smart_mode: bool,
+    (dedup, reuse_identical): (bool, bool),
+    reporter: &dyn Reporter,
+) -> Result<TransferPlan> {
+    let (files_to_download, _, _) = files_to_transfer(
+        fs_hierarchy,
+        &Storage::parse(out_path)?,
+        protection,
+        smart_mode,
+        (dedup, reuse_identical),
+        reporter,
+    )
+    .await?;
+    Ok(TransferPlan::new(
+        fs_hierarchy,
+        files_to_download.iter().map(|(id, _)| id.as_str()),
+        out_path,
+    ))
+}
+
+/// What a backup into `out_path` would do file by file (smart mode applied), nothing is downloaded
+/// nor written. `tags`: the tags the backup is limited to, see [`write_tag_view`]
+pub async fn dry_run_backup(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    BackupOptions {
+        out_path,
+        override_mode,
+        smart_mode,
+        protection,
+        dedup,
+        reuse_identical,
+        ..
+    }: &BackupOptions,
+    by_tag: bool,
+    tags: &[String],
+) -> Result<Vec<PlannedAction>> {
+    let (files_to_download, reused, _) = files_to_transfer(
+        fs_hierarchy,
+        &Storage::parse(out_path)?,
+        protection,
+        *smart_mode,
+        (*dedup, *reuse_identical),
+        &SilentReporter,
+    )
+    .await?;
+    let files_to_download = files_to_download
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<HashSet<_>>();
+    // path of the document whose file each reused one links to, by ID
+    let reused = reused
+        .into_iter()
+        .map(|reused| (reused.id, reused.same_as))
+        .collect::<HashMap<_, _>>();
+
+    // same walk as `copy_to_storage`
+    fn walk(
+        folder: &FolderNode,
+        parent: &str,
+        out_path: &str,
+        fs_hierarchy: &RemarkableFSHierarchy,
+        (files_to_download, reused): (&HashSet<String>, &HashMap<String, String>),
+        (protection, override_mode): (&Protection, bool),
+        dry_run: &mut DryRun,
+    ) {
+        let curr_path = join_path(parent, &folder.name);
+        if !protection.obfuscate_names {
+            dry_run.create_folder(&join_path(out_path, &curr_path));
+        }
+        for id in &folder.files_id {
+            let Some(doc) = fs_hierarchy.find_document(id) else {
+                continue;
+            };
+            let path = join_path(&curr_path, &ensure_file_extension(&doc.vissible_name));
+            let path = join_path(out_path, &protection.stored_path(id, &path));
+            match reused.get(id) {
+                Some(same_as) => dry_run.push(PlannedAction::Skip {
+                    path,
+                    reason: format!("same file as '{same_as}', linked to it"),
+                }),
+                None if files_to_download.contains(id) => {
+                    dry_run.download(id, &path, override_mode)
+                }
+                None => dry_run.push(PlannedAction::Skip {
+                    path,
+                    reason: "not modified since the last backup".to_string(),
+                }),
+            }
+        }
+        for subfolder in &folder.subfolders {
+            walk(
+                subfolder,
+                &curr_path,
+                out_path,
+                fs_hierarchy,
+                (files_to_download, reused),
+                (protection, override_mode),
+                dry_run,
+            );
+        }
+    }
+
+    let mut dry_run = DryRun::default();
+    dry_run.create_folder(out_path);
+    if protection.obfuscate_names {
+        dry_run.create_folder(&join_path(out_path, DOCUMENTS_DIR));
+    }
+    walk(
+        &fs_hierarchy.folder_hierarchy,
+        "",
+        out_path,
+        fs_hierarchy,
+        (&files_to_download, &reused),
+        (protection, *override_mode),
+        &mut dry_run,
+    );
+
+    if by_tag {
+        // see `write_tag_view`: the folders of the tags of the run are rebuilt from scratch
+        let view_path = join_path(out_path, TAG_VIEW_DIR);
+        for folder in rebuilt_tag_folders(Path::new(&view_path), tags) {
+            dry_run.delete(&folder.to_string_lossy());
+        }
+        for (id, folder_path) in fs_hierarchy.folder_hierarchy.files_path(out_path) {
+            let Some(doc) = fs_hierarchy.find_document(&id) else {
+                continue;
+            };
+            let target = join_path(&folder_path, &ensure_file_extension(&doc.vissible_name));
+            if !dry_run.exists(&target) {
+                continue;
+            }
+            for tag in doc.tag_names() {
+                if is_rebuilt(tag, tags) {
+                    dry_run.create_folder(&join_path(&view_path, &tag_folder(tag)));
+                }
+            }
+        }
+    }
+    Ok(dry_run.finish())
 }
 
 pub async fn sync_full_backup(
@@ -153,123 +399,371 @@ pub async fn sync_full_backup(
         udp_mode,
         override_mode,
         smart_mode,
+        protection,
+        dedup,
+        reuse_identical,
     }: BackupOptions,
+    reporter: &dyn Reporter,
 ) -> Result<()> {
-    let files_to_download = which_files_to_download(fs_hierarchy, &out_path, smart_mode);
+    let storage = Storage::parse(&out_path)?;
+    let (files_to_download, reused, previous) = files_to_transfer(
+        fs_hierarchy,
+        &storage,
+        &protection,
+        smart_mode,
+        (dedup, reuse_identical),
+        reporter,
+    )
+    .await?;
 
     let total_download = files_to_download.len();
-    if total_download == 0 && smart_mode {
-        println!(
-            "{}",
-            "[SMART MODE]: No change made since last backup, exiting... (PS: set smart_mode to false if you still want to download)".green()
-        );
+    if total_download == 0 && reused.is_empty() && smart_mode {
+        reporter.report(Event::Completed {
+            message: "[SMART MODE]: No change made since last backup, exiting... (PS: set smart_mode to false if you still want to download)".to_string(),
+        });
         return Ok(());
     }
 
-    println!(
-        "{}",
-        format!("Downloading {total_download} files... (This may take a (very) long time)").blue()
+    let plan = TransferPlan::new(
+        fs_hierarchy,
+        files_to_download.iter().map(|(id, _)| id.as_str()),
+        &out_path,
     );
+    plan.check_space(&out_path, reporter)?;
+    reporter.report(Event::TransferPlanned {
+        total: total_download,
+        total_bytes: plan.bytes,
+    });
 
     let client = reqwest::Client::new();
-    let mut files: Vec<RmkFile> = vec![];
+    let mut downloads = DownloadQueue::new(&client, reporter);
     for (id, name) in files_to_download {
         let name = ensure_file_extension(&name);
-        println!("{}", format!("Downloading {name}...").purple());
-        let resp = client
-            .get(format!("http://10.11.99.1/download/{id}/placeholder"))
-            .send()
-            .await?;
-        let b = udp_continue!(resp.bytes().await, udp_mode, "").to_vec();
+        let doc = udp_continue!(
+            fs_hierarchy
+                .find_document(&id)
+                .ok_or(anyhow!("No file with the ID '{id}'")),
+            udp_mode,
+            reporter,
+            format!("Failed to download '{name}'")
+        );
+        downloads.push((id, name.clone()), doc, name);
+    }
+
+    let mut files: Vec<RmkFile> = vec![];
+    while let Some(((id, name), result)) = downloads.next().await {
+        let b = udp_continue!(
+            result,
+            udp_mode,
+            reporter,
+            format!("Failed to download '{name}'")
+        );
         files.push((id, name, b))
     }
 
-    println!(
-        "{}",
-        "Copying downloaded files to local file system...".blue()
-    );
-    copy_to_localfs(
-        &fs_hierarchy.folder_hierarchy,
-        &files,
-        &out_path,
-        udp_mode,
-        override_mode,
-    )?;
-    println!(
-        "{}",
-        format!("Finished copying file, go see: '{out_path}'").green()
-    );
+    reporter.report(Event::Info {
+        message: match storage.local_path() {
+            Some(_) => "Copying downloaded files to local file system...".to_string(),
+            None => format!("Copying downloaded files to '{}'...", storage.display("")),
+        },
+    });
+    let written = match dedup {
+        true => {
+            let checksums = dedup::store_files(
+                fs_hierarchy,
+                &files,
+                &reused,
+                &storage,
+                (udp_mode, override_mode),
+                reporter,
+            )
+            .await?;
+            dedup::write_manifest(
+                fs_hierarchy,
+                &checksums,
+                previous.as_ref(),
+                &storage,
+                reporter,
+            )
+            .await
+        }
+        false => {
+            copy_to_storage(
+                &fs_hierarchy.folder_hierarchy,
+                &files,
+                &storage,
+                &protection,
+                "",
+                (udp_mode, override_mode),
+                reporter,
+            )
+            .await?;
+            write_manifest(fs_hierarchy, &files, &storage, &protection).await
+        }
+    };
+    if let Err(why) = written {
+        reporter.report(Event::Warning {
+            message: format!("Failed to write the manifest of the backup: {why}"),
+        });
+    }
+    if is_cancelled() {
+        reporter.report(Event::Interrupted {
+            message: format!(
+                "Backup interrupted, the files downloaded so far are in '{}': run it again to finish it",
+                storage.display("")
+            ),
+        });
+        return Err(Cancelled.into());
+    }
+    reporter.report(Event::Completed {
+        message: format!("Finished copying file, go see: '{}'", storage.display("")),
+    });
 
     Ok(())
 }
 
-/* [DEAD CODE]: remarkable does not support concurrent requests
-pub async fn async_full_backup(
+/// (Re)write the manifest of a directory backup with the checksum of every file, so that `verify`
+/// can check it later. Files that were not downloaded this time keep their previous checksum
+async fn write_manifest(
     fs_hierarchy: &RemarkableFSHierarchy,
-    BackupOptions {
-        out_path,
-        udp_mode,
-        override_mode,
-        smart_mode,
-    }: BackupOptions,
-    concurrent_requests: usize,
+    downloaded: &[RmkFile],
+    storage: &Storage,
+    protection: &Protection,
 ) -> Result<()> {
-    let files_to_download = which_files_to_download(fs_hierarchy, &out_path, smart_mode);
-    let total_download = files_to_download.len();
+    let manifest_path = protection.manifest_path();
+    // the previous checksums of an encrypted backup cannot be read without its key
+    let previous: Option<Manifest> = storage
+        .read(&manifest_path)
+        .await?
+        .and_then(|raw| protection.open(raw))
+        .and_then(|raw| serde_json::from_slice(&raw).ok());
+    let stored = storage.list().await?;
 
-    println!(
-        "{}",
-        format!("Downloading {total_download} files...").blue()
-    );
-    let client = reqwest::Client::new();
-    let file_bdatas = stream::iter(files_to_download)
-        .map(|(id, name)| {
-            let client = &client;
-            async move {
-                let resp = client
-                    .get(format!("http://10.11.99.1/download/{id}/placeholder"))
-                    .send()
-                    .await?;
-                resp.bytes().await.map(|b| (id, name, b.to_vec()))
-            }
-        })
-        .buffer_unordered(concurrent_requests);
+    let mut manifest = Manifest::from_hierarchy(fs_hierarchy);
+    let mut documents = vec![];
+    for mut entry in manifest.documents {
+        let stored_path = protection.stored_path(&entry.id, &entry.path);
+        if !stored.contains_key(&stored_path) {
+            continue;
+        }
+        let previous_sha256 = previous
+            .iter()
+            .flat_map(|previous| &previous.documents)
+            .find(|old| {
+                old.id == entry.id
+                    && old.path == entry.path
+                    && old.modified_client == entry.modified_client
+            })
+            .and_then(|old| old.sha256.clone());
+        entry.sha256 = match downloaded.iter().find(|(id, _, _)| *id == entry.id) {
+            Some((_, _, bytes)) => Some(sha256_hex(bytes)),
+            None if previous_sha256.is_some() => previous_sha256,
+            // backups made before the checksums were recorded
+            None => match storage
+                .read(&stored_path)
+                .await?
+                .and_then(|raw| protection.open(raw))
+            {
+                Some(bytes) if validate_file(&bytes).is_ok() => Some(sha256_hex(&bytes)),
+                _ => None,
+            },
+        };
+        entry.stored_path = (stored_path != entry.path).then_some(stored_path);
+        documents.push(entry);
+    }
+    manifest.documents = documents;
+
+    storage
+        .write(
+            &manifest_path,
+            &protection.seal(&serde_json::to_vec_pretty(&manifest)?)?,
+            true,
+        )
+        .await
+}
 
-    let files = file_bdatas
-        .filter_map(|file| async { file.ok() })
-        .collect::<Vec<RmkFile>>()
-        .await;
+/// Folder of a directory backup where the documents are grouped by tag
+pub const TAG_VIEW_DIR: &str = "by-tag";
+
+#[cfg(unix)]
+fn link_file(relative_target: &Path, _target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(relative_target, link)
+}
+
+#[cfg(not(unix))]
+fn link_file(_relative_target: &Path, target: &Path, link: &Path) -> std::io::Result<()> {
+    fs::hard_link(target, link)
+}
 
-    let success = files.len();
-    let failure = total_download - success;
-    if failure != 0 && !udp_mode {
-        return Err(anyhow!("Failure, at least on download failed".red().bold()));
+/// name of the folder of `tag` in the tag view
+fn tag_folder(tag: &str) -> String {
+    tag.replace(['/', '\\'], "_")
+}
+
+/// whether the folder of `tag` is rebuilt by a backup limited to `tags` (every tag if empty)
+fn is_rebuilt(tag: &str, tags: &[String]) -> bool {
+    tags.is_empty()
+        || tags
+            .iter()
+            .any(|rebuilt| tag_folder(rebuilt).eq_ignore_ascii_case(&tag_folder(tag)))
+}
+
+/// what is removed from the tag view at `view_path` before a backup limited to `tags` rebuilds it:
+/// the whole view, or only the folders of `tags`
+fn rebuilt_tag_folders(view_path: &Path, tags: &[String]) -> Vec<PathBuf> {
+    if tags.is_empty() {
+        return match view_path.exists() {
+            true => vec![view_path.to_path_buf()],
+            false => vec![],
+        };
     }
+    fs::read_dir(view_path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            (entry.path().is_dir() && is_rebuilt(&name, tags)).then(|| entry.path())
+        })
+        .collect()
+}
 
-    println!(
-        "Successful download: {}",
-        format!("{success}/{total_download}").green()
-    );
-    println!(
-        "Failed download: {}",
-        format!("{failure}/{total_download}").red()
-    );
+/// (Re)create `by-tag/<tag>/<document>` in a directory backup: links to the backed up files, one
+/// folder per tag. Documents that are not in the backup are left out.
+///
+/// A backup limited to some `tags` only rebuilds their folders: the documents of the other tags
+/// are not in `fs_hierarchy`, their folders are left as they are
+pub fn write_tag_view(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    out_path: &str,
+    tags: &[String],
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let view_path = Path::new(out_path).join(TAG_VIEW_DIR);
+    // the view only holds links, it is rebuilt from scratch so that removed tags disappear
+    for folder in rebuilt_tag_folders(&view_path, tags) {
+        fs::remove_dir_all(folder)?;
+    }
 
-    println!(
-        "{}",
-        "Copying downloaded files to local file system...".blue()
-    );
-    copy_to_localfs(
-        &fs_hierarchy.folder_hierarchy,
-        &files,
-        &out_path,
-        udp_mode,
-        override_mode,
-    )?;
-    println!(
-        "{}",
-        format!("Finished copying file, go see: '{out_path}'").green()
-    );
+    let mut links = 0;
+    for (id, folder_path) in fs_hierarchy.folder_hierarchy.files_path("") {
+        let Some(doc) = fs_hierarchy.find_document(&id) else {
+            continue;
+        };
+        let name = ensure_file_extension(&doc.vissible_name);
+        let relative_path = Path::new(&folder_path).join(&name);
+        let target = Path::new(out_path).join(&relative_path);
+        if !target.exists() {
+            continue;
+        }
+
+        for tag in doc.tag_names() {
+            if !is_rebuilt(tag, tags) {
+                continue;
+            }
+            let tag_path = view_path.join(tag_folder(tag));
+            fs::create_dir_all(&tag_path)?;
+            // documents with the same name in different folders
+            let mut link = tag_path.join(&name);
+            let mut n = 2;
+            while link.symlink_metadata().is_ok() {
+                let (stem, ext) = name.rsplit_once('.').unwrap_or((&name, ""));
+                link = tag_path.join(format!("{stem} ({n}).{ext}"));
+                n += 1;
+            }
+            let relative_target = Path::new("../..").join(&relative_path);
+            udp_continue!(
+                link_file(&relative_target, &target, &link),
+                true,
+                reporter,
+                format!("Failed to link '{name}' in '{TAG_VIEW_DIR}/{tag}'")
+            );
+            links += 1;
+        }
+    }
 
+    reporter.report(Event::Info {
+        message: format!(
+            "Grouped {links} documents by tag in '{}'",
+            view_path.display()
+        ),
+    });
     Ok(())
-}*/
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events::SilentReporter, scheme::Tag, test_utils::TempDir};
+
+    /// the links of the tag view of the backup in `dir`, e.g: "scifi/Dune.pdf"
+    fn tag_view(dir: &TempDir) -> Vec<String> {
+        let mut links = vec![];
+        for folder in fs::read_dir(dir.join(TAG_VIEW_DIR)).unwrap() {
+            let folder = folder.unwrap();
+            for link in fs::read_dir(folder.path()).unwrap() {
+                links.push(format!(
+                    "{}/{}",
+                    folder.file_name().to_string_lossy(),
+                    link.unwrap().file_name().to_string_lossy()
+                ));
+            }
+        }
+        links.sort();
+        links
+    }
+
+    fn document(id: &str, tags: &[&str]) -> RmkDocument {
+        RmkDocument {
+            id: id.to_string(),
+            vissible_name: id.to_string(),
+            doc_type: DocType::DocumentType,
+            tags: tags
+                .iter()
+                .map(|name| Tag {
+                    name: name.to_string(),
+                    timestamp: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tag_view_of_a_run_limited_to_a_tag_keeps_the_other_tags() {
+        let backup = TempDir::with_files(
+            "full-backup-tag-view",
+            &["root/Dune.pdf", "root/Course.pdf"],
+        );
+        let fs_hierarchy = RemarkableFSHierarchy::from_documents(vec![
+            document("Dune", &["scifi", "reading"]),
+            document("Course", &["exam"]),
+        ]);
+        write_tag_view(&fs_hierarchy, backup.path(), &[], &SilentReporter).unwrap();
+        assert_eq!(
+            tag_view(&backup),
+            ["exam/Course.pdf", "reading/Dune.pdf", "scifi/Dune.pdf"]
+        );
+
+        // "scifi" was removed from Dune, "exam" and "reading" are not part of the run
+        let fs_hierarchy = RemarkableFSHierarchy::from_documents(vec![
+            document("Dune", &["reading"]),
+            document("Course", &["exam"]),
+        ]);
+        let tags = ["SCIFI".to_string()];
+        write_tag_view(
+            &fs_hierarchy.with_tags(&tags),
+            backup.path(),
+            &tags,
+            &SilentReporter,
+        )
+        .unwrap();
+        assert_eq!(tag_view(&backup), ["exam/Course.pdf", "reading/Dune.pdf"]);
+
+        // a run without tags rebuilds the whole view
+        let fs_hierarchy =
+            RemarkableFSHierarchy::from_documents(vec![document("Course", &["exam", "scifi"])]);
+        write_tag_view(&fs_hierarchy, backup.path(), &[], &SilentReporter).unwrap();
+        assert_eq!(tag_view(&backup), ["exam/Course.pdf", "scifi/Course.pdf"]);
+    }
+}
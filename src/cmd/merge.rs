@@ -0,0 +1,623 @@
+//! Merge the documents of a folder of the remarkable into a single PDF (`export-merged`): title
+//! pages listing the contents, then every document of the folder and of its subfolders, with a
+//! bookmark per folder and per document.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use clap::ValueEnum;
+use lopdf::{
+    content::{Content, Operation},
+    dictionary, Bookmark, Dictionary, Document, Object, ObjectId, Stream,
+};
+
+use crate::{
+    cancel::{is_cancelled, Cancelled},
+    events::{Event, Reporter},
+    scheme::RmkDocument,
+    utils::{ensure_file_extension, udp_continue, write_file},
+};
+
+use super::{queue::DownloadQueue, FolderNode, RemarkableFSHierarchy};
+
+/// Order of the documents of each folder in the merged PDF, the subfolders come after them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MergeOrder {
+    /// as listed by the remarkable
+    #[default]
+    Device,
+    /// documents and folders by name
+    Name,
+    /// documents from the oldest modified to the last one, folders by name
+    Date,
+}
+
+pub struct MergeOptions {
+    /// the PDF file to write
+    pub out_path: String,
+    pub order: MergeOrder,
+    /// title of the title page and of the PDF, the name of the folder by default
+    pub title: Option<String>,
+    pub udp_mode: bool,
+    pub override_mode: bool,
+}
+
+/// A4, in points
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+const LINE_HEIGHT: f32 = 16.0;
+/// top of the list of contents on the first title page, below the title
+const FIRST_PAGE_TOP: f32 = 720.0;
+/// bookmark format of the folders
+const BOLD: u32 = 2;
+
+/// A folder of the merged PDF, in the order of the export
+struct Section<'a> {
+    name: String,
+    documents: Vec<&'a RmkDocument>,
+    subsections: Vec<Section<'a>>,
+}
+
+impl<'a> Section<'a> {
+    fn new(
+        fs_hierarchy: &'a RemarkableFSHierarchy,
+        folder: &FolderNode,
+        order: MergeOrder,
+    ) -> Self {
+        let mut documents = folder
+            .files_id
+            .iter()
+            .filter_map(|id| fs_hierarchy.find_document(id))
+            .collect::<Vec<_>>();
+        let mut subsections = folder
+            .subfolders
+            .iter()
+            .map(|subfolder| Section::new(fs_hierarchy, subfolder, order))
+            .collect::<Vec<_>>();
+        match order {
+            MergeOrder::Device => {}
+            MergeOrder::Name => documents.sort_by_key(|doc| doc.vissible_name.to_lowercase()),
+            MergeOrder::Date => documents.sort_by_key(|doc| {
+                DateTime::parse_from_rfc3339(&doc.modified_client)
+                    .map(|date| date.with_timezone(&Utc))
+                    .ok()
+            }),
+        }
+        if order != MergeOrder::Device {
+            subsections.sort_by_key(|section| section.name.to_lowercase());
+        }
+        Self {
+            name: folder.name.clone(),
+            documents,
+            subsections,
+        }
+    }
+
+    fn all_documents(&self) -> Vec<&'a RmkDocument> {
+        let mut documents = self.documents.clone();
+        for subsection in &self.subsections {
+            documents.append(&mut subsection.all_documents());
+        }
+        documents
+    }
+
+    /// keep the documents in `loaded` only, and the folders with at least one of them
+    fn retain_loaded(&mut self, loaded: &HashMap<String, Document>) -> bool {
+        self.documents.retain(|doc| loaded.contains_key(&doc.id));
+        self.subsections
+            .retain_mut(|subsection| subsection.retain_loaded(loaded));
+        !self.documents.is_empty() || !self.subsections.is_empty()
+    }
+
+    /// folders and documents in the order of the PDF, with their depth (0 for the exported folder)
+    fn entries(&self, depth: usize, entries: &mut Vec<Entry<'a>>) {
+        for doc in &self.documents {
+            entries.push(Entry::Document { doc, depth });
+        }
+        for subsection in &self.subsections {
+            entries.push(Entry::Folder {
+                name: subsection.name.clone(),
+                depth,
+            });
+            subsection.entries(depth + 1, entries);
+        }
+    }
+}
+
+enum Entry<'a> {
+    Folder { name: String, depth: usize },
+    Document { doc: &'a RmkDocument, depth: usize },
+}
+
+/// name of a document without the extension added to its file
+fn title_of(doc: &RmkDocument) -> String {
+    doc.vissible_name
+        .strip_suffix(".pdf")
+        .unwrap_or(&doc.vissible_name)
+        .to_string()
+}
+
+/// top of the list of contents of a title page
+fn list_top(first_page: bool) -> f32 {
+    match first_page {
+        true => FIRST_PAGE_TOP,
+        false => PAGE_HEIGHT - MARGIN,
+    }
+}
+
+/// number of lines of contents on a title page
+fn lines_per_page(first_page: bool) -> usize {
+    ((list_top(first_page) - MARGIN) / LINE_HEIGHT) as usize
+}
+
+/// number of title pages needed to list `lines` entries
+fn title_pages(lines: usize) -> usize {
+    1 + lines
+        .saturating_sub(lines_per_page(true))
+        .div_ceil(lines_per_page(false))
+}
+
+/// `text` for the standard fonts (WinAnsiEncoding), the characters they do not have become '?'
+fn encode(text: &str) -> Vec<u8> {
+    text.chars()
+        .flat_map(|c| {
+            match Document::encode_text(Some("WinAnsiEncoding"), &c.to_string()).as_slice() {
+                [] => vec![b'?'],
+                bytes => bytes.to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// a text string of the PDF (outline, document information): UTF-16 unless it is plain ASCII
+fn text_string(text: &str) -> Object {
+    match text.is_ascii() {
+        true => Object::string_literal(text),
+        false => Object::String(
+            [0xFE, 0xFF]
+                .into_iter()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect(),
+            lopdf::StringFormat::Hexadecimal,
+        ),
+    }
+}
+
+/// `text` cut to `max` characters
+fn truncate(text: &str, max: usize) -> String {
+    match text.chars().count() > max {
+        true => format!(
+            "{}…",
+            text.chars().take(max.saturating_sub(1)).collect::<String>()
+        ),
+        false => text.to_string(),
+    }
+}
+
+fn show_text(font: &str, size: f32, x: f32, y: f32, text: &str) -> Vec<Operation> {
+    vec![
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec![font.into(), size.into()]),
+        Operation::new("Td", vec![x.into(), y.into()]),
+        Operation::new("Tj", vec![Object::string_literal(encode(text))]),
+        Operation::new("ET", vec![]),
+    ]
+}
+
+/// content streams of the title pages: the title, then a line per folder and per document with the
+/// page where it starts
+fn title_page_contents(
+    title: &str,
+    subtitle: &str,
+    lines: &[(usize, String, Option<usize>)],
+) -> Vec<Content> {
+    let mut pages = vec![];
+    let mut operations = show_text("F2", 20.0, MARGIN, PAGE_HEIGHT - MARGIN - 20.0, title);
+    operations.append(&mut show_text(
+        "F1",
+        10.0,
+        MARGIN,
+        PAGE_HEIGHT - MARGIN - 40.0,
+        subtitle,
+    ));
+    let mut on_page = 0;
+    for (depth, text, page) in lines {
+        if on_page == lines_per_page(pages.is_empty()) {
+            pages.push(Content { operations });
+            operations = vec![];
+            on_page = 0;
+        }
+        let y = list_top(pages.is_empty()) - LINE_HEIGHT * on_page as f32;
+        let indent = 14.0 * *depth as f32;
+        let text = truncate(text, 80usize.saturating_sub(3 * depth).max(10));
+        // folders in bold, documents with their page
+        let font = match page {
+            Some(_) => "F1",
+            None => "F2",
+        };
+        operations.append(&mut show_text(font, 11.0, MARGIN + indent, y, &text));
+        if let Some(page) = page {
+            operations.append(&mut show_text(
+                "F1",
+                11.0,
+                PAGE_WIDTH - MARGIN - 24.0,
+                y,
+                &page.to_string(),
+            ));
+        }
+        on_page += 1;
+    }
+    pages.push(Content { operations });
+    pages
+}
+
+/// the attributes a page inherits from the page tree, that it loses when it moves to another one
+const INHERITED: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// the dictionary of the page `page_id` of `doc`, with the attributes it inherits from its parents
+fn standalone_page(doc: &Document, page_id: ObjectId) -> Result<Dictionary> {
+    let mut page = doc.get_dictionary(page_id)?.clone();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    while let Some(parent_id) = parent {
+        let node = doc.get_dictionary(parent_id)?;
+        for key in INHERITED {
+            if !page.has(key) {
+                if let Ok(value) = node.get(key) {
+                    page.set(key, value.clone());
+                }
+            }
+        }
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    Ok(page)
+}
+
+/// Merge the `loaded` PDFs of the documents of `section` into one, after title pages listing them:
+/// the merged PDF, with its number of documents and of pages
+fn merge(
+    section: &Section,
+    mut loaded: HashMap<String, Document>,
+    title: &str,
+) -> Result<(Document, usize, usize)> {
+    let mut entries = vec![];
+    section.entries(0, &mut entries);
+    let title_page_count = title_pages(entries.len());
+
+    let mut merged = Document::with_version("1.5");
+    let pages_id = merged.new_object_id();
+    let mut kids = vec![];
+    // first page of each document, and its number
+    let mut first_pages: HashMap<&str, (ObjectId, usize)> = HashMap::new();
+    let mut page_number = title_page_count + 1;
+    for entry in &entries {
+        let Entry::Document { doc, .. } = entry else {
+            continue;
+        };
+        let Some(mut pdf) = loaded.remove(&doc.id) else {
+            continue;
+        };
+        pdf.renumber_objects_with(merged.max_id + 1);
+        merged.max_id = pdf.max_id;
+        let pages = pdf.get_pages().into_values().collect::<Vec<_>>();
+        let mut standalone = vec![];
+        for page_id in &pages {
+            let mut page = standalone_page(&pdf, *page_id)?;
+            page.set("Parent", pages_id);
+            standalone.push((*page_id, page));
+        }
+        // the old catalog, page tree and outline are left out by `prune_objects`
+        merged.objects.extend(pdf.objects);
+        for (page_id, page) in standalone {
+            merged.objects.insert(page_id, Object::Dictionary(page));
+        }
+        if let Some(first) = pages.first() {
+            first_pages.insert(&doc.id, (*first, page_number));
+        }
+        page_number += pages.len();
+        kids.extend(pages);
+    }
+    let total_pages = page_number - 1;
+
+    // title pages
+    let lines = entries
+        .iter()
+        .map(|entry| match entry {
+            Entry::Folder { name, depth } => (*depth, name.clone(), None),
+            Entry::Document { doc, depth } => (
+                *depth,
+                title_of(doc),
+                first_pages.get(doc.id.as_str()).map(|(_, number)| *number),
+            ),
+        })
+        .collect::<Vec<_>>();
+    let subtitle = format!(
+        "{} documents, {total_pages} pages, exported the {}",
+        first_pages.len(),
+        Local::now().format("%Y-%m-%d")
+    );
+    let regular = merged.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let bold = merged.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica-Bold",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let resources = merged.add_object(dictionary! {
+        "Font" => dictionary! { "F1" => regular, "F2" => bold },
+    });
+    let mut title_page_ids = vec![];
+    for content in title_page_contents(title, &subtitle, &lines) {
+        let content_id = merged.add_object(Stream::new(dictionary! {}, content.encode()?));
+        title_page_ids.push(merged.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+            "Resources" => resources,
+            "Contents" => content_id,
+        }));
+    }
+
+    let kids = title_page_ids
+        .iter()
+        .chain(&kids)
+        .map(|id| Object::Reference(*id))
+        .collect::<Vec<_>>();
+    merged.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as i64,
+            "Kids" => kids,
+        }),
+    );
+    let catalog_id = merged.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+        "PageMode" => "UseOutlines",
+    });
+    let info_id = merged.add_object(dictionary! {
+        "Title" => text_string(title),
+        "Producer" => Object::string_literal("remarkable2-downloader"),
+    });
+    merged.trailer.set("Root", catalog_id);
+    merged.trailer.set("Info", info_id);
+    merged.prune_objects();
+
+    // outline: the title pages, then the folders with their documents
+    merged.add_bookmark(
+        Bookmark::new("Contents".to_string(), [0.0; 3], 0, title_page_ids[0]),
+        None,
+    );
+    // last bookmark of each depth, the parents of the deeper ones
+    let mut parents: Vec<u32> = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        let (name, depth, format) = match entry {
+            Entry::Folder { name, depth } => (name.clone(), *depth, BOLD),
+            Entry::Document { doc, depth } => (title_of(doc), *depth, 0),
+        };
+        // a folder opens on the first page of its first document
+        let Some((page, _)) = entries[i..].iter().find_map(|entry| match entry {
+            Entry::Document { doc, .. } => first_pages.get(doc.id.as_str()),
+            Entry::Folder { .. } => None,
+        }) else {
+            continue;
+        };
+        parents.truncate(depth);
+        let bookmark = merged.add_bookmark(
+            Bookmark::new(name, [0.0; 3], format, *page),
+            parents.last().copied(),
+        );
+        if let Entry::Folder { .. } = entry {
+            parents.push(bookmark);
+        }
+    }
+    if let Some(outline_id) = merged.build_outline() {
+        merged
+            .get_dictionary_mut(catalog_id)?
+            .set("Outlines", outline_id);
+    }
+    // the outline only writes plain strings, see `text_string`
+    for object in merged.objects.values_mut() {
+        if let Object::Dictionary(dictionary) = object {
+            if let (Ok(_), Ok(Object::String(raw, _))) =
+                (dictionary.get(b"Parent"), dictionary.get(b"Title"))
+            {
+                let title = text_string(&String::from_utf8_lossy(raw));
+                dictionary.set("Title", title);
+            }
+        }
+    }
+    Ok((merged, first_pages.len(), total_pages))
+}
+
+/// Download every document of the folder `folder_id` of the remarkable ("" for root) and of its
+/// subfolders, and merge them into a single PDF with title pages listing the contents and a
+/// bookmark outline mirroring the folders
+pub async fn export_merged(
+    client: &reqwest::Client,
+    fs_hierarchy: &RemarkableFSHierarchy,
+    folder_id: &str,
+    MergeOptions {
+        out_path,
+        order,
+        title,
+        udp_mode,
+        override_mode,
+    }: MergeOptions,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let folder = fs_hierarchy
+        .folder_hierarchy
+        .find(folder_id)
+        .ok_or(anyhow!("No folder with the ID '{folder_id}'"))?;
+    let mut section = Section::new(fs_hierarchy, folder, order);
+    let documents = section.all_documents();
+    if documents.is_empty() {
+        return Err(anyhow!("No document in '{}'", folder.name));
+    }
+    reporter.report(Event::TransferPlanned {
+        total: documents.len(),
+        total_bytes: documents.iter().filter_map(|doc| doc.size_estimate()).sum(),
+    });
+
+    let mut downloads = DownloadQueue::new(client, reporter);
+    for doc in &documents {
+        let name = ensure_file_extension(&doc.vissible_name);
+        downloads.push((doc.id.clone(), name.clone()), doc, name);
+    }
+    let mut loaded = HashMap::new();
+    while let Some(((id, name), result)) = downloads.next().await {
+        let bytes = udp_continue!(
+            result,
+            udp_mode,
+            reporter,
+            format!("Failed to download '{name}'")
+        );
+        let pdf = udp_continue!(
+            Document::load_mem(&bytes),
+            udp_mode,
+            reporter,
+            format!("Failed to read the PDF of '{name}'")
+        );
+        loaded.insert(id, pdf);
+    }
+    if is_cancelled() {
+        reporter.report(Event::Interrupted {
+            message: "Export interrupted, nothing was written".to_string(),
+        });
+        return Err(Cancelled.into());
+    }
+    if !section.retain_loaded(&loaded) {
+        return Err(anyhow!(
+            "None of the documents of '{}' could be read",
+            folder.name
+        ));
+    }
+
+    let title = title.unwrap_or_else(|| match folder_id {
+        "" => "My files".to_string(),
+        _ => folder.name.clone(),
+    });
+    let (mut merged, merged_documents, total_pages) = merge(&section, loaded, &title)?;
+    merged.compress();
+
+    let mut bytes = vec![];
+    merged.save_to(&mut bytes)?;
+    write_file(&out_path, &bytes, override_mode)?;
+    reporter.report(Event::Completed {
+        message: format!(
+            "Merged {merged_documents} documents ({total_pages} pages) into '{out_path}'"
+        ),
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scheme::DocType, test_utils::pdf};
+
+    fn hierarchy() -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(vec![
+            RmkDocument {
+                id: "dune".to_string(),
+                vissible_name: "Dune".to_string(),
+                doc_type: DocType::DocumentType,
+                ..Default::default()
+            },
+            RmkDocument {
+                id: "books".to_string(),
+                vissible_name: "Books".to_string(),
+                doc_type: DocType::CollectionType,
+                ..Default::default()
+            },
+            RmkDocument {
+                id: "course".to_string(),
+                vissible_name: "Course.pdf".to_string(),
+                parent: "books".to_string(),
+                doc_type: DocType::DocumentType,
+                ..Default::default()
+            },
+        ])
+    }
+
+    /// titles of the bookmarks of `doc`
+    fn bookmarks(doc: &Document) -> Vec<String> {
+        let mut titles = doc
+            .objects
+            .values()
+            .filter_map(|object| object.as_dict().ok())
+            .filter(|dictionary| dictionary.has(b"Parent"))
+            .filter_map(|dictionary| dictionary.get(b"Title").ok()?.as_str().ok())
+            .map(|title| String::from_utf8_lossy(title).to_string())
+            .collect::<Vec<_>>();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn merges_the_documents_after_the_title_page() {
+        let fs_hierarchy = hierarchy();
+        let folder = fs_hierarchy.folder_hierarchy.find("").unwrap();
+        let section = Section::new(&fs_hierarchy, folder, MergeOrder::Name);
+        let loaded = HashMap::from([
+            ("dune".to_string(), Document::load_mem(&pdf(2)).unwrap()),
+            ("course".to_string(), Document::load_mem(&pdf(3)).unwrap()),
+        ]);
+
+        let (mut merged, documents, pages) = merge(&section, loaded, "My files").unwrap();
+        assert_eq!((documents, pages), (2, 6));
+        let mut bytes = vec![];
+        merged.save_to(&mut bytes).unwrap();
+        let merged = Document::load_mem(&bytes).unwrap();
+        assert_eq!(merged.get_pages().len(), 6);
+        assert_eq!(bookmarks(&merged), ["Books", "Contents", "Course", "Dune"]);
+    }
+
+    #[test]
+    fn leaves_out_the_documents_that_could_not_be_read() {
+        let fs_hierarchy = hierarchy();
+        let folder = fs_hierarchy.folder_hierarchy.find("").unwrap();
+        let mut section = Section::new(&fs_hierarchy, folder, MergeOrder::Device);
+        let loaded = HashMap::from([("dune".to_string(), Document::load_mem(&pdf(2)).unwrap())]);
+        assert!(section.retain_loaded(&loaded));
+
+        let (merged, documents, pages) = merge(&section, loaded, "My files").unwrap();
+        assert_eq!((documents, pages), (1, 3));
+        assert_eq!(bookmarks(&merged), ["Contents", "Dune"]);
+    }
+
+    #[test]
+    fn paginates_the_contents() {
+        // 41 lines on the first title page, below the title, 45 on the next ones
+        let cases = [
+            (0, 1),
+            (1, 1),
+            (41, 1),
+            (42, 2),
+            (86, 2),
+            (87, 3),
+            (131, 3),
+            (132, 4),
+        ];
+        for (lines, pages) in cases {
+            assert_eq!(title_pages(lines), pages, "{lines} lines");
+            let lines = vec![(0, "Dune".to_string(), Some(1)); lines];
+            assert_eq!(title_page_contents("My files", "", &lines).len(), pages);
+        }
+    }
+
+    #[test]
+    fn cuts_long_names() {
+        assert_eq!(truncate("Dune", 10), "Dune");
+        assert_eq!(truncate("Dune Messiah", 5), "Dune…");
+        assert_eq!(truncate("Dune", 0), "…");
+    }
+}
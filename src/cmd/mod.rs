@@ -1,20 +1,38 @@
-use async_recursion::async_recursion;
-use colored::Colorize;
 use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
     time::Duration,
 };
 
 use crate::{
-    scheme::{DocType, RmkDocument, RmkDocuments, RmkFile},
-    udp_continue, udp_return,
+    crypto::Protection,
+    events::{Event, Reporter, SilentReporter},
+    integrity::{validate_payload, Validation},
+    probe::PdfRoute,
+    scheme::{parse_documents, DocType, RmkDocument, RmkDocuments, RmkFile},
+    storage::Storage,
+    utils::{join_path, udp_continue, udp_return},
 };
 use anyhow::{anyhow, Result};
+use futures::{future::LocalBoxFuture, stream, FutureExt, StreamExt, TryStreamExt};
+use serde_derive::Serialize;
+use serde_json::Value;
 
+pub mod archive;
+pub mod decrypt;
+pub mod dedup;
 pub mod download;
+pub mod dry_run;
 pub mod full_backup;
+pub mod merge;
+pub mod plan;
+pub mod queue;
+pub mod restore;
+pub mod status;
+pub mod upload;
+pub mod verify;
 
 #[derive(Debug)]
 pub struct FolderNode {
@@ -24,108 +42,592 @@ pub struct FolderNode {
     pub subfolders: Vec<FolderNode>,
 }
 
+impl RemarkableFSHierarchy {
+    /// rebuild the hierarchy from a flat list of documents (e.g: read back from the cache)
+    pub fn from_documents(all_docs: RmkDocuments) -> Self {
+        Self {
+            folder_hierarchy: build_folder(&all_docs, "", "root"),
+            all_docs,
+        }
+    }
+
+    pub fn find_document(&self, id: &str) -> Option<&RmkDocument> {
+        self.all_docs.iter().find(|doc| doc.id == id)
+    }
+
+    /// path of a document or a folder in the remarkable, e.g: "/Books/Fantasy/Dune", "/" is for root
+    pub fn path_of(&self, id: &str) -> Option<String> {
+        if id.is_empty() {
+            return Some("/".to_string());
+        }
+        let mut names = vec![];
+        let mut current = self.find_document(id)?;
+        loop {
+            names.push(current.vissible_name.as_str());
+            if current.parent.is_empty() {
+                break;
+            }
+            current = self.find_document(&current.parent)?;
+        }
+        names.reverse();
+        Some(format!("/{}", names.join("/")))
+    }
+
+    /// find a document or a folder from its path in the remarkable, e.g: "/Books/Fantasy/Dune"
+    pub fn find_by_path(&self, path: &str) -> Option<&RmkDocument> {
+        let path = normalize_path(path);
+        self.all_docs
+            .iter()
+            .find(|doc| self.path_of(&doc.id).as_deref() == Some(path.as_str()))
+    }
+
+    /// documents and folders directly inside the folder `folder_id` ("" is for root)
+    pub fn children(&self, folder_id: &str) -> Vec<&RmkDocument> {
+        self.all_docs
+            .iter()
+            .filter(|doc| doc.parent == folder_id)
+            .collect()
+    }
+
+    /// number of documents, of folders and size of everything inside a folder (recursively)
+    pub fn folder_summary(&self, folder: &FolderNode) -> FolderSummary {
+        let mut summary = FolderSummary {
+            documents: folder.files_id.len(),
+            folders: folder.subfolders.len(),
+            size: folder
+                .files_id
+                .iter()
+                .filter_map(|id| self.find_document(id)?.size_estimate())
+                .sum(),
+        };
+        for subfolder in &folder.subfolders {
+            let sub_summary = self.folder_summary(subfolder);
+            summary.documents += sub_summary.documents;
+            summary.folders += sub_summary.folders;
+            summary.size += sub_summary.size;
+        }
+        summary
+    }
+
+    /// documents and folders whose name contains `query` (case insensitive)
+    pub fn search(&self, query: &str) -> Vec<&RmkDocument> {
+        let query = query.to_lowercase();
+        self.all_docs
+            .iter()
+            .filter(|doc| doc.vissible_name.to_lowercase().contains(&query))
+            .collect()
+    }
+
+    /// every tag used on the remarkable with how many documents and pages have it, sorted by name
+    pub fn tags(&self) -> Vec<TagSummary> {
+        fn summary<'a>(tags: &'a mut Vec<TagSummary>, name: &str) -> &'a mut TagSummary {
+            match tags.iter().position(|tag| tag.name == name) {
+                Some(i) => &mut tags[i],
+                None => {
+                    tags.push(TagSummary {
+                        name: name.to_string(),
+                        ..Default::default()
+                    });
+                    tags.last_mut().unwrap()
+                }
+            }
+        }
+
+        let mut tags = vec![];
+        for doc in &self.all_docs {
+            for name in doc.tag_names() {
+                summary(&mut tags, name).documents += 1;
+            }
+            for page_tag in &doc.page_tags {
+                summary(&mut tags, &page_tag.name).pages += 1;
+            }
+        }
+        tags.sort_by_key(|tag| tag.name.to_lowercase());
+        tags
+    }
+
+    /// same hierarchy with only the documents that have at least one of `tags` (folders are all kept)
+    pub fn with_tags(&self, tags: &[String]) -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(
+            self.all_docs
+                .iter()
+                .filter(|doc| {
+                    doc.doc_type != DocType::DocumentType || tags.iter().any(|tag| doc.has_tag(tag))
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// normalize a path of the remarkable: leading '/', no trailing nor repeated '/', e.g: "Books//Fantasy/" -> "/Books/Fantasy"
+pub fn normalize_path(path: &str) -> String {
+    format!(
+        "/{}",
+        path.split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    )
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FolderSummary {
+    pub documents: usize,
+    pub folders: usize,
+    /// estimated from the `size_in_bytes` of the documents
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TagSummary {
+    pub name: String,
+    /// documents with this tag, on the document itself or on one of its pages
+    pub documents: usize,
+    /// pages with this tag
+    pub pages: usize,
+}
+
+impl FolderNode {
+    /// find a folder of the hierarchy (this one included) by its id
+    pub fn find(&self, id: &str) -> Option<&FolderNode> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.subfolders
+            .iter()
+            .find_map(|subfolder| subfolder.find(id))
+    }
+
+    /// list every folder of the hierarchy (this one included) with its path relative to `parent`, e.g: "root/Books"
+    pub fn folder_paths(&self, parent: &str) -> Vec<(String, String)> {
+        let curr_path = join_path(parent, &self.name);
+        let mut paths = vec![(self.id.clone(), curr_path.clone())];
+        for subfolder in &self.subfolders {
+            paths.append(&mut subfolder.folder_paths(&curr_path));
+        }
+        paths
+    }
+
+    /// list every file id of the hierarchy along with the path of the folder containing it
+    pub fn files_path(&self, parent: &str) -> Vec<(String, String)> {
+        let curr_path = join_path(parent, &self.name);
+        let mut files = self
+            .files_id
+            .iter()
+            .map(|id| (id.to_owned(), curr_path.clone()))
+            .collect::<Vec<_>>();
+        for subfolder in &self.subfolders {
+            files.append(&mut subfolder.files_path(&curr_path));
+        }
+        files
+    }
+}
+
+/// folder `id` of `all_docs` with everything inside it
+fn build_folder(all_docs: &RmkDocuments, id: &str, name: &str) -> FolderNode {
+    let children = all_docs.iter().filter(|doc| doc.parent == id);
+    FolderNode {
+        name: name.to_string(),
+        id: id.to_string(),
+        files_id: children
+            .clone()
+            .filter(|doc| doc.doc_type == DocType::DocumentType)
+            .map(|doc| doc.id.clone())
+            .collect(),
+        subfolders: children
+            .filter(|doc| doc.doc_type == DocType::CollectionType)
+            .map(|doc| build_folder(all_docs, &doc.id, &doc.vissible_name))
+            .collect(),
+    }
+}
+
 pub struct RemarkableFSHierarchy {
     pub all_docs: RmkDocuments,
     pub folder_hierarchy: FolderNode,
 }
 
-#[async_recursion(?Send)]
+/// Parse warnings are dropped, see [`fetch_documents_with_client`] to get them
 pub async fn fetch_documents(id: &str, name: &str) -> Result<RemarkableFSHierarchy> {
-    let mut all_docs: RmkDocuments = vec![];
-    let mut subfolders_hierarchy: Vec<FolderNode> = vec![];
+    fetch_documents_with_client(&reqwest::Client::new(), id, name, &SilentReporter).await
+}
 
-    let client = reqwest::Client::new();
-    let mut docs = client
+/// List the documents and folders directly inside the folder `id`, entries that cannot be read
+/// are reported as warnings and left out
+pub async fn list_folder(
+    client: &reqwest::Client,
+    id: &str,
+    reporter: &dyn Reporter,
+) -> Result<RmkDocuments> {
+    let listing = client
         .get(format!("http://10.11.99.1/documents/{}", id))
         .timeout(Duration::from_secs(1))
         .send()
         .await?
-        .json::<RmkDocuments>()
+        .json::<Value>()
         .await?;
+    let (docs, warnings) = parse_documents(listing)?;
+    for message in warnings {
+        reporter.report(Event::Warning { message });
+    }
+    Ok(docs)
+}
 
-    let sub_folder = docs
-        .iter()
-        .filter(|RmkDocument { doc_type, .. }| doc_type == &DocType::CollectionType);
-    for RmkDocument {
-        id, vissible_name, ..
-    } in sub_folder
-    {
-        let mut sub_hierarchy = fetch_documents(id, vissible_name).await?;
-        subfolders_hierarchy.push(sub_hierarchy.folder_hierarchy);
-        all_docs.append(&mut sub_hierarchy.all_docs);
+/// [`list_folder`] for each folder of `ids`, a few at a time if asked (see [`set_concurrency`]).
+/// A listing made concurrently must only hold children of its folder, otherwise everything is
+/// listed again one at a time
+pub async fn list_folders(
+    client: &reqwest::Client,
+    ids: &[String],
+    reporter: &dyn Reporter,
+) -> Result<Vec<RmkDocuments>> {
+    if concurrency() > 1 && ids.len() > 1 {
+        let listings = stream::iter(ids)
+            .map(|id| async move {
+                let docs = list_folder(client, id, reporter).await?;
+                match docs.iter().find(|doc| doc.parent != *id) {
+                    Some(doc) => Err(anyhow!(
+                        "'{}' was listed in the wrong folder",
+                        doc.vissible_name
+                    )),
+                    None => Ok(docs),
+                }
+            })
+            .buffered(concurrency())
+            .try_collect::<Vec<_>>()
+            .await;
+        match listings {
+            Ok(listings) => return Ok(listings),
+            Err(why) => fall_back_to_sequential(&why, reporter),
+        }
     }
 
-    let folder_hierarchy = FolderNode {
-        name: name.to_string(),
-        id: id.to_string(),
-        files_id: docs
+    let mut listings = vec![];
+    for id in ids {
+        listings.push(list_folder(client, id, reporter).await?);
+    }
+    Ok(listings)
+}
+
+/// same as [`fetch_documents`] but reuses an existing client and reports parse warnings
+pub async fn fetch_documents_with_client(
+    client: &reqwest::Client,
+    id: &str,
+    name: &str,
+    reporter: &dyn Reporter,
+) -> Result<RemarkableFSHierarchy> {
+    let mut all_docs: RmkDocuments = vec![];
+    // listed level by level, so that the folders of a level can be listed concurrently
+    let mut folders = vec![id.to_string()];
+    while !folders.is_empty() {
+        let listings = list_folders(client, &folders, reporter).await?;
+        folders = listings
             .iter()
-            .filter_map(|RmkDocument { id, doc_type, .. }| match doc_type {
-                DocType::DocumentType => Some(id.to_owned()),
-                _ => None,
-            })
-            .collect::<_>(),
-        subfolders: subfolders_hierarchy,
-    };
-    all_docs.append(&mut docs);
+            .flatten()
+            .filter(|doc| doc.doc_type == DocType::CollectionType)
+            .map(|doc| doc.id.clone())
+            .collect();
+        all_docs.extend(listings.into_iter().flatten());
+    }
 
     Ok(RemarkableFSHierarchy {
+        folder_hierarchy: build_folder(&all_docs, id, name),
         all_docs,
-        folder_hierarchy,
     })
 }
 
-pub fn copy_to_localfs(
-    folder_hierarchy: &FolderNode,
-    files: &[RmkFile],
-    path: &str,
-    udp_mode: bool,
-    override_mode: bool,
-) -> Result<()> {
-    // update path to the subfolder
-    let curr_path = &format!(
-        "{path}{}{}",
-        if path.ends_with('/') { "" } else { "/" },
-        folder_hierarchy.name
-    );
-    // create folder if does not exist
-    if !Path::new(curr_path).exists() {
-        let task = fs::create_dir(curr_path);
-        udp_return!(task, udp_mode, "Failed to create dir".red());
-    }
-
-    // copy current folder level files
-    let files_to_copy = files
-        .iter()
-        .filter(|(id, _, _)| folder_hierarchy.files_id.contains(id));
-    for (_, name, bytes) in files_to_copy {
-        let path = format!("{curr_path}/{name}");
-        if override_mode {
-            let task = fs::write(path, bytes);
-            udp_continue!(task, udp_mode, "Failed to write file".red());
-        } else {
-            let mut file = OpenOptions::new()
-                .write(true)
-                .append(false)
-                .truncate(true)
-                .create_new(true)
-                .open(path)?;
-            let task = file.write_all(bytes);
-            udp_continue!(task, udp_mode, "Failed to write file".red());
-        }
-    }
-
-    // create subfolders
-    for subfolder_hierarchy in &folder_hierarchy.subfolders {
-        let task = copy_to_localfs(
-            subfolder_hierarchy,
-            files,
-            curr_path,
-            udp_mode,
-            override_mode,
+static PDF_ROUTE: OnceLock<PdfRoute> = OnceLock::new();
+
+/// Export route used by the downloads, see [`crate::probe::probe_capabilities`] to detect it.
+/// Can only be set once, before the first download (`/download/{id}/placeholder` by default)
+pub fn set_pdf_route(route: PdfRoute) {
+    let _ = PDF_ROUTE.set(route);
+}
+
+static CONCURRENCY: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of requests sent to the remarkable at the same time (1 by default). Concurrent transfers
+/// fall back to one at a time for the rest of the run as soon as the remarkable fails one of them
+pub fn set_concurrency(requests: usize) {
+    CONCURRENCY.store(requests.max(1), Ordering::Relaxed);
+}
+
+pub fn concurrency() -> usize {
+    CONCURRENCY.load(Ordering::Relaxed)
+}
+
+/// the remarkable failed a concurrent request, everything is sent one at a time from now on
+fn fall_back_to_sequential(why: &anyhow::Error, reporter: &dyn Reporter) {
+    if concurrency() > 1 {
+        set_concurrency(1);
+        reporter.report(Event::Warning {
+            message: format!(
+                "The remarkable failed a concurrent request ({why}), sending the remaining ones one at a time"
+            ),
+        });
+    }
+}
+
+fn download_url(id: &str) -> String {
+    PDF_ROUTE.get().copied().unwrap_or_default().url(id)
+}
+
+/// download the pdf export of a document
+pub async fn download_file(client: &reqwest::Client, id: &str) -> Result<Vec<u8>> {
+    let resp = client.get(download_url(id)).send().await?;
+    Ok(resp.bytes().await?.to_vec())
+}
+
+/// attempts to get a valid file before giving up on a document
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+/// download a document once, reporting the progress as the bytes are received, and check the file
+async fn download_checked(
+    client: &reqwest::Client,
+    doc: &RmkDocument,
+    name: &str,
+    reporter: &dyn Reporter,
+) -> Result<(Vec<u8>, Validation)> {
+    let id = doc.id.as_str();
+    reporter.report(Event::DownloadStarted {
+        id: id.to_string(),
+        name: name.to_string(),
+        size_estimate: doc.size_estimate(),
+    });
+    let mut resp = client
+        .get(download_url(id))
+        .send()
+        .await?
+        .error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.to_string());
+    let mut bytes = vec![];
+    while let Some(chunk) = resp.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        reporter.report(Event::DownloadProgress {
+            id: id.to_string(),
+            downloaded: bytes.len() as u64,
+        });
+    }
+    let validation = validate_payload(&bytes, content_type.as_deref())?;
+    Ok((bytes, validation))
+}
+
+/// the exported PDF does not have the number of pages the remarkable reports for the document
+fn page_count_problem(doc: &RmkDocument, validation: &Validation) -> Option<String> {
+    let expected = doc.page_count.filter(|pages| *pages > 0)?;
+    let pages = validation.pages?;
+    (pages as i64 != expected).then(|| {
+        format!("the PDF has {pages} pages instead of the {expected} reported by the remarkable")
+    })
+}
+
+/// the export of an imported PDF holds the whole original, it cannot be much smaller than it
+fn size_problem(doc: &RmkDocument, size: usize) -> Option<String> {
+    let original = doc
+        .size_estimate()
+        .filter(|_| doc.file_type.as_deref() == Some("pdf"))?;
+    (2 * (size as u64) < original).then(|| {
+        format!("the file has {size} bytes, the PDF on the remarkable has {original} bytes")
+    })
+}
+
+/// Whether the file of a concurrent download can be matched with the document it was asked for:
+/// the remarkable reports its number of pages. The other documents are downloaded one at a time
+pub(crate) fn can_verify(doc: &RmkDocument) -> bool {
+    doc.page_count.is_some_and(|pages| pages > 0)
+}
+
+/// Single attempt at downloading a document concurrently with others, the file must match the
+/// document exactly: page count and size (see [`can_verify`]). Only reports
+/// [`Event::DownloadFinished`], a failure is up to the caller
+pub(crate) async fn download_attempt(
+    client: &reqwest::Client,
+    doc: &RmkDocument,
+    name: &str,
+    reporter: &dyn Reporter,
+) -> Result<Vec<u8>> {
+    let (bytes, validation) = download_checked(client, doc, name, reporter).await?;
+    if let Some(problem) =
+        page_count_problem(doc, &validation).or_else(|| size_problem(doc, bytes.len()))
+    {
+        return Err(anyhow!(problem));
+    }
+    reporter.report(Event::DownloadFinished {
+        id: doc.id.clone(),
+        name: name.to_string(),
+        size: bytes.len(),
+    });
+    Ok(bytes)
+}
+
+/// same as [`download_file`] but reports the progress of the download as the bytes are received,
+/// and checks the file (see [`crate::integrity`]): it is downloaded again if it is invalid
+pub async fn download_file_reported(
+    client: &reqwest::Client,
+    doc: &RmkDocument,
+    name: &str,
+    reporter: &dyn Reporter,
+) -> Result<Vec<u8>> {
+    let id = doc.id.as_str();
+    let mut attempt = 1;
+    let result = loop {
+        let last_attempt = attempt == DOWNLOAD_ATTEMPTS;
+        let problem = match download_checked(client, doc, name, reporter).await {
+            Ok((bytes, validation)) => match page_count_problem(doc, &validation) {
+                Some(problem) => anyhow!(problem),
+                None => break Ok(bytes),
+            },
+            Err(why) => why,
+        };
+        if last_attempt {
+            break Err(problem);
+        }
+        reporter.report(Event::Warning {
+            message: format!(
+                "Invalid download of '{name}': {problem}, downloading it again ({}/{DOWNLOAD_ATTEMPTS})",
+                attempt + 1
+            ),
+        });
+        attempt += 1;
+    };
+
+    match result {
+        Ok(bytes) => {
+            reporter.report(Event::DownloadFinished {
+                id: id.to_string(),
+                name: name.to_string(),
+                size: bytes.len(),
+            });
+            Ok(bytes)
+        }
+        Err(why) => {
+            reporter.report(Event::DownloadFailed {
+                id: id.to_string(),
+                name: name.to_string(),
+                error: why.to_string(),
+            });
+            Err(why)
+        }
+    }
+}
+
+/// Write the downloaded `files` into `storage`, in the folders of `folder_hierarchy` (or where
+/// `protection` stores them, encrypted if needed)
+pub fn copy_to_storage<'a>(
+    folder_hierarchy: &'a FolderNode,
+    files: &'a [RmkFile],
+    storage: &'a Storage,
+    protection: &'a Protection,
+    path: &'a str,
+    (udp_mode, override_mode): (bool, bool),
+    reporter: &'a dyn Reporter,
+) -> LocalBoxFuture<'a, Result<()>> {
+    async move {
+        // update path to the subfolder
+        let curr_path = &join_path(path, &folder_hierarchy.name);
+        if !protection.obfuscate_names {
+            let task = storage.create_dir(curr_path).await;
+            udp_return!(task, udp_mode, reporter, "Failed to create dir");
+        }
+
+        // copy current folder level files
+        let files_to_copy = files
+            .iter()
+            .filter(|(id, _, _)| folder_hierarchy.files_id.contains(id));
+        for (id, name, bytes) in files_to_copy {
+            let sealed = udp_continue!(
+                protection.seal(bytes),
+                udp_mode,
+                reporter,
+                format!("Failed to encrypt '{name}'")
+            );
+            let path = protection.stored_path(id, &join_path(curr_path, name));
+            let task = storage.write(&path, &sealed, override_mode).await;
+            udp_continue!(task, udp_mode, reporter, "Failed to write file");
+        }
+
+        // create subfolders
+        for subfolder_hierarchy in &folder_hierarchy.subfolders {
+            let task = copy_to_storage(
+                subfolder_hierarchy,
+                files,
+                storage,
+                protection,
+                curr_path,
+                (udp_mode, override_mode),
+                reporter,
+            )
+            .await;
+            udp_continue!(task, udp_mode, reporter, "Failed to copy subfolder");
+        }
+
+        Ok(())
+    }
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        integrity::{validate_file, FileFormat},
+        test_utils,
+    };
+
+    use super::*;
+
+    fn pdf(pages: i64, size: &str) -> RmkDocument {
+        RmkDocument {
+            id: "dune".to_string(),
+            file_type: Some("pdf".to_string()),
+            page_count: Some(pages),
+            size_in_bytes: Some(size.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn exported(pages: usize) -> Validation {
+        Validation {
+            format: FileFormat::Pdf,
+            pages: Some(pages),
+        }
+    }
+
+    #[test]
+    fn the_page_count_must_match() {
+        assert_eq!(page_count_problem(&pdf(3, "1000"), &exported(3)), None);
+        assert!(page_count_problem(&pdf(3, "1000"), &exported(1)).is_some());
+        // nothing to compare with
+        assert_eq!(page_count_problem(&pdf(0, "1000"), &exported(1)), None);
+
+        // with the pages read from the exported file
+        let validation = validate_file(&test_utils::pdf(2)).unwrap();
+        assert_eq!(page_count_problem(&pdf(2, "1000"), &validation), None);
+        assert_eq!(
+            page_count_problem(&pdf(5, "1000"), &validation).unwrap(),
+            "the PDF has 2 pages instead of the 5 reported by the remarkable"
         );
-        udp_continue!(task, udp_mode, "Failed to copy subfolder".red());
     }
 
-    Ok(())
+    #[test]
+    fn the_export_of_a_pdf_is_not_much_smaller_than_it() {
+        assert_eq!(size_problem(&pdf(3, "1000"), 900), None);
+        assert_eq!(size_problem(&pdf(3, "1000"), 5000), None);
+        assert!(size_problem(&pdf(3, "1000"), 400).is_some());
+        let notebook = RmkDocument {
+            file_type: Some("notebook".to_string()),
+            ..pdf(3, "1000")
+        };
+        assert_eq!(size_problem(&notebook, 10), None);
+    }
+
+    #[test]
+    fn documents_without_page_count_cannot_be_verified() {
+        assert!(can_verify(&pdf(3, "1000")));
+        assert!(!can_verify(&pdf(0, "1000")));
+        assert!(!can_verify(&RmkDocument::default()));
+    }
 }
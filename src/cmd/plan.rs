@@ -0,0 +1,227 @@
+//! What a transfer is about to download and whether it fits on the disk, checked before the
+//! first download.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use indicatif::HumanBytes;
+use serde_derive::Serialize;
+
+use crate::{
+    events::{Event, Reporter},
+    scheme::RmkDocument,
+    storage::is_remote,
+};
+
+use super::RemarkableFSHierarchy;
+
+/// Below this share of the free space left after the transfer, a warning is emitted: the sizes
+/// are estimated from the documents, the exported files may be a bit bigger
+const LOW_SPACE_MARGIN: f64 = 0.1;
+
+/// Documents planned in a top-level folder of the remarkable
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct FolderPlan {
+    /// e.g: "/Books", "/" for the documents at the root
+    pub folder: String,
+    pub documents: usize,
+    /// estimated from the `size_in_bytes` of the documents
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TransferPlan {
+    pub documents: usize,
+    /// estimated from the `size_in_bytes` of the documents
+    pub bytes: u64,
+    pub folders: Vec<FolderPlan>,
+    /// free space where the files are written, `None` if it cannot be known
+    pub free_space: Option<u64>,
+}
+
+/// "/Books" for "/Books/Fantasy/Dune", "/" for a document at the root
+fn top_level_folder(fs_hierarchy: &RemarkableFSHierarchy, doc: &RmkDocument) -> String {
+    let mut top = doc;
+    while let Some(parent) = fs_hierarchy.find_document(&top.parent) {
+        top = parent;
+    }
+    match top.id == doc.id {
+        true => "/".to_string(),
+        false => format!("/{}", top.vissible_name),
+    }
+}
+
+/// free space of the disk holding `path`, or its nearest existing parent
+fn available_space(path: &str) -> Option<u64> {
+    // the free space of a bucket or a WebDAV server is unknown
+    if is_remote(path) {
+        return None;
+    }
+    Path::new(path)
+        .ancestors()
+        .find(|path| path.exists())
+        .and_then(|path| fs2::available_space(path).ok())
+}
+
+impl TransferPlan {
+    /// Plan the download of the documents `ids` into `out_path`
+    pub fn new<'a>(
+        fs_hierarchy: &RemarkableFSHierarchy,
+        ids: impl IntoIterator<Item = &'a str>,
+        out_path: &str,
+    ) -> Self {
+        let mut plan = TransferPlan {
+            free_space: available_space(out_path),
+            ..Default::default()
+        };
+        for doc in ids
+            .into_iter()
+            .filter_map(|id| fs_hierarchy.find_document(id))
+        {
+            let folder = top_level_folder(fs_hierarchy, doc);
+            let bytes = doc.size_estimate().unwrap_or_default();
+            let index = match plan.folders.iter().position(|plan| plan.folder == folder) {
+                Some(index) => index,
+                None => {
+                    plan.folders.push(FolderPlan {
+                        folder,
+                        ..Default::default()
+                    });
+                    plan.folders.len() - 1
+                }
+            };
+            plan.folders[index].documents += 1;
+            plan.folders[index].bytes += bytes;
+            plan.documents += 1;
+            plan.bytes += bytes;
+        }
+        plan.folders.sort_by(|a, b| a.folder.cmp(&b.folder));
+        plan
+    }
+
+    /// Fail if the files do not fit in the free space, warn if they barely do
+    pub fn check_space(&self, out_path: &str, reporter: &dyn Reporter) -> Result<()> {
+        let Some(free_space) = self.free_space else {
+            return Ok(());
+        };
+        if self.bytes > free_space {
+            return Err(anyhow!(
+                "Not enough space in '{out_path}': about {} to download but only {} free",
+                HumanBytes(self.bytes),
+                HumanBytes(free_space)
+            ));
+        }
+        if (free_space - self.bytes) as f64 <= free_space as f64 * LOW_SPACE_MARGIN {
+            reporter.report(Event::Warning {
+                message: format!(
+                    "Low disk space in '{out_path}': about {} to download for {} free, the exported files may be bigger than estimated",
+                    HumanBytes(self.bytes),
+                    HumanBytes(free_space)
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{events::SilentReporter, scheme::DocType};
+
+    fn hierarchy() -> RemarkableFSHierarchy {
+        let folder = |id: &str, name: &str, parent: &str| RmkDocument {
+            id: id.to_string(),
+            vissible_name: name.to_string(),
+            parent: parent.to_string(),
+            doc_type: DocType::CollectionType,
+            ..Default::default()
+        };
+        let doc = |id: &str, parent: &str, size: &str| RmkDocument {
+            id: id.to_string(),
+            vissible_name: id.to_string(),
+            parent: parent.to_string(),
+            doc_type: DocType::DocumentType,
+            size_in_bytes: Some(size.to_string()),
+            ..Default::default()
+        };
+        RemarkableFSHierarchy::from_documents(vec![
+            folder("books", "Books", ""),
+            folder("fantasy", "Fantasy", "books"),
+            folder("work", "Work", ""),
+            doc("dune", "fantasy", "1000"),
+            doc("hobbit", "books", "500"),
+            doc("report", "work", "200"),
+            doc("notes", "", "not a size"),
+        ])
+    }
+
+    #[test]
+    fn counts_the_documents_per_top_level_folder() {
+        let plan = TransferPlan::new(
+            &hierarchy(),
+            ["dune", "hobbit", "notes", "report", "unknown"],
+            "s3://backups/remarkable",
+        );
+        assert_eq!(
+            plan,
+            TransferPlan {
+                documents: 4,
+                bytes: 1700,
+                folders: vec![
+                    FolderPlan {
+                        folder: "/".to_string(),
+                        documents: 1,
+                        bytes: 0,
+                    },
+                    FolderPlan {
+                        folder: "/Books".to_string(),
+                        documents: 2,
+                        bytes: 1500,
+                    },
+                    FolderPlan {
+                        folder: "/Work".to_string(),
+                        documents: 1,
+                        bytes: 200,
+                    },
+                ],
+                // unknown for a storage
+                free_space: None,
+            }
+        );
+        assert_eq!(TransferPlan::new(&hierarchy(), [], "/tmp").documents, 0);
+    }
+
+    #[test]
+    fn measures_the_free_space_of_the_nearest_existing_folder() {
+        let out_path = std::env::temp_dir().join("not-created-yet/backup");
+        let plan = TransferPlan::new(&hierarchy(), ["dune"], out_path.to_str().unwrap());
+        assert!(plan.free_space.is_some());
+    }
+
+    #[test]
+    fn checks_that_the_files_fit() {
+        let plan = |free_space| TransferPlan {
+            bytes: 1000,
+            free_space,
+            ..Default::default()
+        };
+        let warnings = RefCell::new(vec![]);
+        let reporter = |event| warnings.borrow_mut().push(event);
+
+        assert!(plan(None).check_space("/tmp", &reporter).is_ok());
+        assert!(plan(Some(100_000)).check_space("/tmp", &reporter).is_ok());
+        assert!(warnings.borrow().is_empty());
+
+        // less than 10% of the free space left
+        assert!(plan(Some(1050)).check_space("/tmp", &reporter).is_ok());
+        assert!(matches!(&warnings.borrow()[..], [Event::Warning { .. }]));
+
+        let why = plan(Some(999))
+            .check_space("/tmp", &SilentReporter)
+            .unwrap_err();
+        assert!(why.to_string().contains("Not enough space"), "{why}");
+    }
+}
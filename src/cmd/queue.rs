@@ -0,0 +1,96 @@
+//! Download several documents, a few at a time if asked (see [`super::set_concurrency`]).
+//!
+//! Older firmwares mix up the files of concurrent downloads: each file must have the page count (and
+//! size) of the document it was asked for, the first one that doesn't (or any other failure) makes
+//! the queue fall back to one download at a time, and the failed documents are downloaded again.
+//! Documents without a page count cannot be checked, they are always downloaded one at a time.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+
+use crate::{cancel::is_cancelled, events::Reporter, scheme::RmkDocument};
+
+use super::{
+    can_verify, concurrency, download_attempt, download_file_reported, fall_back_to_sequential,
+};
+
+struct Job<'a, T> {
+    /// whatever the caller needs to handle the downloaded file
+    data: T,
+    doc: &'a RmkDocument,
+    name: String,
+}
+
+type Attempt<'a, T> = LocalBoxFuture<'a, (Job<'a, T>, Result<Vec<u8>>)>;
+
+pub struct DownloadQueue<'a, T> {
+    client: &'a reqwest::Client,
+    reporter: &'a dyn Reporter,
+    pending: VecDeque<Job<'a, T>>,
+    in_flight: FuturesUnordered<Attempt<'a, T>>,
+}
+
+impl<'a, T: 'a> DownloadQueue<'a, T> {
+    pub fn new(client: &'a reqwest::Client, reporter: &'a dyn Reporter) -> Self {
+        Self {
+            client,
+            reporter,
+            pending: VecDeque::new(),
+            in_flight: FuturesUnordered::new(),
+        }
+    }
+
+    /// download `doc` as `name`, `data` is given back along with the file
+    pub fn push(&mut self, data: T, doc: &'a RmkDocument, name: String) {
+        self.pending.push_back(Job { data, doc, name });
+    }
+
+    /// Next downloaded file (in the order they finish), `None` once everything is downloaded.
+    ///
+    /// Once cancelled (see [`crate::cancel`]), the downloads in progress finish but no new one starts
+    pub async fn next(&mut self) -> Option<(T, Result<Vec<u8>>)> {
+        loop {
+            if is_cancelled() {
+                self.pending.clear();
+            }
+            let limit = concurrency();
+            while limit > 1 && self.in_flight.len() < limit {
+                // the others are downloaded one at a time once these are done
+                let Some(job) = self
+                    .pending
+                    .iter()
+                    .position(|job| can_verify(job.doc))
+                    .and_then(|index| self.pending.remove(index))
+                else {
+                    break;
+                };
+                let (client, reporter) = (self.client, self.reporter);
+                self.in_flight.push(
+                    async move {
+                        let result = download_attempt(client, job.doc, &job.name, reporter).await;
+                        (job, result)
+                    }
+                    .boxed_local(),
+                );
+            }
+
+            // empty once everything is sent one at a time
+            match self.in_flight.next().await {
+                Some((job, Ok(bytes))) => return Some((job.data, Ok(bytes))),
+                Some((job, Err(why))) => {
+                    fall_back_to_sequential(&why, self.reporter);
+                    self.pending.push_front(job);
+                    continue;
+                }
+                None => {}
+            }
+
+            let job = self.pending.pop_front()?;
+            let result =
+                download_file_reported(self.client, job.doc, &job.name, self.reporter).await;
+            return Some((job.data, result));
+        }
+    }
+}
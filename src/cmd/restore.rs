@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    crypto::ensure_not_encrypted,
+    events::{Event, Reporter},
+    manifest::{Manifest, MANIFEST_FILE},
+    scheme::DocType,
+    utils::{is_dir, join_path},
+};
+
+use super::{
+    upload::{is_uploadable, upload_files, UploadTask},
+    RemarkableFSHierarchy,
+};
+
+/// A document found in the backup
+pub struct BackupDocument {
+    /// folder of the document in the remarkable, e.g: "root/Books"
+    pub folder: String,
+    /// name of the file, e.g: "Dune.pdf"
+    pub name: String,
+    pub local_path: PathBuf,
+}
+
+pub enum RestoreAction {
+    Upload {
+        doc: BackupDocument,
+        folder_id: String,
+        folder: String,
+    },
+    Skip {
+        doc: BackupDocument,
+        reason: String,
+    },
+    /// the folder of the document does not exist on the remarkable, which cannot create it
+    MissingFolder {
+        doc: BackupDocument,
+    },
+}
+
+pub struct RestorePlan {
+    /// folders of the backup that do not exist in the remarkable
+    pub missing_folders: Vec<String>,
+    pub actions: Vec<RestoreAction>,
+}
+
+impl RestorePlan {
+    pub fn uploads(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|action| matches!(action, RestoreAction::Upload { .. }))
+            .count()
+    }
+
+    /// Fail if some documents cannot be put back in their folder: the web interface of the
+    /// remarkable cannot create folders, a partial restore would leave them out
+    pub fn ensure_complete(&self) -> Result<()> {
+        let blocked = self
+            .actions
+            .iter()
+            .filter(|action| matches!(action, RestoreAction::MissingFolder { .. }))
+            .count();
+        if blocked == 0 {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{blocked} documents are in folders that do not exist on the remarkable, and its web interface cannot create folders: create {} on your tablet and run restore again, or use '--into-nearest-folder'",
+            self.missing_folders
+                .iter()
+                .map(|folder| format!("'{folder}'"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+}
+
+/// the remarkable strips the extension of the uploaded files, so names are compared without it
+pub(super) fn normalize_name(name: &str) -> String {
+    let lower = name.to_lowercase();
+    match lower.strip_suffix(".pdf").or(lower.strip_suffix(".epub")) {
+        Some(stem) => stem.to_string(),
+        None => lower,
+    }
+}
+
+fn parent_folder(path: &str) -> Option<&str> {
+    path.rsplit_once('/').map(|(parent, _)| parent)
+}
+
+/// Read the documents of a backup, from its manifest if there is one, by walking the folder otherwise
+fn read_backup(backup_path: &str, reporter: &dyn Reporter) -> Result<Vec<BackupDocument>> {
+    let manifest_path = Path::new(backup_path).join(MANIFEST_FILE);
+    if manifest_path.is_file() {
+        let manifest: Manifest = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+        reporter.report(Event::Info {
+            message: format!(
+                "Using the manifest of the backup made the {}",
+                manifest.created_at
+            ),
+        });
+        return Ok(manifest
+            .documents
+            .into_iter()
+            .filter_map(|entry| {
+                let local_path = Path::new(backup_path).join(&entry.path);
+                if !local_path.is_file() {
+                    reporter.report(Event::Warning {
+                        message: format!(
+                            "'{}' is in the manifest but not in the backup, ignoring it",
+                            entry.path
+                        ),
+                    });
+                    return None;
+                }
+                Some(BackupDocument {
+                    folder: parent_folder(&entry.path).unwrap_or("root").to_string(),
+                    name: entry.name,
+                    local_path,
+                })
+            })
+            .collect());
+    }
+
+    fn walk(dir: &Path, folder: &str, docs: &mut Vec<BackupDocument>) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path.is_dir() {
+                walk(&path, &join_path(folder, &name), docs)?;
+            } else if is_uploadable(&path) {
+                docs.push(BackupDocument {
+                    folder: folder.to_string(),
+                    name,
+                    local_path: path,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // backups made by this cli store everything under a "root" folder
+    let root_path = Path::new(backup_path).join("root");
+    let root_path = match root_path.is_dir() {
+        true => root_path,
+        false => PathBuf::from(backup_path),
+    };
+    let mut docs = vec![];
+    walk(&root_path, "root", &mut docs)?;
+    Ok(docs)
+}
+
+/// Compare the backup with the remarkable to know what has to be uploaded where
+pub fn plan_restore(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    backup_path: &str,
+    into_nearest_folder: bool,
+    reporter: &dyn Reporter,
+) -> Result<RestorePlan> {
+    if !is_dir(backup_path) {
+        return Err(anyhow!("'{backup_path}' is not a directory"));
+    }
+    ensure_not_encrypted(backup_path)?;
+    let backup_docs = read_backup(backup_path, reporter)?;
+
+    let device_folders = fs_hierarchy
+        .folder_hierarchy
+        .folder_paths("")
+        .into_iter()
+        .map(|(id, path)| (path, id))
+        .collect::<HashMap<_, _>>();
+    let device_files = fs_hierarchy
+        .folder_hierarchy
+        .files_path("")
+        .into_iter()
+        .filter_map(|(id, folder)| {
+            fs_hierarchy
+                .all_docs
+                .iter()
+                .find(|doc| doc.id == id && doc.doc_type == DocType::DocumentType)
+                .map(|doc| (folder, normalize_name(&doc.vissible_name)))
+        })
+        .collect::<HashSet<_>>();
+
+    let mut missing_folders = vec![];
+    // documents planned for upload, by folder and name on the remarkable
+    let mut planned: HashMap<(String, String), PathBuf> = HashMap::new();
+    let mut actions = vec![];
+    for doc in backup_docs {
+        let target = match device_folders.get(&doc.folder) {
+            Some(folder_id) => Some((doc.folder.clone(), folder_id.to_owned())),
+            None => {
+                // the folder does not exist on the remarkable
+                let mut folder = doc.folder.as_str();
+                while let Some(parent) = parent_folder(folder) {
+                    if !missing_folders.iter().any(|missing| missing == folder) {
+                        missing_folders.push(folder.to_string());
+                    }
+                    folder = parent;
+                    if device_folders.contains_key(folder) {
+                        break;
+                    }
+                }
+                device_folders
+                    .get(folder)
+                    .filter(|_| into_nearest_folder)
+                    .map(|folder_id| (folder.to_string(), folder_id.to_owned()))
+            }
+        };
+        let Some((folder, folder_id)) = target else {
+            actions.push(RestoreAction::MissingFolder { doc });
+            continue;
+        };
+
+        let key = (folder, normalize_name(&doc.name));
+        if device_files.contains(&key) {
+            actions.push(RestoreAction::Skip {
+                doc,
+                reason: "already on the remarkable".to_string(),
+            });
+            continue;
+        }
+        if let Some(first) = planned.get(&key) {
+            actions.push(RestoreAction::Skip {
+                reason: format!(
+                    "conflicts with '{}', both would be uploaded as '{}' into '{}'",
+                    first.display(),
+                    key.1,
+                    key.0
+                ),
+                doc,
+            });
+            continue;
+        }
+        planned.insert(key.clone(), doc.local_path.clone());
+        actions.push(RestoreAction::Upload {
+            folder_id,
+            folder: key.0,
+            doc,
+        });
+    }
+    missing_folders.sort();
+
+    Ok(RestorePlan {
+        missing_folders,
+        actions,
+    })
+}
+
+/// Upload every document planned for upload, nothing if some of them have no folder to go to
+pub async fn execute_restore(
+    plan: RestorePlan,
+    udp_mode: bool,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    plan.ensure_complete()?;
+    let uploads = plan
+        .actions
+        .into_iter()
+        .filter_map(|action| match action {
+            RestoreAction::Upload {
+                doc,
+                folder_id,
+                folder,
+            } => Some(UploadTask {
+                local_path: doc.local_path,
+                folder_id,
+                folder,
+            }),
+            RestoreAction::Skip { .. } | RestoreAction::MissingFolder { .. } => None,
+        })
+        .collect::<Vec<_>>();
+    upload_files(&reqwest::Client::new(), &uploads, udp_mode, reporter).await?;
+    reporter.report(Event::Completed {
+        message: "Finished restoring the backup".to_string(),
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{events::SilentReporter, scheme::RmkDocument, test_utils::TempDir};
+
+    use super::*;
+
+    fn doc(id: &str, name: &str, parent: &str, doc_type: DocType) -> RmkDocument {
+        RmkDocument {
+            id: id.to_string(),
+            vissible_name: name.to_string(),
+            parent: parent.to_string(),
+            doc_type,
+            ..Default::default()
+        }
+    }
+
+    /// the remarkable: /Books/Dune and /Notes
+    fn device() -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(vec![
+            doc("books", "Books", "", DocType::CollectionType),
+            doc("dune", "Dune", "books", DocType::DocumentType),
+            doc("notes", "Notes", "", DocType::DocumentType),
+        ])
+    }
+
+    fn summary(plan: &RestorePlan) -> Vec<(String, &str)> {
+        let mut actions = plan
+            .actions
+            .iter()
+            .map(|action| match action {
+                RestoreAction::Upload { doc, folder, .. } => {
+                    (join_path(&doc.folder, &doc.name), folder.as_str())
+                }
+                RestoreAction::Skip { doc, .. } => (join_path(&doc.folder, &doc.name), "skip"),
+                RestoreAction::MissingFolder { doc } => {
+                    (join_path(&doc.folder, &doc.name), "missing")
+                }
+            })
+            .collect::<Vec<_>>();
+        actions.sort();
+        actions
+    }
+
+    #[test]
+    fn documents_already_on_the_remarkable_are_skipped() {
+        let backup = TempDir::with_files(
+            "restore-skip",
+            &[
+                "root/Books/Dune.pdf",
+                "root/Books/Emma.pdf",
+                "root/Notes.pdf",
+            ],
+        );
+        let plan = plan_restore(&device(), backup.path(), false, &SilentReporter).unwrap();
+        assert_eq!(
+            summary(&plan),
+            [
+                ("root/Books/Dune.pdf".to_string(), "skip"),
+                ("root/Books/Emma.pdf".to_string(), "root/Books"),
+                ("root/Notes.pdf".to_string(), "skip"),
+            ]
+        );
+        assert!(plan.ensure_complete().is_ok());
+    }
+
+    #[test]
+    fn missing_folders_block_the_restore() {
+        let backup = TempDir::with_files(
+            "restore-missing",
+            &["root/Books/Fantasy/Old/Hobbit.pdf", "root/Books/Emma.pdf"],
+        );
+        let plan = plan_restore(&device(), backup.path(), false, &SilentReporter).unwrap();
+        assert_eq!(
+            plan.missing_folders,
+            ["root/Books/Fantasy", "root/Books/Fantasy/Old"]
+        );
+        assert_eq!(
+            summary(&plan),
+            [
+                ("root/Books/Emma.pdf".to_string(), "root/Books"),
+                ("root/Books/Fantasy/Old/Hobbit.pdf".to_string(), "missing"),
+            ]
+        );
+        assert!(plan.ensure_complete().is_err());
+    }
+
+    #[test]
+    fn into_nearest_folder_uploads_into_the_nearest_existing_parent() {
+        let backup = TempDir::with_files(
+            "restore-nearest",
+            &[
+                "root/Books/Fantasy/Hobbit.pdf",
+                "root/Books/Fantasy/Dune.pdf",
+            ],
+        );
+        let plan = plan_restore(&device(), backup.path(), true, &SilentReporter).unwrap();
+        assert_eq!(plan.missing_folders, ["root/Books/Fantasy"]);
+        assert_eq!(
+            summary(&plan),
+            [
+                // Dune is already in /Books
+                ("root/Books/Fantasy/Dune.pdf".to_string(), "skip"),
+                ("root/Books/Fantasy/Hobbit.pdf".to_string(), "root/Books"),
+            ]
+        );
+        assert!(plan.ensure_complete().is_ok());
+    }
+
+    #[test]
+    fn documents_with_the_same_name_are_uploaded_once() {
+        let backup = TempDir::with_files(
+            "restore-conflict",
+            &["root/Emma.pdf", "root/Emma.epub", "root/Sub/Emma.pdf"],
+        );
+        let plan = plan_restore(&device(), backup.path(), true, &SilentReporter).unwrap();
+        assert_eq!(plan.uploads(), 1);
+        assert_eq!(
+            summary(&plan),
+            [
+                ("root/Emma.epub".to_string(), "root"),
+                ("root/Emma.pdf".to_string(), "skip"),
+                ("root/Sub/Emma.pdf".to_string(), "skip"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use serde_derive::Serialize;
+
+use crate::{
+    crypto::ensure_not_encrypted,
+    manifest::{Manifest, ManifestEntry, MANIFEST_FILE},
+    utils::{is_dir, join_path},
+};
+
+use super::RemarkableFSHierarchy;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "change", content = "from")]
+pub enum Change {
+    /// on the remarkable but not in the backup
+    New,
+    /// modified on the remarkable since it was backed up
+    Modified,
+    /// moved or renamed on the remarkable, from its path in the backup
+    Moved(String),
+    /// in the backup but deleted from the remarkable
+    Deleted,
+    /// a file of the backup folder that is not a document of the remarkable
+    Untracked,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangedDocument {
+    /// `None` for untracked files
+    pub id: Option<String>,
+    /// path relative to the backup folder, e.g: "root/Books/Dune.pdf"
+    pub path: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BackupStatus {
+    /// when the backup was made, `None` if it has no manifest (the changes are then found from
+    /// the paths and modification times of the files)
+    pub created_at: Option<String>,
+    /// documents of the remarkable that are up to date in the backup
+    pub up_to_date: usize,
+    pub changes: Vec<ChangedDocument>,
+}
+
+/// whether the document was modified on the remarkable after the local file was written
+fn modified_after(entry: &ManifestEntry, file_path: &Path) -> bool {
+    let Ok(device_modified) = DateTime::<chrono::Local>::from_str(&entry.modified_client) else {
+        return true;
+    };
+    match fs::metadata(file_path).and_then(|metadata| metadata.modified()) {
+        Ok(local_modified) => device_modified > DateTime::<chrono::Local>::from(local_modified),
+        Err(_) => true,
+    }
+}
+
+/// every file of the backup (outside of the tag view, manifest and lock), relative to `backup_path`
+fn local_files(backup_path: &str) -> Result<Vec<String>> {
+    fn walk(dir: &Path, relative: &str, files: &mut Vec<String>) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = join_path(relative, &name);
+            match entry.file_type()?.is_dir() {
+                true => walk(&entry.path(), &path, files)?,
+                // files being written by a transfer
+                false if name.ends_with(".part") => {}
+                false => files.push(path),
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    let root_path = Path::new(backup_path).join("root");
+    if root_path.is_dir() {
+        walk(&root_path, "root", &mut files)?;
+    }
+    Ok(files)
+}
+
+/// Compare a backup folder with the remarkable, nothing is downloaded. Documents are matched by ID
+/// with the manifest of the backup, by path and modification time when the backup has none
+pub fn backup_status(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    backup_path: &str,
+) -> Result<BackupStatus> {
+    if !is_dir(backup_path) {
+        return Err(anyhow!("'{backup_path}' is not a directory"));
+    }
+    ensure_not_encrypted(backup_path)?;
+    let manifest: Option<Manifest> = match fs::read(Path::new(backup_path).join(MANIFEST_FILE)) {
+        Ok(raw) => Some(serde_json::from_slice(&raw)?),
+        Err(_) => None,
+    };
+    let backed_up = manifest
+        .iter()
+        .flat_map(|manifest| &manifest.documents)
+        .map(|entry| (entry.id.as_str(), entry))
+        .collect::<HashMap<_, _>>();
+    let local_files = local_files(backup_path)?;
+    let local_paths = local_files
+        .iter()
+        .map(|path| path.as_str())
+        .collect::<HashSet<_>>();
+
+    let mut status = BackupStatus {
+        created_at: manifest
+            .as_ref()
+            .map(|manifest| manifest.created_at.clone()),
+        ..Default::default()
+    };
+    let device = Manifest::from_hierarchy(fs_hierarchy);
+    for entry in &device.documents {
+        let file_path = Path::new(backup_path).join(&entry.path);
+        let change = match backed_up.get(entry.id.as_str()) {
+            Some(old) if local_paths.contains(old.path.as_str()) => {
+                if old.path != entry.path {
+                    Some(Change::Moved(old.path.clone()))
+                } else if old.modified_client != entry.modified_client {
+                    Some(Change::Modified)
+                } else {
+                    None
+                }
+            }
+            // not in the manifest (or its file was removed): compare with the file at its path
+            _ if local_paths.contains(entry.path.as_str()) => {
+                modified_after(entry, &file_path).then_some(Change::Modified)
+            }
+            _ => Some(Change::New),
+        };
+        match change {
+            Some(change) => status.changes.push(ChangedDocument {
+                id: Some(entry.id.clone()),
+                path: entry.path.clone(),
+                change,
+            }),
+            None => status.up_to_date += 1,
+        }
+    }
+
+    let device_ids = device
+        .documents
+        .iter()
+        .map(|entry| entry.id.as_str())
+        .collect::<HashSet<_>>();
+    let device_paths = device
+        .documents
+        .iter()
+        .map(|entry| entry.path.as_str())
+        .collect::<HashSet<_>>();
+    let moved_from = status
+        .changes
+        .iter()
+        .filter_map(|document| match &document.change {
+            Change::Moved(from) => Some(from.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let deleted = backed_up
+        .values()
+        .filter(|old| !device_ids.contains(old.id.as_str()))
+        .map(|old| (old.path.as_str(), old.id.as_str()))
+        .collect::<HashMap<_, _>>();
+    for path in local_files {
+        if device_paths.contains(path.as_str()) || moved_from.contains(&path) {
+            continue;
+        }
+        let (id, change) = match deleted.get(path.as_str()) {
+            Some(id) => (Some(id.to_string()), Change::Deleted),
+            None => (None, Change::Untracked),
+        };
+        status.changes.push(ChangedDocument { id, path, change });
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::encrypted_manifest_file,
+        scheme::{DocType, RmkDocument},
+        test_utils::TempDir,
+    };
+
+    /// write the manifest of the backup in `dir`, `documents`: (id, path, modified_client)
+    fn write_manifest(dir: &TempDir, documents: &[(&str, &str, &str)]) {
+        let manifest = Manifest {
+            version: 1,
+            created_at: "2024-01-31T18:02:00+00:00".to_string(),
+            documents: documents
+                .iter()
+                .map(|(id, path, modified_client)| ManifestEntry {
+                    id: id.to_string(),
+                    path: path.to_string(),
+                    modified_client: modified_client.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+    }
+
+    const OLD: &str = "2024-01-01T10:00:00.000Z";
+    const NEW: &str = "2024-02-01T10:00:00.000Z";
+
+    fn document(id: &str, name: &str, parent: &str, modified_client: &str) -> RmkDocument {
+        RmkDocument {
+            id: id.to_string(),
+            vissible_name: name.to_string(),
+            parent: parent.to_string(),
+            doc_type: DocType::DocumentType,
+            modified_client: modified_client.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn device() -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(vec![
+            RmkDocument {
+                id: "books".to_string(),
+                vissible_name: "Books".to_string(),
+                doc_type: DocType::CollectionType,
+                ..Default::default()
+            },
+            document("dune", "Dune", "", OLD),
+            document("course", "Course", "", NEW),
+            document("notes", "Notes", "books", OLD),
+            document("new", "New", "", OLD),
+        ])
+    }
+
+    /// the changes by path, sorted
+    fn changes(status: &BackupStatus) -> Vec<(String, Option<String>, Change)> {
+        let mut changes = status
+            .changes
+            .iter()
+            .map(|document| {
+                (
+                    document.path.clone(),
+                    document.id.clone(),
+                    document.change.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    #[test]
+    fn classifies_changes_with_the_manifest() {
+        let backup = TempDir::with_files(
+            "status-manifest",
+            &[
+                "root/Dune.pdf",
+                "root/Course.pdf",
+                "root/Notes.pdf",
+                "root/Gone.pdf",
+                "root/Stray.pdf",
+            ],
+        );
+        write_manifest(
+            &backup,
+            &[
+                ("dune", "root/Dune.pdf", OLD),
+                ("course", "root/Course.pdf", OLD),
+                ("notes", "root/Notes.pdf", OLD),
+                ("gone", "root/Gone.pdf", OLD),
+            ],
+        );
+
+        let status = backup_status(&device(), backup.path()).unwrap();
+        assert_eq!(
+            status.created_at.as_deref(),
+            Some("2024-01-31T18:02:00+00:00")
+        );
+        assert_eq!(status.up_to_date, 1);
+        let some = |id: &str| Some(id.to_string());
+        assert_eq!(
+            changes(&status),
+            [
+                (
+                    "root/Books/Notes.pdf".to_string(),
+                    some("notes"),
+                    Change::Moved("root/Notes.pdf".to_string())
+                ),
+                (
+                    "root/Course.pdf".to_string(),
+                    some("course"),
+                    Change::Modified
+                ),
+                ("root/Gone.pdf".to_string(), some("gone"), Change::Deleted),
+                ("root/New.pdf".to_string(), some("new"), Change::New),
+                ("root/Stray.pdf".to_string(), None, Change::Untracked),
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_dates_without_a_manifest() {
+        let backup = TempDir::with_files(
+            "status-no-manifest",
+            &["root/Dune.pdf", "root/Books/Notes.pdf"],
+        );
+        let future = (chrono::Local::now() + chrono::Duration::days(1)).to_rfc3339();
+        let device = RemarkableFSHierarchy::from_documents(vec![
+            RmkDocument {
+                id: "books".to_string(),
+                vissible_name: "Books".to_string(),
+                doc_type: DocType::CollectionType,
+                ..Default::default()
+            },
+            // written after the last change on the remarkable
+            document("dune", "Dune", "", OLD),
+            document("notes", "Notes", "books", &future),
+        ]);
+
+        let status = backup_status(&device, backup.path()).unwrap();
+        assert_eq!(status.created_at, None);
+        assert_eq!(status.up_to_date, 1);
+        assert_eq!(
+            changes(&status),
+            [(
+                "root/Books/Notes.pdf".to_string(),
+                Some("notes".to_string()),
+                Change::Modified
+            )]
+        );
+    }
+
+    #[test]
+    fn refuses_encrypted_backups() {
+        let backup = TempDir::new("status-encrypted");
+        fs::write(backup.join(&encrypted_manifest_file()), b"age").unwrap();
+        assert!(backup_status(&device(), backup.path()).is_err());
+    }
+}
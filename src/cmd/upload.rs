@@ -0,0 +1,277 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::multipart::{Form, Part};
+
+use crate::{
+    cancel::{is_cancelled, Cancelled},
+    events::{Event, Reporter},
+    scheme::DocType,
+    utils::{join_path, udp_continue},
+};
+
+use super::{
+    dry_run::PlannedAction, normalize_path, restore::normalize_name, RemarkableFSHierarchy,
+};
+
+/// A local file to upload into a folder of the remarkable
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadTask {
+    pub local_path: PathBuf,
+    /// "" is for root
+    pub folder_id: String,
+    /// path of the folder, only used to report the progress
+    pub folder: String,
+}
+
+/// Extensions of the files accepted by the remarkable web interface
+pub const UPLOADABLE_EXTENSIONS: [&str; 2] = ["pdf", "epub"];
+
+pub fn is_uploadable(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| UPLOADABLE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// id of the folder at `path` in the remarkable, "" for root
+fn find_folder_id<'a>(fs_hierarchy: &'a RemarkableFSHierarchy, path: &str) -> Result<&'a str> {
+    if normalize_path(path) == "/" {
+        return Ok("");
+    }
+    match fs_hierarchy.find_by_path(path) {
+        Some(doc) if doc.doc_type == DocType::CollectionType => Ok(&doc.id),
+        Some(_) => Err(anyhow!("'{path}' is not a folder")),
+        None => Err(anyhow!("No such folder: '{path}'")),
+    }
+}
+
+/// What uploading the local files and folders `local_paths` into the folder `upload_path` of the
+/// remarkable would do. The web interface cannot create folders: the files of a local folder go
+/// into the folder of the same name, which must already exist. Documents already present are skipped
+pub fn plan_upload(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    local_paths: &[String],
+    upload_path: &str,
+) -> Result<Vec<PlannedAction>> {
+    fn plan_path(
+        fs_hierarchy: &RemarkableFSHierarchy,
+        path: &Path,
+        folder_id: Option<&str>,
+        folder: &str,
+        actions: &mut Vec<PlannedAction>,
+    ) -> Result<()> {
+        // e.g: "." or ".."
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => fs::canonicalize(path)?
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        let children = |doc_type: DocType| {
+            folder_id
+                .map(|id| fs_hierarchy.children(id))
+                .unwrap_or_default()
+                .into_iter()
+                .filter(move |doc| doc.doc_type == doc_type)
+        };
+
+        if path.is_dir() {
+            let subfolder_id = children(DocType::CollectionType)
+                .find(|doc| doc.vissible_name == name)
+                .map(|doc| doc.id.as_str());
+            let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries {
+                plan_path(
+                    fs_hierarchy,
+                    &entry.path(),
+                    subfolder_id,
+                    &join_path(folder, &name),
+                    actions,
+                )?;
+            }
+            return Ok(());
+        }
+
+        let local_path = path.display().to_string();
+        let skip_reason = match folder_id {
+            _ if !is_uploadable(path) => Some("neither a pdf nor an epub file".to_string()),
+            None => Some(format!(
+                "folder '{folder}' does not exist on the remarkable"
+            )),
+            Some(_) => children(DocType::DocumentType)
+                .any(|doc| normalize_name(&doc.vissible_name) == normalize_name(&name))
+                .then(|| "already on the remarkable".to_string()),
+        };
+        actions.push(match (skip_reason, folder_id) {
+            (Some(reason), _) => PlannedAction::Skip {
+                path: local_path,
+                reason,
+            },
+            (None, folder_id) => PlannedAction::Upload {
+                local_path,
+                folder: folder.to_string(),
+                folder_id: folder_id.unwrap_or_default().to_string(),
+            },
+        });
+        Ok(())
+    }
+
+    let folder_id = find_folder_id(fs_hierarchy, upload_path)?;
+    let mut actions = vec![];
+    for local_path in local_paths {
+        let path = Path::new(local_path);
+        if !path.exists() {
+            return Err(anyhow!("No such file or folder: '{local_path}'"));
+        }
+        plan_path(
+            fs_hierarchy,
+            path,
+            Some(folder_id),
+            &normalize_path(upload_path),
+            &mut actions,
+        )?;
+    }
+    Ok(actions)
+}
+
+/// Upload what [`plan_upload`] planned, the skipped files are reported
+pub async fn upload_planned(
+    client: &reqwest::Client,
+    actions: Vec<PlannedAction>,
+    udp_mode: bool,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let mut uploads = vec![];
+    for action in actions {
+        match action {
+            PlannedAction::Upload {
+                local_path,
+                folder,
+                folder_id,
+            } => uploads.push(UploadTask {
+                local_path: PathBuf::from(local_path),
+                folder_id,
+                folder,
+            }),
+            PlannedAction::Skip { path, reason } => reporter.report(Event::Skipped {
+                reason: format!("'{path}': {reason}"),
+            }),
+            _ => {}
+        }
+    }
+    upload_files(client, &uploads, udp_mode, reporter).await?;
+    reporter.report(Event::Completed {
+        message: format!("Finished uploading {} files", uploads.len()),
+    });
+    Ok(())
+}
+
+/// Upload a local pdf/epub file into the folder `folder_id` ("" is for root)
+pub async fn upload_file(client: &reqwest::Client, folder_id: &str, path: &Path) -> Result<()> {
+    if !is_uploadable(path) {
+        return Err(anyhow!(
+            "'{}' is neither a pdf nor an epub file",
+            path.display()
+        ));
+    }
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(anyhow!("Invalid file name: '{}'", path.display()))?
+        .to_string();
+    let mime = match file_name.to_lowercase().ends_with(".epub") {
+        true => "application/epub+zip",
+        false => "application/pdf",
+    };
+    let bytes = fs::read(path)?;
+
+    // the web interface uploads in the last folder that has been listed
+    client
+        .get(format!("http://10.11.99.1/documents/{folder_id}"))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let form = Form::new().part(
+        "file",
+        Part::bytes(bytes).file_name(file_name).mime_str(mime)?,
+    );
+    client
+        .post("http://10.11.99.1/upload")
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Upload files one by one, reporting the progress of each of them
+pub async fn upload_files(
+    client: &reqwest::Client,
+    uploads: &[UploadTask],
+    udp_mode: bool,
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let file_size = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .unwrap_or_default()
+    };
+    reporter.report(Event::TransferPlanned {
+        total: uploads.len(),
+        total_bytes: uploads
+            .iter()
+            .map(|upload| file_size(&upload.local_path))
+            .sum(),
+    });
+
+    for UploadTask {
+        local_path,
+        folder_id,
+        folder,
+    } in uploads
+    {
+        if is_cancelled() {
+            break;
+        }
+        let path = local_path.display().to_string();
+        reporter.report(Event::UploadStarted {
+            path: path.clone(),
+            folder: folder.clone(),
+        });
+        let task = upload_file(client, folder_id, local_path).await;
+        if let Err(why) = &task {
+            reporter.report(Event::UploadFailed {
+                path: path.clone(),
+                error: why.to_string(),
+            });
+        }
+        udp_continue!(
+            task,
+            udp_mode,
+            reporter,
+            format!("Failed to upload '{path}'")
+        );
+        reporter.report(Event::UploadFinished {
+            path,
+            folder: folder.clone(),
+            size: file_size(local_path),
+        });
+    }
+
+    if is_cancelled() {
+        reporter.report(Event::Interrupted {
+            message: "Upload interrupted, the remaining files have not been uploaded".to_string(),
+        });
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
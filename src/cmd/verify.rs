@@ -0,0 +1,201 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde_derive::Serialize;
+
+use crate::{
+    crypto::ensure_not_encrypted,
+    integrity::{sha256_hex, validate_file},
+    manifest::{Manifest, ManifestEntry, MANIFEST_FILE},
+};
+
+use super::archive::{for_each_file, read_manifest};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "reason")]
+pub enum FileStatus {
+    Ok,
+    /// listed in the manifest but not in the backup
+    Missing,
+    /// the file changed since it was backed up
+    ChecksumMismatch,
+    /// the file looks fine but the backup was made before the checksums were recorded
+    NoChecksum,
+    /// the file is not a readable PDF/EPUB
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifiedFile {
+    pub id: String,
+    pub path: String,
+    #[serde(flatten)]
+    pub status: FileStatus,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    /// when the verified backup was made
+    pub created_at: String,
+    pub files: Vec<VerifiedFile>,
+}
+
+impl VerifyReport {
+    /// files that are missing, modified or unreadable
+    pub fn failures(&self) -> impl Iterator<Item = &VerifiedFile> {
+        self.files
+            .iter()
+            .filter(|file| !matches!(file.status, FileStatus::Ok | FileStatus::NoChecksum))
+    }
+}
+
+fn check(entry: &ManifestEntry, bytes: Option<&[u8]>) -> VerifiedFile {
+    let status = match bytes {
+        None => FileStatus::Missing,
+        Some(bytes) => match (&entry.sha256, validate_file(bytes)) {
+            (Some(sha256), _) if *sha256 != sha256_hex(bytes) => FileStatus::ChecksumMismatch,
+            (_, Err(why)) => FileStatus::Invalid(why.to_string()),
+            (Some(_), Ok(_)) => FileStatus::Ok,
+            (None, Ok(_)) => FileStatus::NoChecksum,
+        },
+    };
+    VerifiedFile {
+        id: entry.id.clone(),
+        path: entry.path.clone(),
+        status,
+    }
+}
+
+/// Check every file of a backup (directory or archive) against the checksums of its manifest, and
+/// that each file is still a readable document
+pub fn verify_backup(path: &str) -> Result<VerifyReport> {
+    let backup_path = Path::new(path);
+    if backup_path.is_dir() {
+        ensure_not_encrypted(path)?;
+        let manifest_path = backup_path.join(MANIFEST_FILE);
+        let manifest: Manifest = serde_json::from_slice(&fs::read(&manifest_path).map_err(
+            |why| anyhow!("Cannot read '{}' ({why}), only backups made with this version or newer can be verified", manifest_path.display()),
+        )?)?;
+        let files = manifest
+            .documents
+            .iter()
+            .map(|entry| {
+                let bytes = fs::read(backup_path.join(&entry.path)).ok();
+                check(entry, bytes.as_deref())
+            })
+            .collect();
+        return Ok(VerifyReport {
+            created_at: manifest.created_at,
+            files,
+        });
+    }
+
+    if !backup_path.is_file() {
+        return Err(anyhow!("'{path}' does not exist"));
+    }
+    let manifest = read_manifest(path)?.ok_or(anyhow!(
+        "No manifest in '{path}', only archives made with this tool can be verified"
+    ))?;
+    let mut files = HashMap::new();
+    for_each_file(path, |name, bytes| {
+        if let Some(entry) = manifest.documents.iter().find(|entry| entry.path == name) {
+            files.insert(name.to_string(), check(entry, Some(bytes)));
+        }
+    })?;
+    Ok(VerifyReport {
+        created_at: manifest.created_at.clone(),
+        files: manifest
+            .documents
+            .iter()
+            .map(|entry| {
+                files
+                    .remove(&entry.path)
+                    .unwrap_or_else(|| check(entry, None))
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{pdf, TempDir};
+
+    fn entry(id: &str, sha256: Option<String>) -> ManifestEntry {
+        ManifestEntry {
+            id: id.to_string(),
+            path: format!("root/{id}.pdf"),
+            sha256,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn checks_each_file_against_its_manifest_entry() {
+        let valid = pdf(2);
+        let checksum = Some(sha256_hex(&valid));
+        let truncated = &valid[..valid.len() - 20];
+        let cases: [(ManifestEntry, Option<&[u8]>, FileStatus); 5] = [
+            (
+                entry("dune", checksum.clone()),
+                Some(&valid),
+                FileStatus::Ok,
+            ),
+            (entry("dune", checksum.clone()), None, FileStatus::Missing),
+            (
+                entry("dune", checksum),
+                Some(truncated),
+                FileStatus::ChecksumMismatch,
+            ),
+            (entry("dune", None), Some(&valid), FileStatus::NoChecksum),
+            (
+                entry("dune", Some(sha256_hex(b""))),
+                Some(b""),
+                FileStatus::Invalid("the file is empty".to_string()),
+            ),
+        ];
+        for (entry, bytes, status) in cases {
+            assert_eq!(check(&entry, bytes).status, status);
+        }
+    }
+
+    #[test]
+    fn verifies_a_directory_backup() {
+        let dir = TempDir::new("verify-directory");
+        let valid = pdf(1);
+        dir.write("root/dune.pdf", &valid);
+        dir.write("root/course.pdf", &valid);
+        let manifest = Manifest {
+            created_at: "2024-01-01T10:00:00+00:00".to_string(),
+            documents: vec![
+                entry("dune", Some(sha256_hex(&valid))),
+                entry("course", Some(sha256_hex(b"previous version"))),
+                entry("notes", None),
+            ],
+            ..Manifest::new()
+        };
+        dir.write(MANIFEST_FILE, &serde_json::to_vec(&manifest).unwrap());
+
+        let report = verify_backup(dir.path()).unwrap();
+        assert_eq!(report.created_at, manifest.created_at);
+        let failures = report
+            .failures()
+            .map(|file| (file.id.as_str(), file.status.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            failures,
+            [
+                ("course", FileStatus::ChecksumMismatch),
+                ("notes", FileStatus::Missing)
+            ]
+        );
+    }
+
+    #[test]
+    fn only_verifies_backups_with_a_manifest() {
+        let dir = TempDir::with_files("verify-no-manifest", &["root/dune.pdf"]);
+        let why = verify_backup(dir.path()).unwrap_err();
+        assert!(why.to_string().contains("Cannot read"), "{why}");
+        assert!(verify_backup(&dir.join("nothing")).is_err());
+    }
+}
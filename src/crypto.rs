@@ -0,0 +1,431 @@
+//! Client-side encryption of directory backups with [age](https://age-encryption.org): every
+//! exported file and the manifest are encrypted for X25519 public keys ("age1...") or with a
+//! passphrase, and the files can be stored by document ID so that their names do not leak either.
+//! The encrypted files are standard age files, `age --decrypt` can read them too.
+//!
+//! With a passphrase, the files are encrypted for a key of the backup, itself encrypted with the
+//! passphrase in [`KEY_FILE`]: the passphrase is derived (slow on purpose) once per run instead of
+//! once per file.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use age::{
+    secrecy::{ExposeSecret, SecretString},
+    x25519, Decryptor, Encryptor,
+};
+use anyhow::{anyhow, Result};
+
+use crate::{manifest::MANIFEST_FILE, storage::Storage, utils::join_path};
+
+/// Extension added to the encrypted files
+pub const ENCRYPTED_EXTENSION: &str = "age";
+/// Folder of a backup with obfuscated names where the documents are stored, by ID
+pub const DOCUMENTS_DIR: &str = "documents";
+/// Key of a backup encrypted with a passphrase (an age identity file, encrypted with the passphrase)
+pub const KEY_FILE: &str = "backup-key.age";
+
+/// Manifest of an encrypted backup
+pub fn encrypted_manifest_file() -> String {
+    format!("{MANIFEST_FILE}.{ENCRYPTED_EXTENSION}")
+}
+
+/// Who can read an encrypted backup: the owners of the private keys of its public keys
+#[derive(Clone)]
+pub struct Encryption {
+    recipients: Vec<x25519::Recipient>,
+}
+
+impl Encryption {
+    /// from public keys, e.g: "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+    pub fn with_recipients(keys: &[String]) -> Result<Self> {
+        let recipients = keys
+            .iter()
+            .map(|key| {
+                x25519::Recipient::from_str(key.trim())
+                    .map_err(|why| anyhow!("Invalid age public key '{key}': {why}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { recipients })
+    }
+
+    pub fn encrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let encryptor = Encryptor::with_recipients(
+            self.recipients
+                .iter()
+                .map(|recipient| Box::new(recipient.clone()) as _)
+                .collect(),
+        )
+        .ok_or(anyhow!("No public key to encrypt for"))?;
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(bytes)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+}
+
+/// What can read an encrypted backup: private keys ("AGE-SECRET-KEY-1...") or the passphrase
+#[derive(Clone)]
+pub enum Decryption {
+    Identities(Vec<x25519::Identity>),
+    /// only reads the key of the backup, see [`Decryption::for_backup`]
+    Passphrase(SecretString),
+}
+
+impl Decryption {
+    /// from age identity files (one private key per line, '#' for comments)
+    pub fn from_identity_files(paths: &[String]) -> Result<Self> {
+        let mut identities = vec![];
+        for path in paths {
+            let content = std::fs::read_to_string(path)
+                .map_err(|why| anyhow!("Cannot read the identity file '{path}': {why}"))?;
+            for line in content.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                identities.push(
+                    x25519::Identity::from_str(line)
+                        .map_err(|why| anyhow!("Invalid age private key in '{path}': {why}"))?,
+                );
+            }
+        }
+        if identities.is_empty() {
+            return Err(anyhow!("No private key in {}", paths.join(", ")));
+        }
+        Ok(Decryption::Identities(identities))
+    }
+
+    /// what reads the files of the backup in `storage`: the key of the backup for a passphrase
+    pub async fn for_backup(self, storage: &Storage) -> Result<Self> {
+        match self {
+            Decryption::Passphrase(passphrase) => {
+                let key = backup_key(storage, &passphrase, false).await?;
+                Ok(Decryption::Identities(vec![key]))
+            }
+            identities => Ok(identities),
+        }
+    }
+
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut reader = match (Decryptor::new_buffered(bytes)?, self) {
+            (Decryptor::Recipients(decryptor), Decryption::Identities(identities)) => decryptor
+                .decrypt(
+                    identities
+                        .iter()
+                        .map(|identity| identity as &dyn age::Identity),
+                )?,
+            (Decryptor::Passphrase(decryptor), Decryption::Passphrase(passphrase)) => {
+                decryptor.decrypt(passphrase, None)?
+            }
+            (Decryptor::Recipients(_), Decryption::Passphrase(_)) => {
+                return Err(anyhow!(
+                    "Encrypted for public keys, a private key is needed ('--identity')"
+                ))
+            }
+            (Decryptor::Passphrase(_), Decryption::Identities(_)) => {
+                return Err(anyhow!("Encrypted with a passphrase, not for a public key"))
+            }
+        };
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+}
+
+/// How the files of a directory backup are written
+#[derive(Clone, Default)]
+pub struct Protection {
+    pub encryption: Option<Encryption>,
+    /// to read the previous manifest of an encrypted backup, and keep the checksums of the
+    /// documents that are not downloaded again (the passphrase, or a private key)
+    pub decryption: Option<Decryption>,
+    /// store the documents as `documents/<id>.<extension>` instead of their path on the remarkable
+    pub obfuscate_names: bool,
+}
+
+impl Protection {
+    /// where the file of the document `id` exported at `path` (e.g: "root/Books/Dune.pdf") is
+    /// stored in the backup
+    pub fn stored_path(&self, id: &str, path: &str) -> String {
+        let path = match self.obfuscate_names {
+            true => {
+                let extension = path.rsplit_once('.').map_or("pdf", |(_, ext)| ext);
+                join_path(DOCUMENTS_DIR, &format!("{id}.{extension}"))
+            }
+            false => path.to_string(),
+        };
+        match self.encryption {
+            Some(_) => format!("{path}.{ENCRYPTED_EXTENSION}"),
+            None => path,
+        }
+    }
+
+    pub fn manifest_path(&self) -> String {
+        match self.encryption {
+            Some(_) => encrypted_manifest_file(),
+            None => MANIFEST_FILE.to_string(),
+        }
+    }
+
+    /// the content of a stored file, `None` if it is encrypted and cannot be decrypted
+    pub fn open(&self, bytes: Vec<u8>) -> Option<Vec<u8>> {
+        match (&self.encryption, &self.decryption) {
+            (None, _) => Some(bytes),
+            (Some(_), Some(decryption)) => decryption.decrypt(&bytes).ok(),
+            (Some(_), None) => None,
+        }
+    }
+
+    /// the content to store for `bytes`: encrypted if needed
+    pub fn seal(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match &self.encryption {
+            Some(encryption) => encryption.encrypt(bytes),
+            None => Ok(bytes.to_vec()),
+        }
+    }
+}
+
+/// The key of the backup in `storage` encrypted with `passphrase`, read from [`KEY_FILE`]. A new key
+/// is made when the backup has none yet, it is only saved if `save_new` is set
+pub async fn backup_key(
+    storage: &Storage,
+    passphrase: &SecretString,
+    save_new: bool,
+) -> Result<x25519::Identity> {
+    if let Some(encrypted) = storage.read(KEY_FILE).await? {
+        let decrypted = Decryption::Passphrase(passphrase.clone())
+            .decrypt(&encrypted)
+            .map_err(|why| {
+                anyhow!("Cannot read the key of the backup, wrong passphrase? ({why})")
+            })?;
+        let key = String::from_utf8_lossy(&decrypted)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|line| x25519::Identity::from_str(line).ok())
+            .ok_or(anyhow!("'{KEY_FILE}' holds no key"))?;
+        return Ok(key);
+    }
+
+    let key = x25519::Identity::generate();
+    if save_new {
+        let identity_file = format!(
+            "# key of a remarkable2-downloader backup, public key: {}\n{}\n",
+            key.to_public(),
+            key.to_string().expose_secret()
+        );
+        let encryptor = Encryptor::with_user_passphrase(passphrase.clone());
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(identity_file.as_bytes())?;
+        writer.finish()?;
+        storage.write(KEY_FILE, &encrypted, false).await?;
+    }
+    Ok(key)
+}
+
+impl Protection {
+    /// encryption with a passphrase, for the key of the backup in `storage` (see [`backup_key`])
+    pub async fn with_passphrase(
+        storage: &Storage,
+        passphrase: &SecretString,
+        save_new_key: bool,
+    ) -> Result<Self> {
+        let key = backup_key(storage, passphrase, save_new_key).await?;
+        Ok(Self {
+            encryption: Some(Encryption {
+                recipients: vec![key.to_public()],
+            }),
+            decryption: Some(Decryption::Identities(vec![key])),
+            obfuscate_names: false,
+        })
+    }
+}
+
+/// Fail if `backup_path` holds an encrypted backup, which has to be decrypted before being read
+pub fn ensure_not_encrypted(backup_path: &str) -> Result<()> {
+    match Path::new(backup_path)
+        .join(encrypted_manifest_file())
+        .exists()
+    {
+        true => Err(anyhow!(
+            "'{backup_path}' is an encrypted backup, run 'decrypt' first to get a plain copy of it"
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Fail if an encrypted backup is about to be written into `storage` holding a plain one: its
+/// files would stay readable next to the encrypted copies
+pub async fn ensure_no_plain_backup(storage: &Storage, protection: &Protection) -> Result<()> {
+    match protection.encryption.is_some() && storage.exists(MANIFEST_FILE).await? {
+        true => Err(anyhow!(
+            "'{}' holds a plain backup, its files would stay readable next to the encrypted ones: back up into another folder, or delete the plain backup first",
+            storage.display("")
+        )),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn passphrase(passphrase: &str) -> SecretString {
+        SecretString::new(passphrase.to_string())
+    }
+
+    fn for_new_key() -> (Encryption, Decryption) {
+        let key = x25519::Identity::generate();
+        (
+            Encryption::with_recipients(&[key.to_public().to_string()]).unwrap(),
+            Decryption::Identities(vec![key]),
+        )
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let (encryption, decryption) = for_new_key();
+        let encrypted = encryption.encrypt(b"%PDF-1.4 Dune").unwrap();
+        assert_ne!(encrypted, b"%PDF-1.4 Dune");
+        assert_eq!(decryption.decrypt(&encrypted).unwrap(), b"%PDF-1.4 Dune");
+
+        // only for its recipients
+        let (_, other) = for_new_key();
+        assert!(other.decrypt(&encrypted).is_err());
+        assert!(Encryption::with_recipients(&["age1nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn refuses_a_corrupt_ciphertext() {
+        let (encryption, decryption) = for_new_key();
+        let mut encrypted = encryption.encrypt(b"%PDF-1.4 Dune").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 0xff;
+        assert!(decryption.decrypt(&encrypted).is_err());
+        assert!(decryption.decrypt(b"not an age file").is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_key_of_a_passphrase_backup() {
+        let dir = TempDir::new("crypto-backup-key");
+        let storage = Storage::parse(dir.path()).unwrap();
+
+        // only saved when asked to
+        backup_key(&storage, &passphrase("secret"), false)
+            .await
+            .unwrap();
+        assert!(!storage.exists(KEY_FILE).await.unwrap());
+
+        let key = backup_key(&storage, &passphrase("secret"), true)
+            .await
+            .unwrap();
+        let stored = storage.read(KEY_FILE).await.unwrap().unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("AGE-SECRET-KEY"));
+        let again = backup_key(&storage, &passphrase("secret"), true)
+            .await
+            .unwrap();
+        assert_eq!(again.to_public().to_string(), key.to_public().to_string());
+
+        // the files sealed for the key are read with the passphrase
+        let protection = Protection::with_passphrase(&storage, &passphrase("secret"), false)
+            .await
+            .unwrap();
+        let sealed = protection.seal(b"%PDF-1.4 Dune").unwrap();
+        let decryption = Decryption::Passphrase(passphrase("secret"))
+            .for_backup(&storage)
+            .await
+            .unwrap();
+        assert_eq!(decryption.decrypt(&sealed).unwrap(), b"%PDF-1.4 Dune");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_wrong_passphrase() {
+        let dir = TempDir::new("crypto-wrong-passphrase");
+        let storage = Storage::parse(dir.path()).unwrap();
+        backup_key(&storage, &passphrase("secret"), true)
+            .await
+            .unwrap();
+
+        let Err(why) = backup_key(&storage, &passphrase("guess"), false).await else {
+            panic!("read the key with a wrong passphrase");
+        };
+        assert!(why.to_string().contains("wrong passphrase"), "{why}");
+    }
+
+    #[test]
+    fn maps_the_stored_paths() {
+        let (encryption, _) = for_new_key();
+        let cases = [
+            (None, false, "root/Books/Dune.pdf"),
+            (Some(encryption.clone()), false, "root/Books/Dune.pdf.age"),
+            (None, true, "documents/dune.pdf"),
+            (Some(encryption), true, "documents/dune.pdf.age"),
+        ];
+        for (encryption, obfuscate_names, stored) in cases {
+            let protection = Protection {
+                encryption,
+                decryption: None,
+                obfuscate_names,
+            };
+            assert_eq!(
+                protection.stored_path("dune", "root/Books/Dune.pdf"),
+                stored
+            );
+        }
+        let protection = Protection {
+            obfuscate_names: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            protection.stored_path("course", "root/Course.epub"),
+            "documents/course.epub"
+        );
+    }
+
+    #[test]
+    fn opens_only_what_it_can_decrypt() {
+        let (encryption, decryption) = for_new_key();
+        let sealed = Protection {
+            encryption: Some(encryption.clone()),
+            ..Default::default()
+        };
+        let bytes = sealed.seal(b"%PDF-1.4").unwrap();
+        assert_eq!(sealed.open(bytes.clone()), None);
+        let readable = Protection {
+            decryption: Some(decryption),
+            ..sealed
+        };
+        assert_eq!(readable.open(bytes).unwrap(), b"%PDF-1.4");
+        assert_eq!(
+            Protection::default().open(b"%PDF-1.4".to_vec()).unwrap(),
+            b"%PDF-1.4"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_encrypt_next_to_a_plain_backup() {
+        let dir = TempDir::with_files("crypto-plain-backup", &["root/Dune.pdf"]);
+        let storage = Storage::parse(dir.path()).unwrap();
+        let (encryption, _) = for_new_key();
+        let encrypted = Protection {
+            encryption: Some(encryption),
+            ..Default::default()
+        };
+        assert!(ensure_no_plain_backup(&storage, &encrypted).await.is_ok());
+
+        dir.write(MANIFEST_FILE, b"{}");
+        let why = ensure_no_plain_backup(&storage, &encrypted)
+            .await
+            .unwrap_err();
+        assert!(why.to_string().contains("plain backup"), "{why}");
+        assert!(ensure_no_plain_backup(&storage, &Protection::default())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+use serde_derive::Serialize;
+
+/// Everything the library has to say while it works, it never prints by itself:
+/// it's up to the caller (e.g. the CLI) to decide how to display these events.
+///
+/// Serialized as flat records tagged by `event`, e.g: `{"event":"download_started","id":"...","name":"...","size_estimate":null}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// general information about what is going on
+    Info {
+        message: String,
+    },
+    /// something went wrong but it does not stop the operation
+    Warning {
+        message: String,
+    },
+    /// smart mode will download the document because it changed since the last backup
+    SmartModeAdded {
+        id: String,
+        name: String,
+    },
+    /// smart mode won't download the document because it did not change since the last backup
+    SmartModeSkipped {
+        id: String,
+        name: String,
+    },
+    /// `total` documents are about to be transferred, `total_bytes` is an estimation of their size
+    TransferPlanned {
+        total: usize,
+        total_bytes: u64,
+    },
+    DownloadStarted {
+        id: String,
+        name: String,
+        size_estimate: Option<u64>,
+    },
+    /// `downloaded` bytes of the document have been received so far
+    DownloadProgress {
+        id: String,
+        downloaded: u64,
+    },
+    DownloadFinished {
+        id: String,
+        name: String,
+        size: usize,
+    },
+    DownloadFailed {
+        id: String,
+        name: String,
+        error: String,
+    },
+    /// the document was not downloaded, it looks identical (same size and pages) to the file
+    /// `same_as` already in the backup or downloaded in this run, see [`crate::cmd::dedup`]
+    DownloadReused {
+        id: String,
+        name: String,
+        same_as: String,
+    },
+    UploadStarted {
+        path: String,
+        folder: String,
+    },
+    UploadFinished {
+        path: String,
+        folder: String,
+        size: u64,
+    },
+    UploadFailed {
+        path: String,
+        error: String,
+    },
+    /// the `documents` of a content-addressed backup are stored as `files` distinct files, which
+    /// saves `saved_bytes` compared to a copy per document
+    Deduplicated {
+        documents: usize,
+        files: usize,
+        saved_bytes: u64,
+    },
+    /// udp mode skipped a file or a folder because of an error
+    Skipped {
+        reason: String,
+    },
+    /// the operation is over
+    Completed {
+        message: String,
+    },
+    /// the operation stopped early because it was cancelled, see [`crate::cancel`]
+    Interrupted {
+        message: String,
+    },
+}
+
+/// Receives the events emitted by the library
+pub trait Reporter {
+    fn report(&self, event: Event);
+}
+
+impl<F: Fn(Event)> Reporter for F {
+    fn report(&self, event: Event) {
+        self(event)
+    }
+}
+
+/// Reporter ignoring every event
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn report(&self, _: Event) {}
+}
@@ -0,0 +1,375 @@
+//! History of a backup folder kept in a git repository: after each run the folder is committed with
+//! a message listing the changes, and the commit is tagged with the date of the run. Each document
+//! gets a metadata sidecar so that `git diff`/`git log` show what changed besides the PDFs.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::Path,
+    process::{Command, Output},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+
+use crate::{
+    cmd::RemarkableFSHierarchy,
+    events::{Event, Reporter},
+    lock::LOCK_FILE,
+    manifest::{Manifest, MANIFEST_FILE, METADATA_DIR},
+    report::{ChangeReport, REPORTS_DIR},
+    utils::write_file,
+};
+
+/// What git must not track in a backup folder
+const IGNORED: [&str; 2] = [LOCK_FILE, "*.part"];
+
+/// Identity of the commits when git has none configured
+const DEFAULT_AUTHOR: [&str; 4] = [
+    "-c",
+    "user.name=remarkable2-downloader",
+    "-c",
+    "user.email=remarkable2-downloader@localhost",
+];
+
+fn run_git(repo: &str, args: &[&str]) -> Result<Output> {
+    Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .map_err(|why| anyhow!("Cannot run git ({why}), is it installed?"))
+}
+
+/// run a git command in `repo`, fails with its error output
+fn git(repo: &str, args: &[&str]) -> Result<String> {
+    let output = run_git(repo, args)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'git {}' failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// (Re)write `metadata/<id>.json` for every document of the backup (the same layout as in the
+/// archives), the sidecars of the documents that are no longer backed up are removed
+pub fn write_sidecars(fs_hierarchy: &RemarkableFSHierarchy, out_path: &str) -> Result<()> {
+    let manifest: Manifest =
+        serde_json::from_slice(&fs::read(Path::new(out_path).join(MANIFEST_FILE))?)?;
+    let metadata_path = Path::new(out_path).join(METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+
+    let mut sidecars = HashSet::new();
+    for entry in &manifest.documents {
+        let Some(doc) = fs_hierarchy.find_document(&entry.id) else {
+            continue;
+        };
+        let name = format!("{}.json", entry.id);
+        let path = metadata_path.join(&name);
+        let metadata = serde_json::to_vec_pretty(doc)?;
+        // only rewritten when it changed, to keep the modification times meaningful
+        if fs::read(&path).ok().as_ref() != Some(&metadata) {
+            write_file(&path, &metadata, true)?;
+        }
+        sidecars.insert(name);
+    }
+    for entry in fs::read_dir(&metadata_path)? {
+        let entry = entry?;
+        if !sidecars.contains(entry.file_name().to_string_lossy().as_ref()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// e.g: "Backup of 2024-01-31 18:02: 2 added, 1 updated, 1 removed", followed by the paths
+pub fn commit_message(report: &ChangeReport) -> String {
+    let date = DateTime::parse_from_rfc3339(&report.created_at)
+        .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|_| report.created_at.clone());
+    let mut counts = vec![];
+    for (count, what) in [
+        (report.new.len(), "added"),
+        (report.updated.len(), "updated"),
+        (report.moved.len(), "moved"),
+        (report.deleted.len(), "removed"),
+        (report.failures.len(), "failed"),
+    ] {
+        if count > 0 {
+            counts.push(format!("{count} {what}"));
+        }
+    }
+    let mut message = match counts.is_empty() {
+        true => format!("Backup of {date}"),
+        false => format!("Backup of {date}: {}", counts.join(", ")),
+    };
+
+    let mut section = |title: &str, lines: Vec<String>| {
+        if !lines.is_empty() {
+            message.push_str(&format!("\n\n{title}:\n  {}", lines.join("\n  ")));
+        }
+    };
+    section(
+        "Added",
+        report.new.iter().map(|entry| entry.path.clone()).collect(),
+    );
+    section(
+        "Updated",
+        report
+            .updated
+            .iter()
+            .map(|update| update.after.path.clone())
+            .collect(),
+    );
+    section(
+        "Moved",
+        report
+            .moved
+            .iter()
+            .map(|update| format!("{} -> {}", update.before.path, update.after.path))
+            .collect(),
+    );
+    section(
+        "Removed",
+        report
+            .deleted
+            .iter()
+            .map(|entry| entry.path.clone())
+            .collect(),
+    );
+    section(
+        "Failed",
+        report
+            .failures
+            .iter()
+            .map(|failure| format!("{}: {}", failure.name, failure.error))
+            .collect(),
+    );
+    message
+}
+
+/// make `out_path` a git repository if it is not in one yet, and keep the lock and temporary
+/// files out of it
+fn init_repository(out_path: &str, reporter: &dyn Reporter) -> Result<()> {
+    let in_repository = run_git(out_path, &["rev-parse", "--is-inside-work-tree"])?
+        .status
+        .success();
+    if !in_repository {
+        git(out_path, &["init", "--quiet"])?;
+        reporter.report(Event::Info {
+            message: format!("Created a git repository in '{out_path}'"),
+        });
+    }
+
+    let gitignore_path = Path::new(out_path).join(".gitignore");
+    let mut gitignore = fs::read_to_string(&gitignore_path).unwrap_or_default();
+    let missing = IGNORED
+        .iter()
+        .filter(|pattern| !gitignore.lines().any(|line| line.trim() == **pattern))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        if !gitignore.is_empty() && !gitignore.ends_with('\n') {
+            gitignore.push('\n');
+        }
+        for pattern in missing {
+            gitignore.push_str(&format!("{pattern}\n"));
+        }
+        write_file(&gitignore_path, gitignore.as_bytes(), true)?;
+    }
+    Ok(())
+}
+
+/// Commit the backup folder `out_path` (a git repository is created if needed) with a message
+/// summarising `report`, and tag the commit with the date of the run.
+///
+/// Returns the id of the commit, `None` if nothing changed since the last commit
+pub fn commit_backup(
+    fs_hierarchy: &RemarkableFSHierarchy,
+    out_path: &str,
+    report: &ChangeReport,
+    reporter: &dyn Reporter,
+) -> Result<Option<String>> {
+    init_repository(out_path, reporter)?;
+    write_sidecars(fs_hierarchy, out_path)?;
+
+    // only the backup folder, it may be a folder of a bigger repository
+    git(out_path, &["add", "--all", "--", "."])?;
+    // a report is saved by every run, it is not a change of the backup by itself
+    let exclude_reports = format!(":(exclude){REPORTS_DIR}");
+    let unchanged = run_git(
+        out_path,
+        &["diff", "--cached", "--quiet", "--", ".", &exclude_reports],
+    )?
+    .status
+    .success();
+    if unchanged {
+        reporter.report(Event::Info {
+            message: "Nothing changed since the last commit of the backup".to_string(),
+        });
+        return Ok(None);
+    }
+
+    let message = commit_message(report);
+    let mut args = vec![];
+    if git(out_path, &["config", "user.email"]).is_err() {
+        args.extend(DEFAULT_AUTHOR);
+    }
+    args.extend(["commit", "--quiet", "-m", message.as_str(), "--", "."]);
+    git(out_path, &args)?;
+
+    let date = DateTime::parse_from_rfc3339(&report.created_at)
+        .map(|date| date.with_timezone(&Local))
+        .unwrap_or_else(|_| Local::now());
+    let tag = format!("backup-{}", date.format("%Y%m%d-%H%M%S"));
+    // runs of the same second share their tag
+    if let Err(why) = git(out_path, &["tag", &tag]) {
+        reporter.report(Event::Warning {
+            message: format!("Failed to tag the backup: {why}"),
+        });
+    }
+    reporter.report(Event::Info {
+        message: format!(
+            "Committed the backup: {} (tag '{tag}')",
+            message.lines().next().unwrap_or_default()
+        ),
+    });
+    Ok(Some(git(out_path, &["rev-parse", "HEAD"])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        events::SilentReporter,
+        manifest::ManifestEntry,
+        report::{BackupFailure, DocumentUpdate},
+        scheme::{DocType, RmkDocument},
+        test_utils::TempDir,
+    };
+
+    fn entry(id: &str, path: &str) -> ManifestEntry {
+        ManifestEntry {
+            id: id.to_string(),
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn hierarchy(names: &[(&str, &str)]) -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(
+            names
+                .iter()
+                .map(|(id, name)| RmkDocument {
+                    id: id.to_string(),
+                    vissible_name: name.to_string(),
+                    doc_type: DocType::DocumentType,
+                    ..Default::default()
+                })
+                .collect(),
+        )
+    }
+
+    /// a backup folder holding the documents of `fs_hierarchy` and their manifest
+    fn write_backup(dir: &TempDir, fs_hierarchy: &RemarkableFSHierarchy) {
+        let manifest = Manifest::from_hierarchy(fs_hierarchy);
+        for entry in &manifest.documents {
+            dir.write(&entry.path, b"%PDF-1.4");
+        }
+        dir.write(MANIFEST_FILE, &serde_json::to_vec(&manifest).unwrap());
+    }
+
+    #[test]
+    fn lists_the_changes_in_the_commit_message() {
+        let report = ChangeReport {
+            created_at: "2024-01-31T18:02:00+01:00".to_string(),
+            new: vec![
+                entry("notes", "root/Notes.pdf"),
+                entry("dune", "root/Dune.pdf"),
+            ],
+            moved: vec![DocumentUpdate {
+                before: entry("course", "root/Course.pdf"),
+                after: entry("course", "root/Books/Course.pdf"),
+            }],
+            failures: vec![BackupFailure {
+                id: "broken".to_string(),
+                name: "Broken.pdf".to_string(),
+                error: "the file is empty".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            commit_message(&report),
+            "Backup of 2024-01-31 18:02: 2 added, 1 moved, 1 failed\n\
+             \n\
+             Added:\n  root/Notes.pdf\n  root/Dune.pdf\n\
+             \n\
+             Moved:\n  root/Course.pdf -> root/Books/Course.pdf\n\
+             \n\
+             Failed:\n  Broken.pdf: the file is empty"
+        );
+        let unchanged = ChangeReport {
+            created_at: "not a date".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(commit_message(&unchanged), "Backup of not a date");
+    }
+
+    #[test]
+    fn keeps_a_sidecar_per_backed_up_document() {
+        let dir = TempDir::new("history-sidecars");
+        let fs_hierarchy = hierarchy(&[("dune", "Dune"), ("notes", "Notes")]);
+        write_backup(&dir, &fs_hierarchy);
+        dir.write("metadata/deleted.json", b"{}");
+        write_sidecars(&fs_hierarchy, dir.path()).unwrap();
+
+        let mut sidecars = fs::read_dir(dir.join(METADATA_DIR))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        sidecars.sort();
+        assert_eq!(sidecars, ["dune.json", "notes.json"]);
+        let metadata: RmkDocument =
+            serde_json::from_slice(&fs::read(dir.join("metadata/dune.json")).unwrap()).unwrap();
+        assert_eq!(metadata.vissible_name, "Dune");
+    }
+
+    #[test]
+    fn commits_and_tags_each_backup_with_changes() {
+        let dir = TempDir::new("history-commit");
+        let fs_hierarchy = hierarchy(&[("dune", "Dune")]);
+        write_backup(&dir, &fs_hierarchy);
+        dir.write(LOCK_FILE, b"1234");
+        let report = ChangeReport {
+            created_at: "2024-01-31T12:00:00+00:00".to_string(),
+            new: vec![entry("dune", "root/Dune.pdf")],
+            ..Default::default()
+        };
+
+        let commit = commit_backup(&fs_hierarchy, dir.path(), &report, &SilentReporter).unwrap();
+        assert!(commit.is_some());
+        let files = git(dir.path(), &["ls-files"]).unwrap();
+        assert!(files.contains("root/Dune.pdf") && files.contains("metadata/dune.json"));
+        assert!(!files.contains(LOCK_FILE), "{files}");
+        assert_eq!(
+            git(dir.path(), &["log", "-1", "--format=%s"]).unwrap(),
+            "Backup of 2024-01-31 12:00: 1 added"
+        );
+        assert!(git(dir.path(), &["tag"])
+            .unwrap()
+            .starts_with("backup-2024013"));
+
+        // a new report alone is not a change of the backup
+        dir.write("reports/backup-20240131-120000.md", b"# Backup");
+        let unchanged = ChangeReport {
+            created_at: "2024-01-31T12:00:01+00:00".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            commit_backup(&fs_hierarchy, dir.path(), &unchanged, &SilentReporter).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Check that a downloaded file is what it claims to be: the remarkable sometimes answers with an
+//! HTML error page or cuts the transfer short.
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+const PDF_MAGIC: &[u8] = b"%PDF-";
+const PDF_TRAILER: &[u8] = b"%%EOF";
+/// EPUB and .rmdoc files are zip archives
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// end of central directory record, the last thing written in a zip
+const ZIP_END_MAGIC: &[u8] = b"PK\x05\x06";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Pdf,
+    /// EPUB or .rmdoc
+    Zip,
+}
+
+/// What could be checked on a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validation {
+    pub format: FileFormat,
+    /// only for PDFs
+    pub pages: Option<usize>,
+}
+
+/// e.g: "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Check the structure of a file: known magic bytes, complete trailer, and a readable page tree for PDFs
+pub fn validate_file(bytes: &[u8]) -> Result<Validation> {
+    // the trailer may be followed by a few bytes (new lines, padding)
+    let tail = &bytes[bytes.len().saturating_sub(1024)..];
+    if bytes.starts_with(PDF_MAGIC) {
+        if !contains(tail, PDF_TRAILER) {
+            return Err(anyhow!("the PDF is truncated (no '%%EOF' at the end)"));
+        }
+        let pdf = lopdf::Document::load_mem(bytes)
+            .map_err(|why| anyhow!("the PDF cannot be read: {why}"))?;
+        return Ok(Validation {
+            format: FileFormat::Pdf,
+            pages: Some(pdf.get_pages().len()),
+        });
+    }
+    if bytes.starts_with(ZIP_MAGIC) {
+        // the end record is at most 22 bytes + a 64KiB comment from the end
+        let tail = &bytes[bytes.len().saturating_sub(22 + u16::MAX as usize)..];
+        if !contains(tail, ZIP_END_MAGIC) {
+            return Err(anyhow!(
+                "the archive is truncated (no end of central directory)"
+            ));
+        }
+        return Ok(Validation {
+            format: FileFormat::Zip,
+            pages: None,
+        });
+    }
+
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(64)]).to_lowercase();
+    if start.trim_start().starts_with("<!doctype html") || start.trim_start().starts_with("<html") {
+        return Err(anyhow!("the remarkable answered with an HTML page"));
+    }
+    match bytes.is_empty() {
+        true => Err(anyhow!("the file is empty")),
+        false => Err(anyhow!("unknown file format (neither a PDF nor an EPUB)")),
+    }
+}
+
+/// Check a payload received from the remarkable, see [`validate_file`]
+pub fn validate_payload(bytes: &[u8], content_type: Option<&str>) -> Result<Validation> {
+    if content_type.is_some_and(|content_type| content_type.starts_with("text/html")) {
+        return Err(anyhow!("the remarkable answered with an HTML page"));
+    }
+    validate_file(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{pdf, zip};
+
+    #[test]
+    fn checks_the_structure_of_pdfs() {
+        let valid = pdf(3);
+        let truncated = &valid[..valid.len() - 20];
+        let no_page_tree = b"%PDF-1.4\nnot a document\n%%EOF\n";
+        let cases: [(&[u8], Option<&str>); 5] = [
+            (&valid, None),
+            (truncated, Some("truncated")),
+            (no_page_tree, Some("cannot be read")),
+            (b"%PDX-1.4 ...%%EOF", Some("unknown file format")),
+            (b"", Some("empty")),
+        ];
+        for (bytes, problem) in cases {
+            match (validate_file(bytes), problem) {
+                (Ok(validation), None) => assert_eq!(
+                    validation,
+                    Validation {
+                        format: FileFormat::Pdf,
+                        pages: Some(3)
+                    }
+                ),
+                (Err(why), Some(problem)) => assert!(why.to_string().contains(problem), "{why}"),
+                (result, problem) => panic!("{result:?} instead of {problem:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn checks_the_structure_of_zips() {
+        let valid = zip(&[("mimetype", b"application/epub+zip")]);
+        // the end record is searched in the last 64KiB
+        let mut commented = valid.clone();
+        commented.extend(vec![b' '; 60_000]);
+        let mut too_far = valid.clone();
+        too_far.extend(vec![b' '; 70_000]);
+        let cases: [(&[u8], Option<&str>); 5] = [
+            (&valid, None),
+            (&commented, None),
+            (&valid[..valid.len() - 22], Some("truncated")),
+            (&too_far, Some("truncated")),
+            (b"PK\x03\x03", Some("unknown file format")),
+        ];
+        for (bytes, problem) in cases {
+            match (validate_file(bytes), problem) {
+                (Ok(validation), None) => assert_eq!(
+                    validation,
+                    Validation {
+                        format: FileFormat::Zip,
+                        pages: None
+                    }
+                ),
+                (Err(why), Some(problem)) => assert!(why.to_string().contains(problem), "{why}"),
+                (result, problem) => panic!("{result:?} instead of {problem:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_html_pages() {
+        for bytes in [&b"<!DOCTYPE html><html>"[..], b"  <html><body>Error"] {
+            let why = validate_file(bytes).unwrap_err();
+            assert!(why.to_string().contains("HTML"), "{why}");
+        }
+        assert!(validate_payload(&pdf(1), Some("text/html; charset=utf-8")).is_err());
+        assert!(validate_payload(&pdf(1), Some("application/pdf")).is_ok());
+        assert!(validate_payload(&pdf(1), None).is_ok());
+    }
+
+    #[test]
+    fn hashes_in_hexadecimal() {
+        assert_eq!(
+            sha256_hex(b"test"),
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+    }
+}
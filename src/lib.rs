@@ -0,0 +1,48 @@
+//! Library behind the `remarkable2-downloader` CLI, it talks to the web interface of a remarkable2
+//! plugged in via USB (http://10.11.99.1).
+//!
+//! Nothing is printed: the operations return their results and emit [`events::Event`] to the
+//! [`events::Reporter`] they are given, so that they can be embedded in other tools.
+//!
+//! ```no_run
+//! use remarkable2_downloader::{
+//!     cmd::full_backup::{sync_full_backup, BackupOptions},
+//!     events::Event,
+//!     fetch_documents,
+//! };
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let fs_hierarchy = fetch_documents("", "root").await?;
+//! let reporter = |event: Event| eprintln!("{event:?}");
+//! let options = BackupOptions {
+//!     out_path: "./backup".to_string(),
+//!     udp_mode: true,
+//!     override_mode: true,
+//!     smart_mode: true,
+//!     protection: Default::default(),
+//!     dedup: false,
+//!     reuse_identical: false,
+//! };
+//! sync_full_backup(&fs_hierarchy, options, &reporter).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod cache;
+pub mod cancel;
+pub mod cmd;
+pub mod crypto;
+pub mod events;
+pub mod history;
+pub mod integrity;
+pub mod lock;
+pub mod manifest;
+pub mod probe;
+pub mod report;
+pub mod scheme;
+pub mod storage;
+#[cfg(test)]
+mod test_utils;
+pub mod utils;
+
+pub use cmd::{fetch_documents, FolderNode, RemarkableFSHierarchy};
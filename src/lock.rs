@@ -0,0 +1,424 @@
+//! Advisory lock on a directory, so that two runs (e.g. a cron job and a manual backup) do not
+//! write into the same backup at the same time.
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    cancel::{track_temp_file, untrack_temp_file},
+    events::{Event, Reporter},
+};
+
+/// Name of the lock file created at the root of the locked directory
+pub const LOCK_FILE: &str = ".remarkable2-downloader.lock";
+/// The process of a lock taken on another host cannot be checked, the lock is considered stale
+/// after this long
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// A lock file is written right after being created, one still unreadable after this long was left
+/// half-written by a run that was killed
+const UNREADABLE_GRACE: Duration = Duration::from_secs(5);
+/// How often an unreadable lock file is read again, while the other run writes it
+const UNREADABLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Content of a lock file: who holds the lock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub host: String,
+    /// e.g: "backup"
+    pub command: String,
+    pub started_at: String,
+}
+
+/// Error returned when the directory is locked by another run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Locked {
+    pub dir: PathBuf,
+    /// `None` if the other run is still writing its lock file
+    pub holder: Option<LockInfo>,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.holder {
+            Some(holder) => write!(
+                f,
+                "'{}' is used by another run ('{}' started at {} by process {} on '{}'), wait for it to finish or use '--wait'",
+                self.dir.display(),
+                holder.command,
+                holder.started_at,
+                holder.pid,
+                holder.host
+            ),
+            None => write!(
+                f,
+                "'{}' is used by another run that is starting, wait for it to finish or use '--wait'",
+                self.dir.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Locked {}
+
+/// How long to wait for a directory locked by another run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    Never,
+    For(Duration),
+    Forever,
+}
+
+/// Lock on a directory, released when dropped
+#[derive(Debug)]
+pub struct DirLock {
+    path: PathBuf,
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+        untrack_temp_file(&self.path);
+    }
+}
+
+/// name of this machine, "unknown" if it cannot be found
+fn host_name() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or("unknown".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// without a portable way to check, the process is assumed to be running
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// the process holding the lock is gone: it was on this host and is not running anymore, or the
+/// lock is too old
+fn is_stale(holder: &LockInfo) -> bool {
+    if holder.host == host_name() {
+        // left by an earlier process that had the same PID (e.g. PID 1 in containers)
+        return holder.pid == std::process::id() || !is_running(holder.pid);
+    }
+    DateTime::parse_from_rfc3339(&holder.started_at)
+        .map(|started_at| {
+            (Local::now().fixed_offset() - started_at)
+                .to_std()
+                .is_ok_and(|age| age > STALE_AFTER)
+        })
+        .unwrap_or(true)
+}
+
+/// who holds the lock file at `path`, `None` if it cannot be read (yet)
+fn read_holder(path: &Path) -> Option<LockInfo> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+/// the unreadable lock file at `path` was left half-written: it has not been written for longer
+/// than [`UNREADABLE_GRACE`]
+fn is_abandoned(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > UNREADABLE_GRACE))
+}
+
+/// Remove the stale lock file at `path`, held by `holder` (`None`: abandoned while being written).
+/// It is moved aside first and checked again there: a lock taken in the meantime by another run
+/// that removed the stale one too is put back instead of being deleted
+fn remove_stale(path: &Path, holder: Option<&LockInfo>) -> io::Result<()> {
+    let aside = path.with_extension(format!("stale-{}", std::process::id()));
+    match fs::rename(path, &aside) {
+        Ok(()) => {}
+        // another run removed it first
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(why) => return Err(why),
+    }
+    let still_stale = match holder {
+        Some(holder) => read_holder(&aside).as_ref() == Some(holder),
+        None => read_holder(&aside).is_none() && is_abandoned(&aside),
+    };
+    if !still_stale {
+        // fails if yet another run took the lock since, it has it then
+        let _ = fs::hard_link(&aside, path);
+    }
+    match fs::remove_file(&aside) {
+        Err(why) if why.kind() != io::ErrorKind::NotFound => Err(why),
+        _ => Ok(()),
+    }
+}
+
+/// create the lock file, `None` if it already exists
+fn try_lock(path: &Path, info: &LockInfo) -> io::Result<Option<DirLock>> {
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(why) if why.kind() == io::ErrorKind::AlreadyExists => return Ok(None),
+        Err(why) => return Err(why),
+    };
+    // removed right away if the process has to exit without unwinding
+    track_temp_file(path);
+    let lock = DirLock {
+        path: path.to_path_buf(),
+    };
+    file.write_all(&serde_json::to_vec_pretty(info)?)?;
+    Ok(Some(lock))
+}
+
+/// Lock `dir` for `command`, stale locks (see [`LOCK_FILE`]) are taken over. If another run holds
+/// the lock, fails with [`Locked`] or waits for it according to `wait`
+pub async fn lock_dir(
+    dir: &str,
+    command: &str,
+    wait: Wait,
+    reporter: &dyn Reporter,
+) -> Result<DirLock> {
+    let path = Path::new(dir).join(LOCK_FILE);
+    let info = LockInfo {
+        pid: std::process::id(),
+        host: host_name(),
+        command: command.to_string(),
+        started_at: Local::now().to_rfc3339(),
+    };
+
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        if let Some(lock) = try_lock(&path, &info)? {
+            return Ok(lock);
+        }
+        // unreadable: the other run is writing it, or was killed while doing so
+        let holder = read_holder(&path);
+        if holder.is_none() && !path.exists() {
+            // released in the meantime
+            continue;
+        }
+        let stale = match &holder {
+            Some(holder) => is_stale(holder),
+            None => is_abandoned(&path),
+        };
+        if stale {
+            reporter.report(Event::Warning {
+                message: match &holder {
+                    Some(holder) => format!(
+                        "Removing the stale lock of '{}' ('{}' started at {} by process {} on '{}')",
+                        dir, holder.command, holder.started_at, holder.pid, holder.host
+                    ),
+                    None => format!(
+                        "Removing the lock of '{dir}', it was left unreadable by a run that did not finish writing it"
+                    ),
+                },
+            });
+            remove_stale(&path, holder.as_ref())?;
+            continue;
+        }
+
+        let gave_up = match wait {
+            Wait::Never => true,
+            Wait::For(timeout) => started.elapsed() >= timeout,
+            Wait::Forever => false,
+        };
+        if gave_up {
+            return Err(Locked {
+                dir: PathBuf::from(dir),
+                holder,
+            }
+            .into());
+        }
+        let Some(holder) = holder else {
+            tokio::time::sleep(UNREADABLE_POLL_INTERVAL).await;
+            continue;
+        };
+        if !waiting {
+            waiting = true;
+            reporter.report(Event::Info {
+                message: format!(
+                    "Waiting for the other run ('{}' by process {} on '{}') to release '{dir}'...",
+                    holder.command, holder.pid, holder.host
+                ),
+            });
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use crate::{events::SilentReporter, test_utils::TempDir};
+
+    use super::*;
+
+    fn lock_file(dir: &TempDir) -> PathBuf {
+        PathBuf::from(dir.join(LOCK_FILE))
+    }
+
+    fn holder(pid: u32) -> LockInfo {
+        LockInfo {
+            pid,
+            host: host_name(),
+            command: "backup".to_string(),
+            started_at: Local::now().to_rfc3339(),
+        }
+    }
+
+    fn write_lock(path: &Path, bytes: &[u8], age: Duration) {
+        let file = fs::File::create(path).unwrap();
+        (&file).write_all(bytes).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_lock_is_released_when_dropped() {
+        let dir = TempDir::new("lock-release");
+        let lock = lock_dir(dir.path(), "backup", Wait::Never, &SilentReporter)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_holder(&lock_file(&dir)).map(|holder| holder.pid),
+            Some(std::process::id())
+        );
+        drop(lock);
+        assert!(!lock_file(&dir).exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn a_running_holder_keeps_the_lock() {
+        let dir = TempDir::new("lock-running");
+        // a process of this host that is running and is not this one
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let holder = holder(child.id());
+        let raw = serde_json::to_vec(&holder).unwrap();
+        write_lock(&lock_file(&dir), &raw, Duration::ZERO);
+
+        let error = lock_dir(dir.path(), "backup", Wait::Never, &SilentReporter)
+            .await
+            .unwrap_err();
+        let locked = error.downcast::<Locked>().unwrap();
+        assert_eq!(locked.holder, Some(holder));
+
+        let started = Instant::now();
+        let error = lock_dir(
+            dir.path(),
+            "backup",
+            Wait::For(Duration::from_millis(200)),
+            &SilentReporter,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<Locked>());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(read_holder(&lock_file(&dir)), Some(locked.holder.unwrap()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn the_lock_of_a_dead_process_is_taken_over() {
+        let dir = TempDir::new("lock-dead");
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        let raw = serde_json::to_vec(&holder(pid)).unwrap();
+        write_lock(&lock_file(&dir), &raw, Duration::ZERO);
+
+        let _lock = lock_dir(dir.path(), "backup", Wait::Never, &SilentReporter)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_holder(&lock_file(&dir)).map(|holder| holder.pid),
+            Some(std::process::id())
+        );
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_lock_being_written_is_waited_for() {
+        let dir = TempDir::new("lock-unreadable");
+        write_lock(&lock_file(&dir), b"", Duration::ZERO);
+
+        let error = lock_dir(dir.path(), "backup", Wait::Never, &SilentReporter)
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast::<Locked>().unwrap().holder, None);
+
+        let started = Instant::now();
+        let error = lock_dir(
+            dir.path(),
+            "backup",
+            Wait::For(Duration::from_millis(300)),
+            &SilentReporter,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<Locked>());
+        assert!(started.elapsed() < UNREADABLE_GRACE);
+    }
+
+    #[tokio::test]
+    async fn an_abandoned_unreadable_lock_is_taken_over() {
+        let dir = TempDir::new("lock-abandoned");
+        write_lock(
+            &lock_file(&dir),
+            b"{\"pid\":",
+            UNREADABLE_GRACE + Duration::from_secs(1),
+        );
+
+        let _lock = lock_dir(dir.path(), "backup", Wait::Never, &SilentReporter)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_holder(&lock_file(&dir)).map(|holder| holder.pid),
+            Some(std::process::id())
+        );
+    }
+
+    #[test]
+    fn a_lock_taken_in_the_meantime_is_not_removed() {
+        let dir = TempDir::new("lock-replaced");
+        let stale = holder(1);
+        let fresh = holder(2);
+        write_lock(
+            &lock_file(&dir),
+            &serde_json::to_vec(&fresh).unwrap(),
+            Duration::ZERO,
+        );
+
+        remove_stale(&lock_file(&dir), Some(&stale)).unwrap();
+        assert_eq!(read_holder(&lock_file(&dir)), Some(fresh.clone()));
+
+        remove_stale(&lock_file(&dir), Some(&fresh)).unwrap();
+        assert!(!lock_file(&dir).exists());
+        // already removed by another run
+        remove_stale(&lock_file(&dir), Some(&fresh)).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
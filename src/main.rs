@@ -1,17 +1,49 @@
-mod cmd;
-mod scheme;
-mod utils;
+mod cli;
 
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use colored::Colorize;
-
-use crate::{
+use remarkable2_downloader::{
+    cancel::Cancelled,
     cmd::{
-        fetch_documents,
-        full_backup::{sync_full_backup, BackupOptions},
+        archive::{archive_backup, extract_archive, list_archive, plan_archive, ArchiveFormat},
+        decrypt::decrypt_backup,
+        download::{download_documents, dry_run_download, DownloadOptions},
+        full_backup::{
+            dry_run_backup, plan_backup, sync_full_backup, write_tag_view, BackupOptions,
+        },
+        merge::{export_merged, MergeOptions, MergeOrder},
+        normalize_path,
+        restore::{execute_restore, plan_restore},
+        set_concurrency,
+        status::backup_status,
+        upload::{plan_upload, upload_planned},
+        verify::verify_backup,
     },
-    utils::{check_output_path, is_client_up, print_err},
+    crypto::{Decryption, Encryption, Protection, KEY_FILE},
+    history::commit_backup,
+    lock::{lock_dir, Wait},
+    probe::{check_connection, probe_capabilities, Connection},
+    report::{last_backup_manifest, ReportFormat},
+    scheme::DocType,
+    storage::{is_remote, Storage},
+    utils::check_output_path,
+    RemarkableFSHierarchy,
+};
+use std::{cell::RefCell, time::Duration};
+
+use crate::cli::{
+    browse::{format_date, ls, print_tags, tree, SortBy},
+    cached_documents, confirm,
+    doctor::{adapt_to_device, print_diagnostics},
+    handle_interrupts, load_documents,
+    output::{say, set_output, OutputFormat},
+    print_archive_listing, print_backup_status, print_document_info, print_documents, print_err,
+    print_planned_actions, print_restore_plan, print_transfer_plan, print_verify_report,
+    read_passphrase, record_failures, report_changes,
+    shell::Shell,
+    tui::run_tui,
+    INTERRUPTED_EXIT_CODE,
 };
 
 /// Simple CLI script to download files from your remarkable
@@ -30,6 +62,7 @@ struct RmkdwldCli {
     /// When copying the downloaded files to your local file system if a file already at the location where a downloaded file should be copied, the CLI will:
     /// - If set to false (default): halt the execution and return an error without touching at the already present file
     /// - If set to true: override everything inside it
+    ///
     /// Please note that if 'smart_mode' is set to true (which is the default), 'override_mode' will automatically be set to
     /// true to ensure that it can override file that have been modified in remarkable but not yet in local file system
     #[arg(long, default_value_t = false, verbatim_doc_comment)]
@@ -40,45 +73,50 @@ struct RmkdwldCli {
     #[arg(long, default_value_t = true, verbatim_doc_comment)]
     smart_mode: bool,
 
-    /* Remarkable does not support concurrent request, thus I removed these features
-        /// If set to true, download request will be made asynchronously to your remarkable.
-        ///
-        /// While it's faster (because download are made in parralel and not one by one), I do not recommand to enable it because
-        ///
-        /// the remarkable hardware does not support very well all these concurrent request as a consequence I cannot assure you
-        ///
-        /// file integrity (they may mix up, being under the wrong filename or in the wrong place or just not being there, and a lot of other not so funny things)
-        ///
-        /// But fear not this does not corrupt your remarkable in anyway, because mine is still working 👍
-        // #[arg(long, default_value_t = false)]
-        // async_mode: bool,
-
-        /// If async mode set to true, it specifies the number of concurrent requests sent to your remarkable2, as said in the description of the async mode:
-        ///
-        /// the larger this number is the faster but higher is the chance of loosing your file integrity in the process
-        ///
-        /// After some testing remarkable hardware seem to support up to 3 concurrent requests, which is the default, but please try and experiment with 100 concurrents requests 😉
-        ///
-        /// Also setting this to 1 is equivalent to disabling async_mode since you do one request by one
-        // #[arg(long, default_value_t = 2)]
-        // concurrent_request: usize,
-    */
+    /// Number of requests sent to your remarkable2 at the same time (1 by default, one by one).
+    /// Downloads and folder listings are faster with 2 or 3, but older firmwares may mix up the files of concurrent requests:
+    /// each file is checked against the document it was asked for, and at the first error everything falls back to one
+    /// request at a time (the failed files are downloaded again)
+    #[arg(long, global = true, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=16), verbatim_doc_comment)]
+    concurrent_requests: u16,
+
+    /// Format of what is printed on stdout: colored text for humans or JSON records for scripts,
+    /// with 'json'/'ndjson' the human messages are sent to stderr
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text, verbatim_doc_comment)]
+    output: OutputFormat,
+
+    /// Crawl every folder of the remarkable instead of reusing the cached documents,
+    /// by default 'ls', 'tree', 'search', 'info' and 'tags' only list the folders modified since
+    /// the last run again (the transfers always crawl every folder)
+    #[arg(long, global = true, default_value_t = false, verbatim_doc_comment)]
+    refresh: bool,
+
+    /// If another run is using the same backup/output folder, wait for it to finish instead of failing,
+    /// at most SECONDS if given
+    #[arg(long, global = true, value_name = "SECONDS", num_args = 0..=1, verbatim_doc_comment)]
+    wait: Option<Option<u64>>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    /// [NOT IMPLEMENTED YET] Upload folders/files to remarkable2
+    /// Upload folders/files to remarkable2, documents already present are skipped
     Upload {
-        /// Where is the location/path of the local file/folder you want to upload
-        #[arg(long)]
+        /// Where is the location/path of the local file/folder you want to upload (pdf and epub files only).
+        /// The remarkable web interface cannot create folders: the files of a local folder are uploaded into the
+        /// folder of the same name, which must already exist on the remarkable
+        #[arg(long, required = true, verbatim_doc_comment)]
         datapath: Vec<String>,
         /// path in the remarkable2, e.g: "/my_books/fantasy", "/" is for root
         #[arg(long)]
         uploadpath: String,
+        /// Only print what would be uploaded or skipped
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
-    /// [NOT IMPLEMENTED YET] Download files from remarkable2
+    /// Download files and folders from remarkable2
     Download {
         /// Paths of the files in the remarkable to download (one of the 2 options must be filled)
         #[arg(short, long)]
@@ -86,29 +124,224 @@ enum Commands {
         /// IDs of the files to download (one of the 2 options must be filled)
         #[arg(long)]
         ids: Option<Vec<String>>,
-        /// Folder location to save the downloaded files
-        #[arg(short, long)]
+        /// Folder location to save the downloaded files, or the URL of a bucket ("s3://bucket/prefix") or
+        /// of a WebDAV folder ("webdav://host/path", "webdavs://" for https)
+        #[arg(short, long, verbatim_doc_comment)]
         output_path: String,
         /// if the output path does not exist yet, allow this cli to create it for you
         #[arg(short, long, default_value_t = true)]
         allow_creation: bool,
+        /// Only print what would be downloaded, overwritten or skipped, and the folders that would be created
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Merge every document of a folder of the remarkable2 (subfolders included) into a single PDF, with title pages
+    /// listing the contents and a bookmark per folder and per document
+    #[command(verbatim_doc_comment)]
+    ExportMerged {
+        /// Path of the folder in the remarkable2, e.g: "/Courses/Algebra", "/" is for root
+        folder: String,
+        /// PDF file to write, "<folder name>.pdf" in the current folder by default
+        #[arg(short, long)]
+        output_path: Option<String>,
+        /// Order of the documents of each folder, the subfolders come after them
+        #[arg(long, value_enum, default_value_t = MergeOrder::Device)]
+        order: MergeOrder,
+        /// Title of the title page and of the PDF, the name of the folder by default
+        #[arg(long)]
+        title: Option<String>,
     },
     /// Download all the files and folder from remarkable2 (Full backup if smart_mode set to false)
     Backup {
-        /// Folder location to save the downloaded files
-        #[arg(short, long)]
+        /// Folder location to save the downloaded files, or the URL of a bucket ("s3://bucket/prefix") or
+        /// of a WebDAV folder ("webdav://host/path", "webdavs://" for https)
+        #[arg(short, long, verbatim_doc_comment)]
         output_path: String,
         /// if the output path does not exist yet, allow this cli to create it for you
         #[arg(short, long, default_value_t = true)]
         allow_creation: bool,
+        /// Instead of copying the files as is, write everything (files, metadata and a manifest) into a single archive
+        /// created inside the output path. Smart mode does not apply: an archive is always a full backup
+        #[arg(long, value_enum, verbatim_doc_comment)]
+        archive: Option<ArchiveFormat>,
+        /// Only back up the documents with this tag (on the document or on one of its pages), can be repeated
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Also group the backed up documents by tag in a 'by-tag' folder of links to the files. With --tag, only the
+        /// folders of these tags are rebuilt
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "archive",
+            verbatim_doc_comment
+        )]
+        by_tag: bool,
+        /// Only print what would be downloaded: number of documents and size per top-level folder, and the free space
+        #[arg(long, default_value_t = false)]
+        plan: bool,
+        /// Only print what would be done file by file: download, overwrite, skip, create or delete a folder
+        #[arg(long, default_value_t = false, conflicts_with_all = ["archive", "plan"])]
+        dry_run: bool,
+        /// Format of the change report saved in the 'reports' folder of the output path after each backup:
+        /// new, updated, moved and deleted documents since the previous backup, and the failures
+        #[arg(long, value_enum, default_value_t = ReportFormat::Markdown, verbatim_doc_comment)]
+        report_format: ReportFormat,
+        /// Also print the change report at the end of the backup
+        #[arg(long, default_value_t = false)]
+        print_report: bool,
+        /// Commit the output path in a git repository after the backup (created if needed), with a message listing
+        /// the changes and a tag with the date of the run. A metadata file per document is kept in 'metadata' for the diffs
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with = "archive",
+            verbatim_doc_comment
+        )]
+        git: bool,
+        /// Encrypt every file and the manifest for this age public key ("age1..."), can be repeated. The backup is read
+        /// back with 'decrypt' (or age) and the private key. The change report is encrypted too, it needs '--identity'
+        #[arg(
+            long = "recipient",
+            value_name = "PUBLIC_KEY",
+            group = "encryption",
+            conflicts_with_all = ["archive", "by_tag", "git"],
+            verbatim_doc_comment
+        )]
+        recipients: Vec<String>,
+        /// Encrypt every file and the manifest with a passphrase, read from $REMARKABLE2_PASSPHRASE or asked for.
+        /// The files are encrypted for a key of the backup, kept in 'backup-key.age' encrypted with the passphrase
+        #[arg(
+            long,
+            default_value_t = false,
+            group = "encryption",
+            conflicts_with_all = ["archive", "by_tag", "git"],
+            verbatim_doc_comment
+        )]
+        passphrase: bool,
+        /// Private key of one of the recipients (age identity file), to keep the checksums of the documents that are
+        /// not downloaded again (smart mode) and to compare the manifests for the change report
+        #[arg(
+            long,
+            value_name = "FILE",
+            requires = "recipients",
+            verbatim_doc_comment
+        )]
+        identity: Vec<String>,
+        /// Store the encrypted documents as 'documents/<id>.pdf.age' so that their names and folders do not show either
+        #[arg(long, default_value_t = false, requires = "encryption")]
+        obfuscate_names: bool,
+        /// Store each exported file once in 'objects', by checksum, however many documents share it. In a local folder
+        /// each document is a hard link to its file, and the manifest of every run is kept in 'snapshots'
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = ["archive", "encryption"],
+            verbatim_doc_comment
+        )]
+        dedup: bool,
+        /// With --dedup, do not download the documents with the same size and number of pages as a stored file (or as
+        /// another document of the run): they are assumed identical, which different annotations could prove wrong
+        #[arg(
+            long,
+            default_value_t = false,
+            requires = "dedup",
+            verbatim_doc_comment
+        )]
+        reuse_identical: bool,
+    },
+    /// Upload the documents of a backup folder back to the remarkable2, documents already present are skipped
+    Restore {
+        /// Folder location of the backup (as created by the 'backup' command)
+        #[arg(short, long)]
+        backup_path: String,
+        /// Only print what would be uploaded
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Do not ask for confirmation before uploading
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+        /// The remarkable web interface cannot create folders: restore refuses to run when folders of the
+        /// backup do not exist on the remarkable. If set, their documents are uploaded into the nearest
+        /// existing parent folder instead
+        #[arg(long, default_value_t = false, verbatim_doc_comment)]
+        into_nearest_folder: bool,
+    },
+    /// Compare a backup folder with the remarkable: documents new, modified, moved or deleted on the remarkable since
+    /// the backup, and files of the backup that are not on the remarkable. Nothing is downloaded
+    #[command(verbatim_doc_comment)]
+    Status {
+        /// Folder location of the backup (as created by the 'backup' command)
+        #[arg(short, long)]
+        output_path: String,
+    },
+    /// Check the connection to the remarkable and which features its web interface supports
+    #[command(alias = "probe")]
+    Doctor,
+    /// Check that the files of a backup (folder or archive) are intact, against the checksums recorded when
+    /// it was made (does not need the remarkable to be plugged in)
+    #[command(verbatim_doc_comment)]
+    Verify {
+        /// Folder or archive (.tar.zst or .zip) of the backup
+        backup_path: String,
+    },
+    /// Decrypt a backup made with '--recipient' or '--passphrase' into a plain backup folder, that can be verified and
+    /// restored (does not need the remarkable to be plugged in)
+    #[command(verbatim_doc_comment)]
+    Decrypt {
+        /// Folder of the encrypted backup, or the URL of its storage ("s3://...", "webdav://...")
+        #[arg(short, long)]
+        backup_path: String,
+        /// Folder location to save the decrypted backup
+        #[arg(short, long)]
+        output_path: String,
+        /// age identity file with the private key, for a backup encrypted for public keys (the passphrase is asked
+        /// for otherwise), can be repeated
+        #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+        identity: Vec<String>,
+    },
+    /// Read back a backup archive (does not need the remarkable to be plugged in)
+    Archive {
+        #[command(subcommand)]
+        action: ArchiveAction,
+    },
+    /// List the content of a folder of the remarkable2
+    Ls {
+        /// path in the remarkable2, e.g: "/my_books/fantasy", "/" is for root
+        #[arg(default_value = "/")]
+        path: String,
+        /// Long format: type, pages, size, modification date, tags and ID
+        #[arg(short, long, default_value_t = false)]
+        long: bool,
+        #[arg(short, long, value_enum, default_value_t = SortBy::Name)]
+        sort: SortBy,
     },
-    /// [NOT IMPLEMENTED YET] Search files by name
+    /// Show the folder hierarchy of the remarkable2, with the number of documents and size of each folder
+    Tree {
+        /// path in the remarkable2, e.g: "/my_books/fantasy", "/" is for root
+        #[arg(default_value = "/")]
+        path: String,
+        /// Do not show what is deeper than this depth (1 is only the content of the folder)
+        #[arg(short, long)]
+        depth: Option<usize>,
+        #[arg(short, long, value_enum, default_value_t = SortBy::Name)]
+        sort: SortBy,
+    },
+    /// Interactive shell to browse the remarkable2 and transfer files, the documents are fetched only once
+    Shell,
+    /// Full-screen browser to navigate the documents, see their details and download or upload files
+    Tui,
+    /// Search files and folders by name
     Search {
         /// Name of the file to search
         #[arg(short, long)]
         name: String,
     },
-    /// [NOT IMPLEMENTED YET] Get information on a specific file
+    /// List the tags used on the remarkable with their number of documents and pages
+    Tags {
+        /// List the documents with this tag instead
+        tag: Option<String>,
+    },
+    /// Get information on a specific file or folder
     Info {
         /// Path of the file in the remarkable (one of the 2 options must be filled)
         #[arg(short, long)]
@@ -119,74 +352,648 @@ enum Commands {
     },
 }
 
+#[derive(Debug, Subcommand)]
+enum ArchiveAction {
+    /// List the documents stored in a backup archive
+    List {
+        /// Path of the archive (.tar.zst or .zip)
+        archive_path: String,
+    },
+    /// Extract a backup archive
+    Extract {
+        /// Path of the archive (.tar.zst or .zip)
+        archive_path: String,
+        /// Folder location where to extract the archive
+        #[arg(short, long)]
+        output_path: String,
+    },
+}
+
+impl RmkdwldCli {
+    fn lock_wait(&self) -> Wait {
+        match self.wait {
+            None => Wait::Never,
+            Some(None) => Wait::Forever,
+            Some(Some(seconds)) => Wait::For(Duration::from_secs(seconds)),
+        }
+    }
+}
+
+/// The options that need the output path to be a local folder
+fn check_remote_output(command: &Commands) -> Result<()> {
+    let (output_path, local_options) = match command {
+        Commands::Download {
+            output_path,
+            dry_run,
+            ..
+        } => (output_path, vec![(*dry_run, "--dry-run")]),
+        Commands::Backup {
+            output_path,
+            archive,
+            by_tag,
+            plan,
+            dry_run,
+            git,
+            ..
+        } => (
+            output_path,
+            vec![
+                (archive.is_some(), "--archive"),
+                (*by_tag, "--by-tag"),
+                (*plan, "--plan"),
+                (*dry_run, "--dry-run"),
+                (*git, "--git"),
+            ],
+        ),
+        _ => return Ok(()),
+    };
+    if !is_remote(output_path) {
+        return Ok(());
+    }
+    match local_options.iter().find(|(set, _)| *set) {
+        Some((_, option)) => Err(anyhow!(
+            "'{option}' only works with a local output path, not with the URL of a storage"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Encryption and obfuscation of the directory backup in `output_path`, from the options of the
+/// command. The key of a backup encrypted with a passphrase is only created when `save_new_key`
+async fn backup_protection(
+    output_path: &str,
+    (recipients, passphrase, identities): (&[String], bool, &[String]),
+    obfuscate_names: bool,
+    save_new_key: bool,
+) -> Result<Protection> {
+    let protection = match (recipients.is_empty(), passphrase) {
+        (false, _) => Protection {
+            encryption: Some(Encryption::with_recipients(recipients)?),
+            decryption: match identities.is_empty() {
+                true => None,
+                false => Some(Decryption::from_identity_files(identities)?),
+            },
+            ..Default::default()
+        },
+        (true, true) => {
+            let storage = Storage::parse(output_path)?;
+            // a new passphrase is typed twice
+            let new = !storage.exists(KEY_FILE).await?;
+            let passphrase = read_passphrase(new)?;
+            Protection::with_passphrase(&storage, &passphrase, save_new_key).await?
+        }
+        (true, false) => Protection::default(),
+    };
+    Ok(Protection {
+        obfuscate_names,
+        ..protection
+    })
+}
+
+/// keep only the documents with one of `tags`, or everything if no tag is given
+fn filter_by_tags(fs_hierarchy: RemarkableFSHierarchy, tags: &[String]) -> RemarkableFSHierarchy {
+    match tags.is_empty() {
+        true => fs_hierarchy,
+        false => {
+            say(format!("Only backing up the documents tagged: {}", tags.join(", ")).bright_blue());
+            fs_hierarchy.with_tags(tags)
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    match run().await {
+        Err(why) if why.is::<Cancelled>() => std::process::exit(INTERRUPTED_EXIT_CODE),
+        result => result,
+    }
+}
+
+async fn run() -> Result<()> {
     let mut cli_args = RmkdwldCli::parse();
+    set_output(cli_args.output);
+    set_concurrency(cli_args.concurrent_requests as usize);
+
+    // commands that do not need the remarkable
+    if let Commands::Archive { action } = &cli_args.command {
+        match action {
+            ArchiveAction::List { archive_path } => {
+                print_archive_listing(&list_archive(archive_path)?)?
+            }
+            ArchiveAction::Extract {
+                archive_path,
+                output_path,
+            } => {
+                extract_archive(archive_path, output_path)?;
+                say(
+                    format!("Finished extracting '{archive_path}', go see: '{output_path}'")
+                        .green(),
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if let Commands::Verify { backup_path } = &cli_args.command {
+        return print_verify_report(&verify_backup(backup_path)?);
+    }
+
+    if let Commands::Decrypt {
+        backup_path,
+        output_path,
+        identity,
+    } = &cli_args.command
+    {
+        let decryption = match identity.is_empty() {
+            true => Decryption::Passphrase(read_passphrase(false)?),
+            false => Decryption::from_identity_files(identity)?,
+        };
+        check_output_path(output_path, true)?;
+        let reporter = cli::reporter();
+        let _lock = lock_dir(
+            output_path,
+            "decrypt",
+            cli_args.lock_wait(),
+            reporter.as_ref(),
+        )
+        .await?;
+        decrypt_backup(
+            backup_path,
+            output_path,
+            &decryption,
+            cli_args.override_mode,
+            reporter.as_ref(),
+        )
+        .await?;
+        return Ok(());
+    }
+
+    if let Commands::Doctor = cli_args.command {
+        let connection = check_connection(&reqwest::Client::new()).await;
+        let capabilities = match connection {
+            Connection::Connected => Some(probe_capabilities(&reqwest::Client::new()).await),
+            _ => None,
+        };
+        return print_diagnostics(connection, capabilities.as_ref());
+    }
+
+    check_remote_output(&cli_args.command)?;
+
     if cli_args.smart_mode && !cli_args.override_mode {
         cli_args.override_mode = true;
-        println!(
-            "{}",
-            "Setting 'override_mode' to true since 'smart_mode' is set to true".yellow()
-        );
+        say("Setting 'override_mode' to true since 'smart_mode' is set to true".yellow());
     }
 
-    println!("{}", "Connecting to remarkable via USB...".bright_blue());
-    if !is_client_up().await {
-        print_err("[FATAL]: Web Usb port is not enable, or your remarkable2 is not plugged in");
-        return Err(anyhow!("CLI exited with errors."));
-    }
-    println!("{}", "Connected to remarkable".green());
-    println!(
-        "{}",
-        "Do not unplug your remarkable during transfers!".bright_blue()
+    // these commands only read the documents, the cache is enough when the remarkable is not plugged in
+    let read_only = matches!(
+        cli_args.command,
+        Commands::Ls { .. }
+            | Commands::Tree { .. }
+            | Commands::Search { .. }
+            | Commands::Info { .. }
+            | Commands::Tags { .. }
+            | Commands::Status { .. }
+            | Commands::Backup { plan: true, .. }
+            | Commands::Backup { dry_run: true, .. }
+            | Commands::Download { dry_run: true, .. }
+            | Commands::Upload { dry_run: true, .. }
+            | Commands::Restore { dry_run: true, .. }
     );
 
-    // fetch the all documents for latter use (may be overkill, but simpler)
-    let fs_hierarchy = match fetch_documents("", "root").await {
-        Ok(hierarchy) => hierarchy,
-        Err(_) => {
-            print_err("[FATAL]: Failed to fetch documents structure from your remarkable");
-            return Err(anyhow!("CLI exited with errors."));
+    say("Connecting to remarkable via USB...".bright_blue());
+    let connection = check_connection(&reqwest::Client::new()).await;
+    let fs_hierarchy = if connection == Connection::Connected {
+        say("Connected to remarkable".green());
+        say("Do not unplug your remarkable during transfers!".bright_blue());
+
+        // probe the web interface only when transferring files, it costs a few requests
+        let needs = match &cli_args.command {
+            _ if read_only => None,
+            Commands::Download { .. } | Commands::ExportMerged { .. } | Commands::Backup { .. } => {
+                Some((true, false))
+            }
+            Commands::Upload { .. } | Commands::Restore { .. } => Some((false, true)),
+            Commands::Shell | Commands::Tui => Some((false, false)),
+            _ => None,
+        };
+        if let Some((downloads, uploads)) = needs {
+            adapt_to_device(downloads, uploads).await?;
+        }
+
+        // the incremental refresh of the cache can miss changes inside a folder, it is only used
+        // to browse the documents
+        let browsing = matches!(
+            cli_args.command,
+            Commands::Ls { .. }
+                | Commands::Tree { .. }
+                | Commands::Search { .. }
+                | Commands::Info { .. }
+                | Commands::Tags { .. }
+        );
+        match load_documents(cli_args.refresh || !browsing).await {
+            Ok(hierarchy) => hierarchy,
+            Err(_) => {
+                print_err("[FATAL]: Failed to fetch documents structure from your remarkable");
+                return Err(anyhow!("CLI exited with errors."));
+            }
+        }
+    } else {
+        match cached_documents().filter(|_| read_only) {
+            Some((hierarchy, updated_at)) => {
+                say(format!(
+                    "Remarkable not reachable, using the documents cached on {}",
+                    format_date(&updated_at)
+                )
+                .yellow());
+                hierarchy
+            }
+            None => {
+                print_err(match connection {
+                    Connection::WebInterfaceDisabled => "[FATAL]: Your remarkable2 is plugged in but its web interface is disabled, turn on 'USB web interface' in Settings > Storage",
+                    _ => "[FATAL]: Your remarkable2 is not plugged in (run 'doctor' for details)",
+                });
+                return Err(anyhow!("CLI exited with errors."));
+            }
         }
     };
 
+    // restore installs it after its confirmation prompt
+    if matches!(
+        cli_args.command,
+        Commands::Download { .. }
+            | Commands::ExportMerged { .. }
+            | Commands::Backup { .. }
+            | Commands::Upload { .. }
+    ) && !read_only
+    {
+        handle_interrupts();
+    }
+    // the commands only reading the documents print records, not events
+    let reporter = match read_only {
+        true => cli::record_reporter(),
+        false => cli::reporter(),
+    };
+    let wait = cli_args.lock_wait();
     match cli_args.command {
-        Commands::Upload { .. } => {
-            return Err(anyhow!(
-                "This feature isn't implemented yet, only 'backup' command works at the moment."
-            ));
+        Commands::Upload {
+            datapath,
+            uploadpath,
+            dry_run,
+        } => {
+            let actions = plan_upload(&fs_hierarchy, &datapath, &uploadpath)?;
+            if dry_run {
+                return print_planned_actions(&actions);
+            }
+            upload_planned(
+                &reqwest::Client::new(),
+                actions,
+                cli_args.udp_mode,
+                reporter.as_ref(),
+            )
+            .await?
+        }
+        Commands::Download {
+            paths,
+            ids,
+            output_path,
+            allow_creation,
+            dry_run,
+        } => {
+            let mut ids = ids.unwrap_or_default();
+            for path in paths.unwrap_or_default() {
+                let doc = fs_hierarchy
+                    .find_by_path(&path)
+                    .ok_or(anyhow!("No such file or folder: '{path}'"))?;
+                ids.push(doc.id.clone());
+            }
+            if ids.is_empty() {
+                return Err(anyhow!("Either '--paths' or '--ids' must be filled"));
+            }
+            if dry_run {
+                let actions =
+                    dry_run_download(&fs_hierarchy, &ids, &output_path, cli_args.override_mode)?;
+                return print_planned_actions(&actions);
+            }
+
+            // remote storages have no lock, nor a folder to create beforehand
+            let _lock = match is_remote(&output_path) {
+                true => None,
+                false => {
+                    check_output_path(&output_path, allow_creation)?;
+                    Some(lock_dir(&output_path, "download", wait, reporter.as_ref()).await?)
+                }
+            };
+            download_documents(
+                &reqwest::Client::new(),
+                &fs_hierarchy,
+                &ids,
+                DownloadOptions {
+                    out_path: output_path,
+                    udp_mode: cli_args.udp_mode,
+                    override_mode: cli_args.override_mode,
+                },
+                reporter.as_ref(),
+            )
+            .await?
+        }
+        Commands::ExportMerged {
+            folder,
+            output_path,
+            order,
+            title,
+        } => {
+            let (folder_id, name) = match normalize_path(&folder).as_str() {
+                "/" => (String::new(), "My files".to_string()),
+                path => {
+                    let doc = fs_hierarchy
+                        .find_by_path(path)
+                        .filter(|doc| doc.doc_type == DocType::CollectionType)
+                        .ok_or(anyhow!("No such folder: '{folder}'"))?;
+                    (doc.id.clone(), doc.vissible_name.clone())
+                }
+            };
+            let output_path = output_path.unwrap_or(format!("{name}.pdf"));
+            export_merged(
+                &reqwest::Client::new(),
+                &fs_hierarchy,
+                &folder_id,
+                MergeOptions {
+                    out_path: output_path,
+                    order,
+                    title,
+                    udp_mode: cli_args.udp_mode,
+                    override_mode: cli_args.override_mode,
+                },
+                reporter.as_ref(),
+            )
+            .await?
         }
-        Commands::Download { .. } => {
-            // check_output_path(&output_path, allow_creation)?;
-            return Err(anyhow!(
-                "This feature isn't implemented yet, only 'backup' command works at the moment."
-            ));
+        Commands::Backup {
+            output_path,
+            archive,
+            tags,
+            plan: true,
+            recipients,
+            passphrase,
+            identity,
+            obfuscate_names,
+            dedup,
+            reuse_identical,
+            ..
+        } => {
+            let fs_hierarchy = filter_by_tags(fs_hierarchy, &tags);
+            let plan = match archive {
+                Some(_) => plan_archive(&fs_hierarchy, &output_path),
+                None => {
+                    plan_backup(
+                        &fs_hierarchy,
+                        &output_path,
+                        &backup_protection(
+                            &output_path,
+                            (&recipients, passphrase, &identity),
+                            obfuscate_names,
+                            false,
+                        )
+                        .await?,
+                        cli_args.smart_mode,
+                        (dedup, reuse_identical),
+                        reporter.as_ref(),
+                    )
+                    .await?
+                }
+            };
+            print_transfer_plan(&plan)?;
+        }
+        Commands::Backup {
+            output_path,
+            tags,
+            by_tag,
+            dry_run: true,
+            recipients,
+            passphrase,
+            identity,
+            obfuscate_names,
+            dedup,
+            reuse_identical,
+            ..
+        } => {
+            let fs_hierarchy = filter_by_tags(fs_hierarchy, &tags);
+            let protection = backup_protection(
+                &output_path,
+                (&recipients, passphrase, &identity),
+                obfuscate_names,
+                false,
+            )
+            .await?;
+            let options = BackupOptions {
+                out_path: output_path,
+                udp_mode: cli_args.udp_mode,
+                override_mode: cli_args.override_mode,
+                smart_mode: cli_args.smart_mode,
+                protection,
+                dedup,
+                reuse_identical,
+            };
+            print_planned_actions(&dry_run_backup(&fs_hierarchy, &options, by_tag, &tags).await?)?;
         }
         Commands::Backup {
             output_path,
             allow_creation,
+            archive: Some(format),
+            tags,
+            report_format,
+            print_report,
+            ..
         } => {
             check_output_path(&output_path, allow_creation)?;
-            sync_full_backup(
+            let _lock = lock_dir(&output_path, "backup", wait, reporter.as_ref()).await?;
+            let previous = last_backup_manifest(&output_path, true, &Protection::default()).await;
+            let failures = RefCell::new(vec![]);
+            let result = archive_backup(
+                &filter_by_tags(fs_hierarchy, &tags),
+                &output_path,
+                format,
+                cli_args.udp_mode,
+                &record_failures(reporter.as_ref(), &failures),
+            )
+            .await;
+            report_changes(
+                previous,
+                &output_path,
+                true,
+                &Protection::default(),
+                failures.into_inner(),
+                result.is_ok(),
+                (report_format, print_report),
+            )
+            .await?;
+            result?;
+        }
+        Commands::Backup {
+            output_path,
+            allow_creation,
+            archive: None,
+            tags,
+            by_tag,
+            report_format,
+            print_report,
+            git,
+            recipients,
+            passphrase,
+            identity,
+            obfuscate_names,
+            dedup,
+            reuse_identical,
+            ..
+        } => {
+            let protection = backup_protection(
+                &output_path,
+                (&recipients, passphrase, &identity),
+                obfuscate_names,
+                true,
+            )
+            .await?;
+            // the manifests of an encrypted backup can only be compared with a key to read them
+            let reported = protection.encryption.is_none() || protection.decryption.is_some();
+            let report_protection = protection.clone();
+            let _lock = match is_remote(&output_path) {
+                true => None,
+                false => {
+                    check_output_path(&output_path, allow_creation)?;
+                    Some(lock_dir(&output_path, "backup", wait, reporter.as_ref()).await?)
+                }
+            };
+            let fs_hierarchy = filter_by_tags(fs_hierarchy, &tags);
+            let previous = last_backup_manifest(&output_path, false, &protection).await;
+            let failures = RefCell::new(vec![]);
+            let result = sync_full_backup(
                 &fs_hierarchy,
                 BackupOptions {
-                    out_path: output_path,
+                    out_path: output_path.clone(),
                     udp_mode: cli_args.udp_mode,
                     override_mode: cli_args.override_mode,
                     smart_mode: cli_args.smart_mode,
+                    protection,
+                    dedup,
+                    reuse_identical,
                 },
+                &record_failures(reporter.as_ref(), &failures),
+            )
+            .await;
+            if result.is_ok() && by_tag {
+                write_tag_view(&fs_hierarchy, &output_path, &tags, reporter.as_ref())?;
+            }
+            if !reported {
+                say("No change report: the manifests of the backup are encrypted, use '--identity' to read them".yellow());
+                return result;
+            }
+            let report = report_changes(
+                previous,
+                &output_path,
+                false,
+                &report_protection,
+                failures.into_inner(),
+                result.is_ok(),
+                (report_format, print_report),
+            )
+            .await?;
+            result?;
+            if let (true, Some(report)) = (git, report) {
+                commit_backup(&fs_hierarchy, &output_path, &report, reporter.as_ref())?;
+            }
+        }
+        Commands::Shell => {
+            Shell::new(
+                fs_hierarchy,
+                cli_args.udp_mode,
+                cli_args.override_mode,
+                wait,
             )
+            .run()
             .await?
         }
-        Commands::Search { .. } => {
-            return Err(anyhow!(
-                "This feature isn't implemented yet, only 'backup' command works at the moment."
-            ));
+        Commands::Tui => {
+            run_tui(
+                fs_hierarchy,
+                cli_args.udp_mode,
+                cli_args.override_mode,
+                wait,
+            )
+            .await?
+        }
+        Commands::Ls { path, long, sort } => ls(&fs_hierarchy, &path, long, sort)?,
+        Commands::Tree { path, depth, sort } => tree(&fs_hierarchy, &path, depth, sort)?,
+        Commands::Search { name } => print_documents(&fs_hierarchy.search(&name), &fs_hierarchy)?,
+        Commands::Tags { tag: None } => print_tags(&fs_hierarchy)?,
+        Commands::Tags { tag: Some(tag) } => {
+            let docs = fs_hierarchy
+                .all_docs
+                .iter()
+                .filter(|doc| doc.has_tag(&tag))
+                .collect::<Vec<_>>();
+            print_documents(&docs, &fs_hierarchy)?
+        }
+        Commands::Info { path, id } => {
+            let doc = match (path, id) {
+                (_, Some(id)) => fs_hierarchy
+                    .find_document(&id)
+                    .ok_or(anyhow!("No file with the ID '{id}'"))?,
+                (Some(path), None) => fs_hierarchy
+                    .find_by_path(&path)
+                    .ok_or(anyhow!("No file at '{path}'"))?,
+                (None, None) => return Err(anyhow!("Either '--path' or '--id' must be filled")),
+            };
+            print_document_info(doc, &fs_hierarchy)?
+        }
+        Commands::Status { output_path } => {
+            print_backup_status(&backup_status(&fs_hierarchy, &output_path)?)?
+        }
+        Commands::Restore {
+            backup_path,
+            dry_run,
+            yes,
+            into_nearest_folder,
+        } => {
+            // a dry run does not write anything, it does not need the backup for itself
+            let _lock = match dry_run {
+                true => None,
+                false => Some(lock_dir(&backup_path, "restore", wait, reporter.as_ref()).await?),
+            };
+            let plan = plan_restore(
+                &fs_hierarchy,
+                &backup_path,
+                into_nearest_folder,
+                reporter.as_ref(),
+            )?;
+            print_restore_plan(&plan)?;
+            if dry_run {
+                if let Err(why) = plan.ensure_complete() {
+                    say(why.to_string().yellow());
+                }
+                return Ok(());
+            }
+            plan.ensure_complete()?;
+            if plan.uploads() == 0 {
+                say("Nothing to restore".green());
+                return Ok(());
+            }
+            if !yes && cli_args.output != OutputFormat::Text {
+                return Err(anyhow!(
+                    "Cannot ask for confirmation with a machine-readable output, use '--yes'"
+                ));
+            }
+            if !yes && !confirm("Proceed with the restore?")? {
+                say("Restore aborted".yellow());
+                return Ok(());
+            }
+            handle_interrupts();
+            execute_restore(plan, cli_args.udp_mode, reporter.as_ref()).await?
         }
-        Commands::Info { .. } => {
-            return Err(anyhow!(
-                "This feature isn't implemented yet, only 'backup' command works at the moment."
-            ));
+        Commands::Archive { .. }
+        | Commands::Doctor
+        | Commands::Verify { .. }
+        | Commands::Decrypt { .. } => {
+            unreachable!("handled before connecting to the remarkable")
         }
     };
 
@@ -0,0 +1,197 @@
+use chrono::Local;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    cmd::RemarkableFSHierarchy,
+    scheme::{DocType, RmkDocument},
+    utils::{ensure_file_extension, join_path},
+};
+
+/// Name of the manifest file at the root of a backup (directory or archive)
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Folder (inside an archive) where the raw metadata of each document is stored
+pub const METADATA_DIR: &str = "metadata";
+
+/// Description of the content of a backup, so that it can be read back without the remarkable
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created_at: String,
+    pub folders: Vec<ManifestFolder>,
+    pub documents: Vec<ManifestEntry>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestFolder {
+    pub id: String,
+    /// Path of the folder relative to the backup root, e.g: "root/Books/Fantasy"
+    pub path: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub id: String,
+    /// Path of the exported file relative to the backup root, e.g: "root/Books/Dune.pdf"
+    pub path: String,
+    pub name: String,
+    pub parent: String,
+    pub modified_client: String,
+    pub page_count: Option<i64>,
+    pub size_in_bytes: Option<String>,
+    /// checksum of the exported file, to verify the backup later (missing in older manifests)
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// where the file is stored when it is not `path`: encrypted or obfuscated backups, e.g:
+    /// "documents/<id>.pdf.age" (the checksum is the one of the decrypted file), or
+    /// content-addressed ones, e.g: "objects/<sha256>.pdf"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_path: Option<String>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            version: 1,
+            created_at: Local::now().to_rfc3339(),
+            ..Default::default()
+        }
+    }
+
+    /// Build the manifest of every document of the remarkable
+    pub fn from_hierarchy(fs_hierarchy: &RemarkableFSHierarchy) -> Self {
+        let mut manifest = Self::new();
+        manifest.folders = fs_hierarchy
+            .folder_hierarchy
+            .folder_paths("")
+            .into_iter()
+            .map(|(id, path)| ManifestFolder { id, path })
+            .collect();
+        manifest.documents = fs_hierarchy
+            .folder_hierarchy
+            .files_path("")
+            .into_iter()
+            .filter_map(|(id, folder)| {
+                fs_hierarchy
+                    .all_docs
+                    .iter()
+                    .find(|doc| doc.id == id && doc.doc_type == DocType::DocumentType)
+                    .map(|doc| ManifestEntry::new(doc, &folder))
+            })
+            .collect();
+        manifest
+    }
+}
+
+impl ManifestEntry {
+    pub fn new(doc: &RmkDocument, folder: &str) -> Self {
+        let name = ensure_file_extension(&doc.vissible_name);
+        Self {
+            id: doc.id.clone(),
+            path: join_path(folder, &name),
+            name,
+            parent: doc.parent.clone(),
+            modified_client: doc.modified_client.clone(),
+            page_count: doc.page_count,
+            size_in_bytes: doc.size_in_bytes.clone(),
+            sha256: None,
+            stored_path: None,
+        }
+    }
+
+    pub fn size_estimate(&self) -> Option<u64> {
+        self.size_in_bytes.as_ref()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hierarchy() -> RemarkableFSHierarchy {
+        RemarkableFSHierarchy::from_documents(vec![
+            RmkDocument {
+                id: "books".to_string(),
+                vissible_name: "Books".to_string(),
+                doc_type: DocType::CollectionType,
+                ..Default::default()
+            },
+            RmkDocument {
+                id: "dune".to_string(),
+                vissible_name: "Dune".to_string(),
+                parent: "books".to_string(),
+                doc_type: DocType::DocumentType,
+                modified_client: "2024-01-31T18:02:00.000Z".to_string(),
+                page_count: Some(3),
+                size_in_bytes: Some("481".to_string()),
+                ..Default::default()
+            },
+            RmkDocument {
+                id: "notes".to_string(),
+                vissible_name: "Notes.pdf".to_string(),
+                doc_type: DocType::DocumentType,
+                ..Default::default()
+            },
+        ])
+    }
+
+    #[test]
+    fn from_hierarchy_lists_folders_and_documents_by_path() {
+        let manifest = Manifest::from_hierarchy(&hierarchy());
+        let mut folders = manifest
+            .folders
+            .iter()
+            .map(|folder| (folder.id.as_str(), folder.path.as_str()))
+            .collect::<Vec<_>>();
+        folders.sort();
+        assert_eq!(folders, [("", "root"), ("books", "root/Books")]);
+
+        let mut documents = manifest.documents.clone();
+        documents.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(documents.len(), 2);
+        let dune = &documents[0];
+        assert_eq!(dune.path, "root/Books/Dune.pdf");
+        assert_eq!(dune.name, "Dune.pdf");
+        assert_eq!(dune.parent, "books");
+        assert_eq!(dune.page_count, Some(3));
+        assert_eq!(dune.size_estimate(), Some(481));
+        assert_eq!(documents[1].path, "root/Notes.pdf");
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let mut manifest = Manifest::from_hierarchy(&hierarchy());
+        manifest.documents[0].sha256 = Some("0123abcd".to_string());
+        manifest.documents[0].stored_path = Some("objects/0123abcd.pdf".to_string());
+        let raw = serde_json::to_string_pretty(&manifest).unwrap();
+        let read: Manifest = serde_json::from_str(&raw).unwrap();
+        assert_eq!(read, manifest);
+
+        // no `stored_path` when the file is at its path
+        let raw = serde_json::to_value(&manifest.documents[1]).unwrap();
+        assert!(raw.get("stored_path").is_none());
+        assert!(raw.get("sha256").unwrap().is_null());
+    }
+
+    #[test]
+    fn reads_manifests_without_checksums() {
+        let raw = r#"{
+            "version": 1,
+            "created_at": "2024-01-31T18:02:00+01:00",
+            "folders": [{"id": "", "path": "root"}],
+            "documents": [{
+                "id": "dune",
+                "path": "root/Dune.pdf",
+                "name": "Dune.pdf",
+                "parent": "",
+                "modified_client": "2024-01-31T18:02:00.000Z",
+                "page_count": null,
+                "size_in_bytes": "481"
+            }]
+        }"#;
+        let manifest: Manifest = serde_json::from_str(raw).unwrap();
+        let dune = &manifest.documents[0];
+        assert_eq!(dune.sha256, None);
+        assert_eq!(dune.stored_path, None);
+        assert_eq!(dune.size_estimate(), Some(481));
+    }
+}
@@ -0,0 +1,386 @@
+//! Find out how the remarkable is connected and what its web interface can do, the endpoints
+//! changed between firmware versions.
+
+use std::time::Duration;
+
+use reqwest::{Method, StatusCode};
+use serde_derive::Serialize;
+use tokio::net::TcpStream;
+
+use crate::{
+    cmd::list_folder,
+    events::SilentReporter,
+    scheme::{DocType, RmkDocument},
+};
+
+const DEVICE_URL: &str = "http://10.11.99.1";
+/// folders listed at most while looking for a document to test the downloads with
+const MAX_LISTINGS: usize = 10;
+/// an export endpoint that does not answer in this time is assumed to be there, busy exporting
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Connection {
+    /// the web interface answers
+    Connected,
+    /// the tablet is plugged in (its USB network answers) but the web interface is turned off
+    WebInterfaceDisabled,
+    /// nothing answers at 10.11.99.1
+    Unreachable,
+}
+
+/// Route used to export a document as PDF
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PdfRoute {
+    /// `/download/{id}/placeholder`
+    #[default]
+    Placeholder,
+    /// `/download/{id}/pdf`
+    Pdf,
+}
+
+impl PdfRoute {
+    pub fn url(&self, id: &str) -> String {
+        match self {
+            PdfRoute::Placeholder => format!("{DEVICE_URL}/download/{id}/placeholder"),
+            PdfRoute::Pdf => format!("{DEVICE_URL}/download/{id}/pdf"),
+        }
+    }
+}
+
+/// What the web interface of the remarkable supports
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub documents: bool,
+    /// `None` if the documents cannot be exported as PDF
+    pub pdf_export: Option<PdfRoute>,
+    pub rmdoc_export: bool,
+    pub upload: bool,
+    pub thumbnails: bool,
+    /// document used to test the downloads, `None` if the remarkable has no document
+    pub sample_document: Option<String>,
+    /// endpoints that did not answer in time, they are assumed to be there
+    pub unconfirmed: Vec<String>,
+}
+
+impl Capabilities {
+    /// best guess of the generation of the web interface from its endpoints
+    pub fn generation(&self) -> &'static str {
+        match (self.documents, self.pdf_export, self.rmdoc_export) {
+            (false, _, _) => "unknown (the documents cannot be listed)",
+            (true, _, true) => "firmware 3.10 or newer (.rmdoc export)",
+            (true, Some(PdfRoute::Placeholder), false) => "firmware 2.x to 3.9",
+            (true, Some(PdfRoute::Pdf), false) => "older firmware (/download/{id}/pdf)",
+            (true, None, false) if self.sample_document.is_none() => {
+                "unknown (no document to test the exports with)"
+            }
+            (true, None, false) => "unknown (no known export endpoint)",
+        }
+    }
+}
+
+/// Tell "web interface disabled" from "not plugged in": when plugged in, the tablet answers on its
+/// USB network (ssh) even with the web interface turned off
+pub async fn check_connection(client: &reqwest::Client) -> Connection {
+    let resp = client
+        .get(DEVICE_URL)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await;
+    match resp {
+        Ok(_) => Connection::Connected,
+        Err(why) if why.is_connect() && !why.is_timeout() => {
+            let ssh =
+                tokio::time::timeout(Duration::from_secs(2), TcpStream::connect("10.11.99.1:22"))
+                    .await;
+            match ssh {
+                Ok(Ok(_)) => Connection::WebInterfaceDisabled,
+                _ => Connection::Unreachable,
+            }
+        }
+        Err(_) => Connection::Unreachable,
+    }
+}
+
+/// What probing an endpoint of the web interface told
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endpoint {
+    Found,
+    Missing,
+    /// it did not answer in time, e.g: the remarkable is slow to export a document
+    Unknown,
+}
+
+/// whether `url` answers with something else than an error or an HTML page. Asked with HEAD first,
+/// so that the remarkable does not have to send the document, the body is never read
+async fn probe_endpoint(client: &reqwest::Client, url: &str) -> Endpoint {
+    for method in [Method::HEAD, Method::GET] {
+        let resp = match client
+            .request(method.clone(), url)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(why) if why.is_timeout() => return Endpoint::Unknown,
+            Err(_) => return Endpoint::Missing,
+        };
+        // older web interfaces only know GET
+        if method == Method::HEAD
+            && matches!(
+                resp.status(),
+                StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+            )
+        {
+            continue;
+        }
+        let is_html = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("text/html"));
+        return match resp.status().is_success() && !is_html {
+            true => Endpoint::Found,
+            false => Endpoint::Missing,
+        };
+    }
+    Endpoint::Missing
+}
+
+/// whether the endpoint `url` is there, it is assumed to be when it does not answer in time (and
+/// added to `unconfirmed`)
+async fn endpoint_exists(
+    client: &reqwest::Client,
+    url: &str,
+    unconfirmed: &mut Vec<String>,
+) -> bool {
+    match probe_endpoint(client, url).await {
+        Endpoint::Found => true,
+        Endpoint::Missing => false,
+        Endpoint::Unknown => {
+            unconfirmed.push(url.to_string());
+            true
+        }
+    }
+}
+
+/// smallest document found on the remarkable, looking through a few folders at most: the export
+/// endpoints are tested with it
+async fn find_sample_document(client: &reqwest::Client) -> Option<RmkDocument> {
+    let mut folders = vec![String::new()];
+    let mut sample: Option<RmkDocument> = None;
+    for _ in 0..MAX_LISTINGS {
+        if folders.is_empty() {
+            break;
+        }
+        let docs = list_folder(client, &folders.remove(0), &SilentReporter)
+            .await
+            .ok()?;
+        let size = |doc: &RmkDocument| (doc.size_estimate().unwrap_or(u64::MAX), doc.page_count);
+        for doc in docs
+            .iter()
+            .filter(|doc| doc.doc_type == DocType::DocumentType)
+        {
+            if sample
+                .as_ref()
+                .is_none_or(|sample| size(doc) < size(sample))
+            {
+                sample = Some(doc.clone());
+            }
+        }
+        folders.extend(
+            docs.into_iter()
+                .filter(|doc| doc.doc_type == DocType::CollectionType)
+                .map(|doc| doc.id),
+        );
+    }
+    sample
+}
+
+/// Try every known endpoint of the web interface, the remarkable must be connected
+pub async fn probe_capabilities(client: &reqwest::Client) -> Capabilities {
+    let documents = list_folder(client, "", &SilentReporter).await.is_ok();
+    let sample = match documents {
+        true => find_sample_document(client).await,
+        false => None,
+    };
+
+    let mut capabilities = Capabilities {
+        documents,
+        // the upload route only accepts POST, it is there if it does not answer 404
+        upload: client
+            .get(format!("{DEVICE_URL}/upload"))
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .is_ok_and(|resp| resp.status() != reqwest::StatusCode::NOT_FOUND),
+        ..Default::default()
+    };
+    let Some(sample) = sample else {
+        return capabilities;
+    };
+
+    let mut unconfirmed = vec![];
+    for route in [PdfRoute::Placeholder, PdfRoute::Pdf] {
+        if endpoint_exists(client, &route.url(&sample.id), &mut unconfirmed).await {
+            capabilities.pdf_export = Some(route);
+            break;
+        }
+    }
+    capabilities.rmdoc_export = endpoint_exists(
+        client,
+        &format!("{DEVICE_URL}/download/{}/rmdoc", sample.id),
+        &mut unconfirmed,
+    )
+    .await;
+    capabilities.thumbnails = endpoint_exists(
+        client,
+        &format!("{DEVICE_URL}/thumbnail/{}", sample.id),
+        &mut unconfirmed,
+    )
+    .await;
+    capabilities.unconfirmed = unconfirmed
+        .iter()
+        .map(|url| url.replace(DEVICE_URL, "").replace(&sample.id, "{id}"))
+        .collect();
+    capabilities.sample_document = Some(sample.vissible_name);
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// the URL of a local web server answering the requests in order with `responses` (expected
+    /// method, status line, content type)
+    async fn serve(responses: Vec<(&'static str, &'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/download/dune/pdf",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move {
+            for (method, status, content_type) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                assert!(request[..read].starts_with(method.as_bytes()));
+                let response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-type: {content_type}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn probes_the_endpoints_with_head_then_get() {
+        let client = reqwest::Client::new();
+        let cases = [
+            (vec![("HEAD", "200 OK", "application/pdf")], Endpoint::Found),
+            (
+                vec![
+                    ("HEAD", "405 Method Not Allowed", "text/plain"),
+                    ("GET", "200 OK", "application/pdf"),
+                ],
+                Endpoint::Found,
+            ),
+            (
+                vec![("HEAD", "404 Not Found", "text/plain")],
+                Endpoint::Missing,
+            ),
+            // the web interface answers unknown routes with its page
+            (
+                vec![("HEAD", "200 OK", "text/html; charset=utf-8")],
+                Endpoint::Missing,
+            ),
+        ];
+        for (responses, endpoint) in cases {
+            let url = serve(responses).await;
+            assert_eq!(probe_endpoint(&client, &url).await, endpoint, "{url}");
+        }
+        assert_eq!(
+            probe_endpoint(&client, "http://127.0.0.1:1/download/dune/pdf").await,
+            Endpoint::Missing
+        );
+    }
+
+    #[test]
+    fn guesses_the_generation_of_the_web_interface() {
+        let capabilities =
+            |documents, pdf_export, rmdoc_export, sample: Option<&str>| Capabilities {
+                documents,
+                pdf_export,
+                rmdoc_export,
+                sample_document: sample.map(str::to_string),
+                ..Default::default()
+            };
+        let cases = [
+            (
+                capabilities(false, None, false, None),
+                "unknown (the documents cannot be listed)",
+            ),
+            (
+                capabilities(true, Some(PdfRoute::Placeholder), true, Some("Dune")),
+                "firmware 3.10 or newer (.rmdoc export)",
+            ),
+            (
+                capabilities(true, Some(PdfRoute::Placeholder), false, Some("Dune")),
+                "firmware 2.x to 3.9",
+            ),
+            (
+                capabilities(true, Some(PdfRoute::Pdf), false, Some("Dune")),
+                "older firmware (/download/{id}/pdf)",
+            ),
+            (
+                capabilities(true, None, false, None),
+                "unknown (no document to test the exports with)",
+            ),
+            (
+                capabilities(true, None, false, Some("Dune")),
+                "unknown (no known export endpoint)",
+            ),
+        ];
+        for (capabilities, generation) in cases {
+            assert_eq!(capabilities.generation(), generation);
+        }
+    }
+
+    #[test]
+    fn serializes_the_capabilities() {
+        assert_eq!(
+            PdfRoute::Placeholder.url("dune"),
+            "http://10.11.99.1/download/dune/placeholder"
+        );
+        let capabilities = Capabilities {
+            documents: true,
+            pdf_export: Some(PdfRoute::Pdf),
+            unconfirmed: vec!["/download/{id}/rmdoc".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&capabilities).unwrap(),
+            serde_json::json!({
+                "documents": true,
+                "pdf_export": "pdf",
+                "rmdoc_export": false,
+                "upload": false,
+                "thumbnails": false,
+                "sample_document": null,
+                "unconfirmed": ["/download/{id}/rmdoc"],
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Connection::WebInterfaceDisabled).unwrap(),
+            "web_interface_disabled"
+        );
+    }
+}
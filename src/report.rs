@@ -0,0 +1,481 @@
+//! What changed in a backup since the previous one, saved as a Markdown or HTML changelog in the
+//! backup folder after each run.
+
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde_derive::Serialize;
+
+use crate::{
+    cmd::archive::{read_manifest, PARTIAL_MARKER},
+    crypto::{Protection, ENCRYPTED_EXTENSION},
+    manifest::{Manifest, ManifestEntry},
+    storage::Storage,
+    utils::join_path,
+};
+
+/// Folder of the backup where the reports are saved
+pub const REPORTS_DIR: &str = "reports";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// Markdown (.md)
+    Markdown,
+    /// standalone HTML page (.html)
+    Html,
+}
+
+impl ReportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Markdown => "md",
+            ReportFormat::Html => "html",
+        }
+    }
+}
+
+/// A document present in both backups
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DocumentUpdate {
+    pub before: ManifestEntry,
+    pub after: ManifestEntry,
+}
+
+/// A document that could not be backed up
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackupFailure {
+    pub id: String,
+    pub name: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ChangeReport {
+    pub created_at: String,
+    /// when the previous backup was made, `None` for the first one
+    pub previous_backup: Option<String>,
+    pub new: Vec<ManifestEntry>,
+    /// modified on the remarkable since the previous backup
+    pub updated: Vec<DocumentUpdate>,
+    /// renamed or moved on the remarkable since the previous backup
+    pub moved: Vec<DocumentUpdate>,
+    /// in the previous backup but no longer on the remarkable
+    pub deleted: Vec<ManifestEntry>,
+    pub failures: Vec<BackupFailure>,
+}
+
+/// Manifest of the last backup made in `out_path` (a local folder or the URL of a storage): its
+/// `manifest.json` (decrypted with `protection`, `None` if it cannot be), or the manifest of its
+/// most recent archive
+pub async fn last_backup_manifest(
+    out_path: &str,
+    archive: bool,
+    protection: &Protection,
+) -> Option<Manifest> {
+    if !archive {
+        let raw = Storage::parse(out_path)
+            .ok()?
+            .read(&protection.manifest_path())
+            .await
+            .ok()??;
+        return serde_json::from_slice(&protection.open(raw)?).ok();
+    }
+    // archives are named after their date, the last one in the alphabetical order is the most recent
+    // (interrupted ones left out)
+    let mut archives = fs::read_dir(out_path)
+        .ok()?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().to_string_lossy().to_string();
+            let is_archive = name.starts_with("remarkable-backup-")
+                && (name.ends_with(".tar.zst") || name.ends_with(".zip"))
+                && !name.contains(&format!(".{PARTIAL_MARKER}."));
+            is_archive.then_some(name)
+        })
+        .collect::<Vec<_>>();
+    archives.sort();
+    let last = Path::new(out_path).join(archives.pop()?);
+    read_manifest(&last.to_string_lossy()).ok().flatten()
+}
+
+/// e.g: "2024-01-31 18:02", the raw value if it cannot be parsed
+fn format_date(date: &str) -> String {
+    match DateTime::<Local>::from_str(date) {
+        Ok(date) => date.format("%Y-%m-%d %H:%M").to_string(),
+        Err(_) => date.to_string(),
+    }
+}
+
+fn pages(entry: &ManifestEntry) -> String {
+    match entry.page_count {
+        Some(pages) => pages.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/// An item of a section of the report: a path and what is to be said about it
+struct Item {
+    path: String,
+    detail: String,
+}
+
+impl ChangeReport {
+    /// Compare the manifest of the previous backup with the one of the backup just made
+    pub fn new(
+        previous: Option<&Manifest>,
+        current: &Manifest,
+        failures: Vec<BackupFailure>,
+    ) -> Self {
+        let before = previous
+            .iter()
+            .flat_map(|manifest| &manifest.documents)
+            .map(|entry| (entry.id.as_str(), entry))
+            .collect::<HashMap<_, _>>();
+        let mut report = Self {
+            created_at: Local::now().to_rfc3339(),
+            previous_backup: previous.map(|manifest| manifest.created_at.clone()),
+            failures,
+            ..Default::default()
+        };
+
+        for after in &current.documents {
+            let Some(&before) = before.get(after.id.as_str()) else {
+                report.new.push(after.clone());
+                continue;
+            };
+            let update = || DocumentUpdate {
+                before: before.clone(),
+                after: after.clone(),
+            };
+            if before.modified_client != after.modified_client {
+                report.updated.push(update());
+            }
+            if before.path != after.path {
+                report.moved.push(update());
+            }
+        }
+        report.deleted = previous
+            .iter()
+            .flat_map(|manifest| &manifest.documents)
+            .filter(|entry| !current.documents.iter().any(|doc| doc.id == entry.id))
+            .cloned()
+            .collect();
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.new.is_empty()
+            && self.updated.is_empty()
+            && self.moved.is_empty()
+            && self.deleted.is_empty()
+            && self.failures.is_empty()
+    }
+
+    fn sections(&self) -> Vec<(String, Vec<Item>)> {
+        let sections = [
+            (
+                "New documents",
+                self.new
+                    .iter()
+                    .map(|entry| Item {
+                        path: entry.path.clone(),
+                        detail: format!(
+                            "{} pages, modified {}",
+                            pages(entry),
+                            format_date(&entry.modified_client)
+                        ),
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "Updated documents",
+                self.updated
+                    .iter()
+                    .map(|DocumentUpdate { before, after }| Item {
+                        path: after.path.clone(),
+                        detail: format!(
+                            "{} pages, modified {} → {}",
+                            match before.page_count == after.page_count {
+                                true => pages(after),
+                                false => format!("{} → {}", pages(before), pages(after)),
+                            },
+                            format_date(&before.modified_client),
+                            format_date(&after.modified_client)
+                        ),
+                    })
+                    .collect(),
+            ),
+            (
+                "Renamed or moved documents",
+                self.moved
+                    .iter()
+                    .map(|DocumentUpdate { before, after }| Item {
+                        path: after.path.clone(),
+                        detail: format!("was {}", before.path),
+                    })
+                    .collect(),
+            ),
+            (
+                "Deleted from the remarkable",
+                self.deleted
+                    .iter()
+                    .map(|entry| Item {
+                        path: entry.path.clone(),
+                        detail: format!("last modified {}", format_date(&entry.modified_client)),
+                    })
+                    .collect(),
+            ),
+            (
+                "Failures",
+                self.failures
+                    .iter()
+                    .map(|failure| Item {
+                        path: failure.name.clone(),
+                        detail: failure.error.clone(),
+                    })
+                    .collect(),
+            ),
+        ];
+        sections
+            .into_iter()
+            .filter(|(_, items)| !items.is_empty())
+            .map(|(title, items)| (format!("{title} ({})", items.len()), items))
+            .collect()
+    }
+
+    fn title(&self) -> String {
+        format!("Backup of {}", format_date(&self.created_at))
+    }
+
+    fn summary(&self) -> String {
+        let compared = match &self.previous_backup {
+            Some(previous) => format!("Compared with the backup of {}", format_date(previous)),
+            None => "First backup of this folder".to_string(),
+        };
+        match self.is_empty() {
+            true => format!("{compared}: no change."),
+            false => format!("{compared}."),
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# {}\n\n{}\n", self.title(), self.summary());
+        for (title, items) in self.sections() {
+            markdown.push_str(&format!("\n## {title}\n\n"));
+            for Item { path, detail } in items {
+                markdown.push_str(&format!("- `{path}`: {detail}\n"));
+            }
+        }
+        markdown
+    }
+
+    pub fn to_html(&self) -> String {
+        fn escape(text: &str) -> String {
+            text.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        }
+
+        let title = escape(&self.title());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{}</p>\n",
+            escape(&self.summary())
+        );
+        for (title, items) in self.sections() {
+            html.push_str(&format!("<h2>{}</h2>\n<ul>\n", escape(&title)));
+            for Item { path, detail } in items {
+                html.push_str(&format!(
+                    "<li><code>{}</code>: {}</li>\n",
+                    escape(&path),
+                    escape(&detail)
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Markdown => self.to_markdown(),
+            ReportFormat::Html => self.to_html(),
+        }
+    }
+
+    /// Save the report in the `reports` folder of the backup, encrypted like its files (e.g:
+    /// "reports/backup-20240131-180200.md.age"), returns its path
+    pub async fn save(
+        &self,
+        out_path: &str,
+        format: ReportFormat,
+        protection: &Protection,
+    ) -> Result<String> {
+        let storage = Storage::parse(out_path)?;
+        let date = DateTime::<Local>::from_str(&self.created_at).unwrap_or_else(|_| Local::now());
+        let mut path = join_path(
+            REPORTS_DIR,
+            &format!(
+                "backup-{}.{}",
+                date.format("%Y%m%d-%H%M%S"),
+                format.extension()
+            ),
+        );
+        if protection.encryption.is_some() {
+            path = format!("{path}.{ENCRYPTED_EXTENSION}");
+        }
+        storage
+            .write(
+                &path,
+                &protection.seal(self.render(format).as_bytes())?,
+                true,
+            )
+            .await?;
+        Ok(storage.display(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest::MANIFEST_FILE, test_utils::TempDir};
+
+    fn entry(id: &str, path: &str, modified: &str, pages: i64) -> ManifestEntry {
+        ManifestEntry {
+            id: id.to_string(),
+            path: path.to_string(),
+            modified_client: modified.to_string(),
+            page_count: Some(pages),
+            ..Default::default()
+        }
+    }
+
+    fn manifest(created_at: &str, documents: Vec<ManifestEntry>) -> Manifest {
+        Manifest {
+            created_at: created_at.to_string(),
+            documents,
+            ..Manifest::new()
+        }
+    }
+
+    fn report() -> ChangeReport {
+        let previous = manifest(
+            "2024-01-01T10:00:00+00:00",
+            vec![
+                entry("dune", "root/Dune.pdf", "2024-01-01T09:00:00Z", 10),
+                entry("course", "root/Course.pdf", "2024-01-01T09:00:00Z", 3),
+                entry("old", "root/Old.pdf", "2024-01-01T09:00:00Z", 1),
+            ],
+        );
+        let current = manifest(
+            "2024-02-01T10:00:00+00:00",
+            vec![
+                entry("dune", "root/Dune.pdf", "2024-01-20T09:00:00Z", 12),
+                entry("course", "root/Books/Course.pdf", "2024-01-01T09:00:00Z", 3),
+                entry("notes", "root/Notes.pdf", "2024-01-25T09:00:00Z", 2),
+            ],
+        );
+        let failures = vec![BackupFailure {
+            id: "broken".to_string(),
+            name: "Broken <1>.pdf".to_string(),
+            error: "the file is empty".to_string(),
+        }];
+        ChangeReport::new(Some(&previous), &current, failures)
+    }
+
+    #[test]
+    fn compares_the_manifests() {
+        let report = report();
+        let ids = |entries: &[ManifestEntry]| {
+            entries
+                .iter()
+                .map(|entry| entry.id.clone())
+                .collect::<Vec<_>>()
+        };
+        let updated_ids = |updates: &[DocumentUpdate]| {
+            updates
+                .iter()
+                .map(|update| update.after.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            report.previous_backup.as_deref(),
+            Some("2024-01-01T10:00:00+00:00")
+        );
+        assert_eq!(ids(&report.new), ["notes"]);
+        assert_eq!(updated_ids(&report.updated), ["dune"]);
+        assert_eq!(updated_ids(&report.moved), ["course"]);
+        assert_eq!(ids(&report.deleted), ["old"]);
+        assert_eq!(report.failures.len(), 1);
+
+        // the first backup only has new documents
+        let first = ChangeReport::new(None, &manifest("", vec![]), vec![]);
+        assert!(first.is_empty());
+        assert!(first
+            .to_markdown()
+            .contains("First backup of this folder: no change."));
+    }
+
+    #[test]
+    fn renders_a_section_per_kind_of_change() {
+        let markdown = report().to_markdown();
+        for line in [
+            "## New documents (1)",
+            "## Updated documents (1)",
+            "- `root/Dune.pdf`: 10 → 12 pages, modified ",
+            "## Renamed or moved documents (1)",
+            "- `root/Books/Course.pdf`: was root/Course.pdf",
+            "## Deleted from the remarkable (1)",
+            "## Failures (1)",
+            "- `Broken <1>.pdf`: the file is empty",
+        ] {
+            assert!(markdown.contains(line), "no '{line}' in:\n{markdown}");
+        }
+
+        let html = report().render(ReportFormat::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<li><code>Broken &lt;1&gt;.pdf</code>: the file is empty</li>"));
+    }
+
+    #[test]
+    fn serializes_the_changes() {
+        let json = serde_json::to_value(report()).unwrap();
+        assert_eq!(json["new"][0]["path"], "root/Notes.pdf");
+        assert_eq!(json["updated"][0]["before"]["page_count"], 10);
+        assert_eq!(json["updated"][0]["after"]["page_count"], 12);
+        assert_eq!(json["moved"][0]["before"]["path"], "root/Course.pdf");
+        assert_eq!(json["failures"][0]["error"], "the file is empty");
+    }
+
+    #[tokio::test]
+    async fn saves_the_report_next_to_the_manifest() {
+        let dir = TempDir::new("report-save");
+        let previous = manifest("2024-01-01T10:00:00+00:00", vec![]);
+        dir.write(MANIFEST_FILE, &serde_json::to_vec(&previous).unwrap());
+        assert_eq!(
+            last_backup_manifest(dir.path(), false, &Protection::default()).await,
+            Some(previous)
+        );
+        assert_eq!(
+            last_backup_manifest(dir.path(), true, &Protection::default()).await,
+            None
+        );
+
+        let report = ChangeReport {
+            created_at: "2024-01-31T12:00:00+00:00".to_string(),
+            ..report()
+        };
+        let saved = report
+            .save(dir.path(), ReportFormat::Markdown, &Protection::default())
+            .await
+            .unwrap();
+        let name = Path::new(&saved).file_name().unwrap().to_string_lossy();
+        assert!(
+            name.starts_with("backup-20240131-") && name.ends_with(".md"),
+            "{name}"
+        );
+        assert_eq!(fs::read_to_string(&saved).unwrap(), report.to_markdown());
+    }
+}
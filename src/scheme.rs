@@ -1,67 +1,262 @@
+use anyhow::{anyhow, Result};
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 
 pub type RmkFile = (String, String, Vec<u8>);
 
 pub type RmkDocuments = Vec<RmkDocument>;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct RmkDocument {
-    #[serde(rename = "Bookmarked")]
+    #[serde(rename = "Bookmarked", deserialize_with = "lenient::boolean")]
     pub bookmarked: bool,
-    #[serde(rename = "CurrentPage")]
+    #[serde(rename = "CurrentPage", deserialize_with = "lenient::opt_i64")]
     pub current_page: Option<i64>,
-    #[serde(rename = "ID")]
+    #[serde(rename = "ID", deserialize_with = "lenient::string")]
     pub id: String,
-    #[serde(rename = "ModifiedClient")]
+    #[serde(rename = "ModifiedClient", deserialize_with = "lenient::string")]
     pub modified_client: String,
-    #[serde(rename = "Parent")]
+    #[serde(rename = "Parent", deserialize_with = "lenient::string")]
     pub parent: String,
-    #[serde(rename = "Type")]
+    #[serde(rename = "Type", deserialize_with = "lenient::or_default")]
     pub doc_type: DocType,
-    #[serde(rename = "VissibleName")]
+    #[serde(rename = "VissibleName", deserialize_with = "lenient::string")]
     pub vissible_name: String,
+    #[serde(deserialize_with = "lenient::or_default")]
     pub c_pages: Option<CPages>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub cover_page_number: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub custom_zoom_center_x: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub custom_zoom_center_y: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub custom_zoom_orientation: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub custom_zoom_page_height: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub custom_zoom_page_width: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub custom_zoom_scale: Option<i64>,
+    #[serde(deserialize_with = "lenient::or_default")]
     pub document_metadata: Option<DocumentMetadata>,
+    #[serde(deserialize_with = "lenient::or_default")]
     pub extra_metadata: Option<ExtraMetadata>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub file_type: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub font_name: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub format_version: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub line_height: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub margins: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub orientation: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub page_count: Option<i64>,
-    #[serde(default)]
-    pub page_tags: Vec<Value>,
+    #[serde(deserialize_with = "lenient::or_default")]
+    pub page_tags: Vec<PageTag>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub size_in_bytes: Option<String>,
-    pub tags: Vec<Value>,
+    #[serde(deserialize_with = "lenient::or_default")]
+    pub tags: Vec<Tag>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub text_alignment: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub text_scale: Option<i64>,
+    #[serde(deserialize_with = "lenient::opt_string")]
     pub zoom_mode: Option<String>,
+    #[serde(deserialize_with = "lenient::opt_i64")]
     pub original_page_count: Option<i64>,
+    #[serde(deserialize_with = "lenient::or_default")]
     pub pages: Option<Vec<String>>,
+    #[serde(deserialize_with = "lenient::or_default")]
     pub redirection_page_map: Option<Vec<i64>>,
+    /// fields this version does not know about (e.g: added by a firmware update), kept as they are
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl RmkDocument {
+    /// size of the document as reported by the remarkable, the exported file may differ a bit
+    pub fn size_estimate(&self) -> Option<u64> {
+        self.size_in_bytes.as_ref()?.parse().ok()
+    }
+
+    /// names of the tags of the document and of its pages, without duplicates
+    pub fn tag_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        let all_tags = self
+            .tags
+            .iter()
+            .map(|tag| tag.name.as_str())
+            .chain(self.page_tags.iter().map(|tag| tag.name.as_str()));
+        for name in all_tags {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// whether the document or one of its pages has the tag `name` (case insensitive)
+    pub fn has_tag(&self, name: &str) -> bool {
+        self.tag_names()
+            .iter()
+            .any(|tag| tag.eq_ignore_ascii_case(name))
+    }
+
+    /// index (from 0) of the page `page_id` in the document
+    pub fn page_index(&self, page_id: &str) -> Option<usize> {
+        if let Some(pages) = self
+            .c_pages
+            .as_ref()
+            .and_then(|c_pages| c_pages.pages.as_ref())
+        {
+            return pages.iter().position(|page| page.id == page_id);
+        }
+        self.pages.as_ref()?.iter().position(|page| page == page_id)
+    }
+}
+
+/// Tag given to a whole document on the remarkable
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tag {
+    pub name: String,
+    /// when the tag was added, in milliseconds since epoch
+    pub timestamp: Option<i64>,
+}
+
+/// Tag given to a single page of a document
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PageTag {
+    pub name: String,
+    pub page_id: String,
+    /// when the tag was added, in milliseconds since epoch
+    pub timestamp: Option<i64>,
+}
+
+/// Deserializers of the fields of [`RmkDocument`] that accept the other JSON types firmwares used for
+/// them (e.g: "sizeInBytes" as a number instead of a string): a value of the wrong type is converted
+/// when it can be, left empty otherwise, instead of leaving the whole document out
+mod lenient {
+    use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+    use serde_json::Value;
+
+    /// scalar values as text, `None` for objects and arrays
+    fn to_text(value: Value) -> Option<String> {
+        match value {
+            Value::String(text) => Some(text),
+            Value::Number(number) => Some(number.to_string()),
+            Value::Bool(flag) => Some(flag.to_string()),
+            _ => None,
+        }
+    }
+
+    /// the ID, name... of a document: an object or an array cannot stand for it
+    pub fn string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::Null => Ok(String::new()),
+            value @ (Value::Array(_) | Value::Object(_)) => Err(serde::de::Error::custom(format!(
+                "expected a string, found {value}"
+            ))),
+            value => Ok(to_text(value).unwrap_or_default()),
+        }
+    }
+
+    pub fn opt_string<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<String>, D::Error> {
+        Ok(to_text(Value::deserialize(deserializer)?))
+    }
+
+    pub fn opt_i64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Number(number) => number
+                .as_i64()
+                .or_else(|| number.as_f64().map(|number| number as i64)),
+            Value::String(text) => text
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .or_else(|| text.trim().parse::<f64>().ok().map(|number| number as i64)),
+            _ => None,
+        })
+    }
+
+    pub fn boolean<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        Ok(match Value::deserialize(deserializer)? {
+            Value::Bool(flag) => flag,
+            Value::Number(number) => number.as_f64().is_some_and(|number| number != 0.0),
+            Value::String(text) => text.eq_ignore_ascii_case("true") || text == "1",
+            _ => false,
+        })
+    }
+
+    /// structured fields (metadata, tags...): the default value when they cannot be read
+    pub fn or_default<'de, D: Deserializer<'de>, T: DeserializeOwned + Default>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        Ok(serde_json::from_value(Value::deserialize(deserializer)?).unwrap_or_default())
+    }
+}
+
+/// Parse the listing of a folder entry by entry: an entry that cannot be read is left out with a
+/// warning instead of failing the whole listing
+pub fn parse_documents(listing: Value) -> Result<(RmkDocuments, Vec<String>)> {
+    let Value::Array(entries) = listing else {
+        return Err(anyhow!("Expected a list of documents from the remarkable"));
+    };
+
+    let mut docs = vec![];
+    let mut warnings = vec![];
+    for (i, entry) in entries.into_iter().enumerate() {
+        let label = match (entry.get("VissibleName"), entry.get("ID")) {
+            (Some(Value::String(name)), _) => format!("'{name}'"),
+            (_, Some(Value::String(id))) => format!("'{id}'"),
+            _ => format!("#{i}"),
+        };
+        let raw_type = entry.get("Type").cloned();
+        match serde_json::from_value::<RmkDocument>(entry) {
+            Ok(doc) if doc.id.is_empty() => {
+                warnings.push(format!("Ignoring entry {label}: it has no ID"))
+            }
+            Ok(doc) => {
+                if doc.doc_type == DocType::Unknown {
+                    warnings.push(format!(
+                        "Entry {label} has an unknown type ({}), it is ignored by the transfers",
+                        raw_type.unwrap_or_default()
+                    ));
+                }
+                docs.push(doc);
+            }
+            Err(why) if raw_type == Some(Value::from("CollectionType")) => warnings.push(format!(
+                "Ignoring folder {label} and everything inside it, it cannot be read: {why}"
+            )),
+            Err(why) => warnings.push(format!("Ignoring entry {label}, it cannot be read: {why}")),
+        }
+    }
+    Ok((docs, warnings))
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DocType {
     CollectionType,
     DocumentType,
+    /// types added by newer firmwares
     #[default]
+    #[serde(other)]
     Unknown,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct CPages {
     pub last_opened: Option<LastOpened>,
     pub original: Option<Original>,
@@ -70,21 +265,21 @@ pub struct CPages {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct LastOpened {
     pub timestamp: Option<String>,
     pub value: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Original {
     pub timestamp: Option<String>,
     pub value: Option<i64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Page {
     pub id: String,
     pub idx: Option<Idx>,
@@ -95,57 +290,56 @@ pub struct Page {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Idx {
     pub timestamp: Option<String>,
     pub value: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Redir {
     pub timestamp: Option<String>,
     pub value: Option<i64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct ScrollTime {
     pub timestamp: Option<String>,
     pub value: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct VerticalScroll {
     pub timestamp: Option<String>,
     pub value: Option<f64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Template {
     pub timestamp: Option<String>,
     pub value: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct Uuid {
     pub first: Option<String>,
     pub second: Option<i64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct DocumentMetadata {
-    #[serde(default)]
     pub authors: Vec<String>,
     pub title: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(default, rename_all = "camelCase")]
 pub struct ExtraMetadata {
     #[serde(rename = "LastBallpointv2Color")]
     pub last_ballpointv2color: Option<String>,
@@ -197,4 +391,99 @@ pub struct ExtraMetadata {
     pub last_sharp_pencilv2size: Option<String>,
     #[serde(rename = "LastTool")]
     pub last_tool: Option<String>,
+    /// tools added by newer firmwares
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(raw: &str) -> (RmkDocuments, Vec<String>) {
+        parse_documents(serde_json::from_str(raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn firmware_2_15_without_tags() {
+        let (docs, warnings) = parse_fixture(include_str!("../tests/fixtures/firmware-2.15.json"));
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[0].doc_type, DocType::CollectionType);
+
+        let dune = &docs[1];
+        assert_eq!(dune.doc_type, DocType::DocumentType);
+        assert_eq!(dune.parent, docs[0].id);
+        assert!(dune.bookmarked);
+        assert_eq!(dune.page_count, Some(412));
+        assert_eq!(dune.size_estimate(), Some(2811904));
+        assert!(dune.tags.is_empty());
+        assert!(dune.tag_names().is_empty());
+        let extra_metadata = dune.extra_metadata.as_ref().unwrap();
+        assert_eq!(extra_metadata.last_pen.as_deref(), Some("Finelinerv2"));
+    }
+
+    #[test]
+    fn firmware_3_5_with_tags_and_pages() {
+        let (docs, warnings) = parse_fixture(include_str!("../tests/fixtures/firmware-3.5.json"));
+        assert!(warnings.is_empty(), "{warnings:?}");
+        let notes = &docs[0];
+        assert_eq!(notes.tag_names(), ["Work", "todo"]);
+        assert!(notes.has_tag("work"));
+        assert_eq!(notes.page_index("b2c3"), Some(1));
+        // a float where an integer is expected
+        assert_eq!(notes.custom_zoom_center_x, Some(702));
+        assert!(notes.extra.is_empty());
+    }
+
+    #[test]
+    fn firmware_3_10_new_fields_and_types() {
+        let (docs, warnings) = parse_fixture(include_str!("../tests/fixtures/firmware-3.10.json"));
+        assert_eq!(docs.len(), 3);
+
+        let roadmap = &docs[1];
+        // a number instead of a string
+        assert_eq!(roadmap.size_in_bytes.as_deref(), Some("183422"));
+        assert_eq!(roadmap.size_estimate(), Some(183422));
+        assert_eq!(
+            roadmap.extra.get("lastOpened"),
+            Some(&Value::from("1742638500000"))
+        );
+        let extra_metadata = roadmap.extra_metadata.as_ref().unwrap();
+        assert_eq!(extra_metadata.last_tool.as_deref(), Some("Shader"));
+        assert_eq!(
+            extra_metadata.extra.get("LastShaderColor"),
+            Some(&Value::from("Gray"))
+        );
+
+        // unknown types are kept, with a warning
+        assert_eq!(docs[2].doc_type, DocType::Unknown);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'Weekly planner'"), "{warnings:?}");
+        assert!(warnings[0].contains("TemplateType"), "{warnings:?}");
+    }
+
+    #[test]
+    fn fields_of_the_wrong_type() {
+        let (docs, warnings) = parse_fixture(include_str!("../tests/fixtures/wrong-types.json"));
+        assert_eq!(docs.len(), 1);
+        let odd = &docs[0];
+        assert!(odd.bookmarked);
+        assert_eq!(odd.current_page, None);
+        assert_eq!(odd.page_count, Some(12));
+        assert_eq!(odd.document_metadata, None);
+        assert!(odd.tags.is_empty());
+
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(
+            warnings[0].starts_with("Ignoring folder 'Unreadable folder' and everything inside it"),
+            "{warnings:?}"
+        );
+        assert!(warnings[1].contains("'No ID'"), "{warnings:?}");
+    }
+
+    #[test]
+    fn not_a_list() {
+        assert!(parse_documents(serde_json::json!({ "ID": "x" })).is_err());
+    }
 }
@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+
+use crate::utils::{join_path, temp_path, write_file};
+
+use super::StoredFile;
+
+/// A folder of the local file system, files are written atomically (see [`write_file`])
+pub struct LocalStorage {
+    root: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        Path::new(&self.root).join(path)
+    }
+
+    pub fn display(&self, path: &str) -> String {
+        join_path(&self.root, path)
+    }
+
+    /// every file under the folder, links (e.g: the tag view of a backup) are not followed and
+    /// hidden files (e.g: `.git`, the files being written) are left out
+    pub fn list(&self) -> Result<HashMap<String, StoredFile>> {
+        fn walk(dir: &Path, relative: &str, files: &mut HashMap<String, StoredFile>) -> Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') {
+                    continue;
+                }
+                let path = join_path(relative, &name);
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    walk(&entry.path(), &path, files)?;
+                } else if file_type.is_file() {
+                    let metadata = entry.metadata()?;
+                    files.insert(
+                        path,
+                        StoredFile {
+                            size: metadata.len(),
+                            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                        },
+                    );
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = HashMap::new();
+        if Path::new(&self.root).is_dir() {
+            walk(Path::new(&self.root), "", &mut files)?;
+        }
+        Ok(files)
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.path(path).exists()
+    }
+
+    pub fn read(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(path)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    pub fn write(&self, path: &str, bytes: &[u8], overwrite: bool) -> Result<()> {
+        let path = self.path(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_file(path, bytes, overwrite)?;
+        Ok(())
+    }
+
+    /// Make `path` a hard link to the file `target`, so that both share their content on disk (a
+    /// copy on file systems without hard links, e.g: FAT). Replaced atomically like [`write_file`]
+    pub fn link(&self, target: &str, path: &str, overwrite: bool) -> Result<()> {
+        let (target, path) = (self.path(target), self.path(path));
+        if !overwrite && path.exists() {
+            return Err(anyhow!("'{}' already exists", path.display()));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = temp_path(&path);
+        let linked = fs::hard_link(&target, &temp)
+            .or_else(|_| fs::copy(&target, &temp).map(|_| ()))
+            .and_then(|_| fs::rename(&temp, &path));
+        if linked.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        Ok(linked?)
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<()> {
+        fs::create_dir_all(self.path(path))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Where backups and downloads are written: a local folder, an S3-compatible bucket or a WebDAV
+//! server. Paths given to a storage are relative to its root and separated by '/', e.g:
+//! "root/Books/Dune.pdf".
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use quick_xml::events::Event as XmlEvent;
+
+pub mod local;
+pub mod s3;
+pub mod webdav;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+pub use webdav::WebDavStorage;
+
+/// A file found in a storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredFile {
+    pub size: u64,
+    /// when it was written, `None` if the storage does not tell
+    pub modified: Option<DateTime<Utc>>,
+}
+
+pub enum Storage {
+    Local(LocalStorage),
+    S3(S3Storage),
+    WebDav(WebDavStorage),
+}
+
+/// whether `target` is a URL of a remote storage rather than a local path, see [`Storage::parse`]
+pub fn is_remote(target: &str) -> bool {
+    ["s3://", "webdav://", "webdavs://"]
+        .iter()
+        .any(|scheme| target.starts_with(scheme))
+}
+
+impl Storage {
+    /// "s3://bucket/prefix" (see [`S3Storage::from_url`]), "webdav://host/path" or
+    /// "webdavs://host/path" for https (see [`WebDavStorage::from_url`]), anything else is a local path
+    pub fn parse(target: &str) -> Result<Self> {
+        if target.starts_with("s3://") {
+            return Ok(Storage::S3(S3Storage::from_url(target)?));
+        }
+        if is_remote(target) {
+            return Ok(Storage::WebDav(WebDavStorage::from_url(target)?));
+        }
+        Ok(Storage::Local(LocalStorage::new(target)))
+    }
+
+    /// the folder of a local storage, `None` for remote ones
+    pub fn local_path(&self) -> Option<&str> {
+        match self {
+            Storage::Local(storage) => Some(storage.root()),
+            _ => None,
+        }
+    }
+
+    /// full location of `path`, for the messages, e.g: "s3://backups/remarkable/root/Dune.pdf"
+    /// (without the credentials), `display("")` for the storage itself
+    pub fn display(&self, path: &str) -> String {
+        let location = match self {
+            Storage::Local(storage) => storage.display(path),
+            Storage::S3(storage) => storage.display(path),
+            Storage::WebDav(storage) => storage.display(path),
+        };
+        match location.trim_end_matches('/') {
+            root if path.is_empty() && !root.is_empty() => root.to_string(),
+            _ => location,
+        }
+    }
+
+    /// every file of the storage, by path
+    pub async fn list(&self) -> Result<HashMap<String, StoredFile>> {
+        match self {
+            Storage::Local(storage) => storage.list(),
+            Storage::S3(storage) => storage.list().await,
+            Storage::WebDav(storage) => storage.list().await,
+        }
+    }
+
+    pub async fn exists(&self, path: &str) -> Result<bool> {
+        match self {
+            Storage::Local(storage) => Ok(storage.exists(path)),
+            Storage::S3(storage) => storage.exists(path).await,
+            Storage::WebDav(storage) => storage.exists(path).await,
+        }
+    }
+
+    /// content of the file at `path`, `None` if there is none
+    pub async fn read(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        match self {
+            Storage::Local(storage) => storage.read(path),
+            Storage::S3(storage) => storage.read(path).await,
+            Storage::WebDav(storage) => storage.read(path).await,
+        }
+    }
+
+    /// write a file (its folders are created if needed), an existing file is only replaced if
+    /// `overwrite` is set
+    pub async fn write(&self, path: &str, bytes: &[u8], overwrite: bool) -> Result<()> {
+        if !overwrite && !matches!(self, Storage::Local(_)) && self.exists(path).await? {
+            return Err(anyhow!("'{}' already exists", self.display(path)));
+        }
+        match self {
+            Storage::Local(storage) => storage.write(path, bytes, overwrite),
+            Storage::S3(storage) => storage.write(path, bytes).await,
+            Storage::WebDav(storage) => storage.write(path, bytes).await,
+        }
+    }
+
+    /// create a folder and its parents, object storages have no folders
+    pub async fn create_dir(&self, path: &str) -> Result<()> {
+        match self {
+            Storage::Local(storage) => storage.create_dir(path),
+            Storage::S3(_) => Ok(()),
+            Storage::WebDav(storage) => storage.create_dir(path).await,
+        }
+    }
+}
+
+/// percent-encode everything but the unreserved characters (and '/' if `keep_slash`), as S3
+/// signatures and WebDAV paths expect it
+pub(crate) fn percent_encode(text: &str, keep_slash: bool) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Text of the elements inside each `element` of an XML document, by local name (namespaces are
+/// ignored), e.g: the `Key` and `Size` of each `Contents` of an S3 listing. Empty elements are
+/// present with an empty text
+pub(crate) fn xml_records(xml: &str, element: &str) -> Result<Vec<HashMap<String, String>>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut records = vec![];
+    let mut record: Option<HashMap<String, String>> = None;
+    let mut current = String::new();
+    loop {
+        match reader.read_event()? {
+            XmlEvent::Start(start) => {
+                let name = String::from_utf8_lossy(start.local_name().as_ref()).to_string();
+                match (&mut record, name == element) {
+                    (None, true) => record = Some(HashMap::new()),
+                    (Some(_), _) => current = name,
+                    _ => {}
+                }
+            }
+            XmlEvent::Empty(empty) => {
+                if let Some(record) = &mut record {
+                    let name = String::from_utf8_lossy(empty.local_name().as_ref()).to_string();
+                    record.entry(name).or_default();
+                }
+            }
+            XmlEvent::Text(text) => {
+                if let Some(record) = &mut record {
+                    record
+                        .entry(current.clone())
+                        .or_default()
+                        .push_str(&text.unescape()?);
+                }
+            }
+            XmlEvent::End(end) if end.local_name().as_ref() == element.as_bytes() => {
+                records.extend(record.take());
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(records)
+}
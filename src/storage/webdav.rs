@@ -0,0 +1,209 @@
+use std::{collections::HashMap, env, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+
+use crate::utils::join_path;
+
+use super::{percent_decode, percent_encode, xml_records, StoredFile};
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:"><prop><resourcetype/><getcontentlength/><getlastmodified/></prop></propfind>"#;
+
+/// A folder of a WebDAV server: Nextcloud, ownCloud, Apache mod_dav, rclone serve webdav...
+pub struct WebDavStorage {
+    /// e.g: "https://cloud.example.com/remote.php/dav/files/me/remarkable", without trailing '/'
+    base_url: String,
+    credentials: Option<(String, String)>,
+    client: reqwest::Client,
+    /// folders known to exist, to create them only once
+    folders: Mutex<Vec<String>>,
+}
+
+impl WebDavStorage {
+    /// "webdav://[user[:password]@]host[:port]/path" (http) or "webdavs://..." (https), the
+    /// credentials can also be given with `WEBDAV_USERNAME` and `WEBDAV_PASSWORD`
+    pub fn from_url(url: &str) -> Result<Self> {
+        let (scheme, location) = match url.split_once("://") {
+            Some(("webdav", location)) => ("http", location),
+            Some(("webdavs", location)) => ("https", location),
+            _ => {
+                return Err(anyhow!(
+                    "'{url}' is not a WebDAV URL (webdav://host/path or webdavs://host/path)"
+                ))
+            }
+        };
+        let mut parsed_url = reqwest::Url::parse(&format!("{scheme}://{location}"))?;
+        let credentials = match parsed_url.username() {
+            "" => env::var("WEBDAV_USERNAME")
+                .ok()
+                .map(|user| (user, env::var("WEBDAV_PASSWORD").unwrap_or_default())),
+            user => Some((
+                percent_decode(user),
+                percent_decode(parsed_url.password().unwrap_or_default()),
+            )),
+        };
+        // the credentials are sent in a header, not in the URL
+        let _ = parsed_url.set_username("");
+        let _ = parsed_url.set_password(None);
+        Ok(Self {
+            base_url: parsed_url.as_str().trim_end_matches('/').to_string(),
+            credentials,
+            client: reqwest::Client::new(),
+            folders: Mutex::new(vec![]),
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        match path.is_empty() {
+            true => format!("{}/", self.base_url),
+            false => format!("{}/{}", self.base_url, percent_encode(path, true)),
+        }
+    }
+
+    pub fn display(&self, path: &str) -> String {
+        join_path(&percent_decode(&self.base_url), path)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, self.url(path));
+        match &self.credentials {
+            Some((user, password)) => request.basic_auth(user, Some(password)),
+            None => request,
+        }
+    }
+
+    fn check(&self, response: Response, what: &str) -> Result<Response> {
+        match response.status().is_success() {
+            true => Ok(response),
+            false => Err(anyhow!(
+                "The WebDAV server refused to {what} in '{}' ({})",
+                self.display(""),
+                response.status()
+            )),
+        }
+    }
+
+    /// files and folders directly inside the folder `path`
+    async fn list_folder(&self, path: &str) -> Result<Vec<(String, Option<StoredFile>)>> {
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND")?, path)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        let listing = self.check(response, "list the files")?.text().await?;
+
+        let base_path = reqwest::Url::parse(&self.url(""))?.path().to_string();
+        let mut entries = vec![];
+        for item in xml_records(&listing, "response")? {
+            // absolute URL or absolute path, percent-encoded
+            let Some(href) = item.get("href") else {
+                continue;
+            };
+            let href = match reqwest::Url::parse(href) {
+                Ok(url) => url.path().to_string(),
+                Err(_) => href.to_string(),
+            };
+            let Some(entry_path) = percent_decode(&href)
+                .strip_prefix(&percent_decode(&base_path))
+                .map(|entry_path| entry_path.trim_matches('/').to_string())
+            else {
+                continue;
+            };
+            // the folder itself is listed too
+            if entry_path == path.trim_matches('/') {
+                continue;
+            }
+            let file = match item.contains_key("collection") {
+                true => None,
+                false => Some(StoredFile {
+                    size: item
+                        .get("getcontentlength")
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or_default(),
+                    modified: item
+                        .get("getlastmodified")
+                        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                        .map(|date| date.with_timezone(&Utc)),
+                }),
+            };
+            entries.push((entry_path, file));
+        }
+        Ok(entries)
+    }
+
+    /// every file, folder by folder (servers often refuse `Depth: infinity`)
+    pub async fn list(&self) -> Result<HashMap<String, StoredFile>> {
+        let mut files = HashMap::new();
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            for (path, file) in self.list_folder(&folder).await? {
+                match file {
+                    Some(file) => {
+                        files.insert(path, file);
+                    }
+                    None => folders.push(path),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    pub async fn exists(&self, path: &str) -> Result<bool> {
+        let response = self.request(Method::HEAD, path).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => self.check(response, "read a file").map(|_| true),
+        }
+    }
+
+    pub async fn read(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = self.check(response, "read a file")?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// create the folder `path` and its parents (the root folder included), WebDAV only creates one
+    /// level at a time
+    pub async fn create_dir(&self, path: &str) -> Result<()> {
+        let parts = path.split('/').filter(|part| !part.is_empty());
+        let mut folder = String::new();
+        for part in std::iter::once("").chain(parts) {
+            folder = join_path(&folder, part);
+            if self.folders.lock().unwrap().contains(&folder) {
+                continue;
+            }
+            let response = self
+                .request(Method::from_bytes(b"MKCOL")?, &folder)
+                .send()
+                .await?;
+            // 405: the folder already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                self.check(response, "create a folder")?;
+            }
+            self.folders.lock().unwrap().push(folder.clone());
+        }
+        Ok(())
+    }
+
+    pub async fn write(&self, path: &str, bytes: &[u8]) -> Result<()> {
+        let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+        self.create_dir(parent).await?;
+        let response = self
+            .request(Method::PUT, path)
+            .body(bytes.to_vec())
+            .send()
+            .await?;
+        self.check(response, "write a file")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Fixtures shared by the tests of the modules
+
+use std::{fs, io::Write, path::PathBuf};
+
+use lopdf::{dictionary, Document, Object, Stream};
+
+/// A directory of the system temp dir, unique to the test `name` and to the process, removed when
+/// dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// an empty directory, `name` is prefixed by the module of the test, e.g: "lock-release"
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "remarkable2-downloader-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// a directory holding a small PDF at each of the `files`, e.g: "root/Books/Dune.pdf"
+    pub fn with_files(name: &str, files: &[&str]) -> Self {
+        let dir = Self::new(name);
+        for file in files {
+            dir.write(file, b"%PDF-1.4");
+        }
+        dir
+    }
+
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// `path` inside the directory
+    pub fn join(&self, path: &str) -> String {
+        self.0.join(path).to_string_lossy().to_string()
+    }
+
+    /// write `bytes` at `path` inside the directory, creating its folders
+    pub fn write(&self, path: &str, bytes: &[u8]) {
+        let path = self.0.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, bytes).unwrap();
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// a readable PDF of `pages` empty pages
+pub fn pdf(pages: usize) -> Vec<u8> {
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let kids = (0..pages)
+        .map(|_| {
+            let content = doc.add_object(Stream::new(dictionary! {}, vec![]));
+            doc.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => content,
+            })
+            .into()
+        })
+        .collect::<Vec<Object>>();
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => pages as i64,
+            "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    doc.trailer.set("Root", catalog_id);
+    let mut bytes = vec![];
+    doc.save_to(&mut bytes).unwrap();
+    bytes
+}
+
+/// a zip archive (like an EPUB) holding `files`, by name
+pub fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for (name, bytes) in files {
+        writer
+            .start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(bytes).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+}
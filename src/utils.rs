@@ -1,23 +1,25 @@
 use anyhow::{anyhow, Result};
-use std::{fs, time::Duration};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use colored::Colorize;
+use crate::cancel::{track_temp_file, untrack_temp_file};
 
-pub fn print_err(err: &str) {
-    eprintln!("{}", err.red().bold());
-}
-
-#[macro_export]
 macro_rules! udp_return {
-    ($result:expr, $udp_mode:expr, $error_msg:expr) => {
+    ($result:expr, $udp_mode:expr, $reporter:expr, $error_msg:expr) => {
         match $result {
             Ok(d) => d,
-            Err(_) => {
+            Err(why) => {
                 if $udp_mode {
-                    println!(
-                        "{}",
-                        "[UDP_MODE]: a subfolder as been entirely skipped due to an error".yellow()
-                    );
+                    $reporter.report($crate::events::Event::Skipped {
+                        reason: format!(
+                            "[UDP_MODE]: a subfolder as been entirely skipped due to an error: {}: {why}",
+                            $error_msg
+                        ),
+                    });
                     return Ok(());
                 } else {
                     return Err(anyhow!($error_msg));
@@ -26,18 +28,20 @@ macro_rules! udp_return {
         }
     };
 }
+pub(crate) use udp_return;
 
-#[macro_export]
 macro_rules! udp_continue {
-    ($result:expr, $udp_mode:expr, $error_msg:expr) => {
+    ($result:expr, $udp_mode:expr, $reporter:expr, $error_msg:expr) => {
         match $result {
             Ok(d) => d,
             Err(why) => {
                 if $udp_mode {
-                    println!(
-                        "{}",
-                        "[UDP_MODE]: a file as been skipped due to an error".yellow()
-                    );
+                    $reporter.report($crate::events::Event::Skipped {
+                        reason: format!(
+                            "[UDP_MODE]: a file as been skipped due to an error: {}: {why}",
+                            $error_msg
+                        ),
+                    });
                     continue;
                 } else {
                     return Err(anyhow!(format!("{}: {why}", $error_msg)));
@@ -46,17 +50,7 @@ macro_rules! udp_continue {
         }
     };
 }
-
-pub trait UnwrapExtra<T> {
-    fn unwrap_colored(self, err: &str) -> T;
-}
-
-impl<T, E: std::fmt::Debug> UnwrapExtra<T> for Result<T, E> {
-    fn unwrap_colored(self, err: &str) -> T {
-        print_err(err);
-        self.unwrap()
-    }
-}
+pub(crate) use udp_continue;
 
 pub async fn is_client_up() -> bool {
     let client = reqwest::Client::new();
@@ -100,3 +94,51 @@ pub fn ensure_file_extension(name: &str) -> String {
         format!("{name}.pdf")
     }
 }
+
+/// join two parts of a relative path with a single '/', an empty parent gives back `name`
+pub fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!(
+            "{parent}{}{name}",
+            if parent.ends_with('/') { "" } else { "/" }
+        )
+    }
+}
+
+/// hidden file next to `path` where it is written before being moved in place
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.part"))
+}
+
+/// Write a file atomically: it is written next to `path` then renamed, so that an interrupted write
+/// never leaves a half-written file (nor truncates the file it replaces).
+/// Fails if `path` exists and `overwrite` is false
+pub fn write_file(path: impl AsRef<Path>, bytes: &[u8], overwrite: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    if !overwrite && path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", path.display()),
+        ));
+    }
+
+    let temp = temp_path(path);
+    track_temp_file(&temp);
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    untrack_temp_file(&temp);
+    written
+}
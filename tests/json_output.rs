@@ -0,0 +1,67 @@
+//! The machine-readable output of the commands printing records: stdout must hold a single JSON
+//! document. The documents come from the cache when the remarkable is not plugged in.
+
+use std::{fs, path::PathBuf, process::Command};
+
+use remarkable2_downloader::{
+    cache::{save_cache, DEFAULT_DEVICE},
+    scheme::{DocType, RmkDocument},
+};
+
+/// a cache directory holding a few documents, removed when dropped
+struct CacheDir(PathBuf);
+
+impl CacheDir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "remarkable2-downloader-json-output-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("XDG_CACHE_HOME", &dir);
+        save_cache(
+            DEFAULT_DEVICE,
+            &vec![
+                RmkDocument {
+                    id: "books".to_string(),
+                    vissible_name: "Books".to_string(),
+                    doc_type: DocType::CollectionType,
+                    ..Default::default()
+                },
+                RmkDocument {
+                    id: "dune".to_string(),
+                    vissible_name: "Dune".to_string(),
+                    parent: "books".to_string(),
+                    doc_type: DocType::DocumentType,
+                    ..Default::default()
+                },
+            ],
+        )
+        .unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for CacheDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn record_commands_print_a_single_json_document() {
+    let cache = CacheDir::new();
+    for args in [&["ls", "/"][..], &["tree"], &["tags"]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_remarkable2-downloader"))
+            .args(["--output", "json"])
+            .args(args)
+            .env("XDG_CACHE_HOME", &cache.0)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{args:?} failed");
+        let records: serde_json::Value = serde_json::from_slice(&output.stdout)
+            .unwrap_or_else(|why| panic!("{args:?} printed more than one document: {why}"));
+        assert!(records.is_array(), "{args:?}");
+    }
+}